@@ -0,0 +1,60 @@
+use tide::{Middleware, Next, Request, StatusCode};
+
+/// Routes that can be reached without a token.
+const PUBLIC_PATHS: [&str; 1] = ["/health"];
+
+/// Middleware requiring `Authorization: Bearer <token>` on every non-public route.
+pub struct BearerAuth {
+    token: String,
+}
+
+impl BearerAuth {
+    pub fn new(token: String) -> Self {
+        Self { token }
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BearerAuth {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if PUBLIC_PATHS.contains(&req.url().path()) {
+            return Ok(next.run(req).await);
+        }
+        let authorized = req
+            .header("Authorization")
+            .and_then(|values| values.as_str().strip_prefix("Bearer "))
+            .map(|given| constant_time_eq(given.as_bytes(), self.token.as_bytes()))
+            .unwrap_or_default();
+        if !authorized {
+            log::warn!("rejected unauthorized request to {}", req.url().path());
+            let mut res = tide::Response::new(StatusCode::Unauthorized);
+            res.insert_header("WWW-Authenticate", "Bearer");
+            return Ok(res);
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+/// Compares two byte strings in time that depends only on their lengths, not their contents.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter()
+        .zip(b.iter())
+        .fold(0u8, |acc, (x, y)| acc | (x ^ y))
+        == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compares_tokens() {
+        assert!(constant_time_eq(b"hunter2", b"hunter2"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter3"));
+        assert!(!constant_time_eq(b"hunter2", b"hunter22"));
+        assert!(constant_time_eq(b"", b""));
+    }
+}
@@ -4,6 +4,9 @@ use clap::{ArgGroup, Parser};
 use serde::*;
 use terminal_size::{terminal_size, Width};
 use themelio_structs::NetID;
+
+use crate::{compression::Compression, selection::CoinSelection};
+
 #[derive(Parser, Clone, Deserialize, Debug)]
 #[clap(group(
     ArgGroup::new("options")
@@ -30,6 +33,10 @@ pub struct Args {
     /// IP of full node on specified `network`; Required when not collecting to "mainnet" or "testnet"
     pub connect: Option<SocketAddr>,
 
+    #[clap(long, display_order(3))]
+    /// SOCKS5 proxy, like Tor's `127.0.0.1:9050`, to reach the full node through
+    pub proxy: Option<SocketAddr>,
+
     #[clap(long, default_value = "127.0.0.1:11773", display_order(4))]
     /// IP to host melwalletd server
     pub listen: SocketAddr,
@@ -38,10 +45,121 @@ pub struct Args {
     /// CORS origins allowed to access daemon
     pub allowed_origin: Vec<String>, // TODO: validate as urls
 
+    #[clap(long, default_value = "GET, POST, PUT", display_order(998))]
+    /// HTTP methods CORS origins may use; add DELETE to let them delete wallets
+    pub allowed_methods: String,
+
+    #[clap(long, display_order(998))]
+    /// HTTP headers CORS origins may send; any header when unset
+    pub allowed_headers: Option<String>,
+
+    #[clap(long, display_order(998))]
+    /// Allow CORS requests to carry credentials
+    pub allow_credentials: bool,
+
+    #[clap(long, requires("tls-key-path"), display_order(998))]
+    /// PEM certificate chain to serve HTTPS with; requires `--tls-key-path`
+    pub tls_cert_path: Option<PathBuf>,
+
+    #[clap(long, requires("tls-cert-path"), display_order(998))]
+    /// PEM private key of the certificate in `--tls-cert-path`
+    pub tls_key_path: Option<PathBuf>,
+
+    #[clap(long, default_value = "1048576", display_order(998))]
+    /// Largest request body accepted, in bytes
+    pub max_body_bytes: u64,
+
+    #[clap(long, default_value = "512", display_order(998))]
+    /// Most connections served at once; connections past this are closed right away
+    pub max_connections: usize,
+
+    #[clap(long, default_value = "1024", display_order(998))]
+    /// Connections the operating system queues up while the daemon is busy accepting others
+    pub listen_backlog: u32,
+
+    #[clap(long, default_value = "gzip", display_order(998))]
+    /// Compression for responses, when the client accepts it: "gzip", "brotli", or "off"; a client accepting only the other algorithm gets that one instead
+    pub compression: Compression,
+
+    #[clap(long, default_value = "1024", display_order(998))]
+    /// Smallest response body compressed, in bytes
+    pub compression_min_bytes: u64,
+
+    #[clap(long, display_order(998))]
+    /// Bearer token that API requests must carry; no authentication when unset, in which case `/shutdown` is not served either
+    pub auth_token: Option<String>,
+
+    #[clap(long, default_value = "5", display_order(5))]
+    /// Seconds to cache the cross-wallet coin listing served at `/coins`
+    pub coin_cache_secs: u64,
+
+    #[clap(long, default_value = "60", display_order(6))]
+    /// Seconds to wait before rebroadcasting a pending transaction again
+    pub retransmit_window_secs: u64,
+
+    #[clap(long, default_value = "15000", display_order(7))]
+    /// Milliseconds between rounds of syncing wallets with the network
+    pub confirm_interval_ms: u64,
+
+    #[clap(long, default_value = "8", display_order(8))]
+    /// How many wallets to sync with the network at once
+    pub confirm_concurrency: usize,
+
+    #[clap(long, default_value = "120", display_order(9))]
+    /// Seconds that coins picked for a prepared transaction stay reserved for it
+    pub coin_reservation_secs: u64,
+
+    #[clap(long, default_value = "^[A-Za-z0-9_]+$", display_order(10))]
+    /// Regex that wallet names must match
+    pub wallet_name_pattern: String,
+
+    #[clap(long, requires("trust-hash"), display_order(11))]
+    /// Block height to trust instead of the built-in checkpoint; requires `--trust-hash`
+    pub trust_height: Option<u64>,
+
+    #[clap(long, requires("trust-height"), display_order(12))]
+    /// Hex-encoded header hash of the block at `--trust-height`
+    pub trust_hash: Option<String>,
+
+    #[clap(long, default_value = "1001000000", display_order(13))]
+    /// Most micromel a single faucet draw may ask for, on networks with a faucet
+    pub faucet_max_value: u128,
+
+    #[clap(long, default_value = "60", display_order(14))]
+    /// Seconds a wallet must wait between faucet draws
+    pub faucet_interval_secs: u64,
+
+    #[clap(long, default_value = "3", display_order(15))]
+    /// Most times a call to the full node is attempted before its error is returned; retries back off exponentially
+    pub node_retry_attempts: u32,
+
+    #[clap(long, display_order(16))]
+    /// Blocks a sent transaction may stay unconfirmed before the wallet gives up on it, freeing its inputs and forgetting its outputs; defaults to 10 on mainnet and testnet, and 100 on custom networks
+    pub commit_timeout_blocks: Option<u64>,
+
+    #[clap(long, default_value = "3", display_order(17))]
+    /// Blocks a transaction may stay unconfirmed before wallets with auto-bump on replace it, if it pays less than the current fee; should be below the commit timeout
+    pub auto_bump_after_blocks: u64,
+
+    #[clap(long, default_value = "largest-first", display_order(18))]
+    /// How inputs are picked for a transaction: "largest-first" uses the fewest inputs and so pays the least fee, "smallest-first" spends dust and keeps the coin count down, and "branch-and-bound" leaves the least change
+    pub coin_selection: CoinSelection,
+
+    #[clap(long, default_value = "100", display_order(19))]
+    /// Unspent coins of one denomination past which `/wallets/:name/utxo-count` flags a wallet as due for consolidation
+    pub fragmentation_threshold: usize,
+
+    #[clap(long, display_order(998))]
+    /// Log filter directives, like `RUST_LOG`; defaults to `RUST_LOG`, or `melwalletd=debug,warn`
+    pub log_level: Option<String>,
+
+    #[clap(long, display_order(998))]
+    /// Serve the wallet directory of another daemon without ever writing to it; secrets are read once at startup
+    pub read_only: bool,
 
     #[serde(skip_serializing)]
     #[clap(long, display_order(998))]
-    /// 
+    /// path to a YAML config file, used instead of the other flags
     pub config: Option<String>,
 
     #[serde(skip_serializing)]
@@ -55,45 +173,171 @@ pub struct Args {
     pub dry_run: bool,
 }
 
-#[derive(Deserialize, Debug, Serialize)]
+#[derive(Deserialize, Debug, Serialize, Clone)]
 pub struct Config {
     pub wallet_dir: PathBuf,
     pub listen: SocketAddr,
     pub network_addr: SocketAddr,
+    #[serde(default)]
+    pub proxy: Option<SocketAddr>,
     pub allowed_origins: Vec<String>,
+    #[serde(default = "default_allowed_methods")]
+    pub allowed_methods: String,
+    #[serde(default)]
+    pub allowed_headers: Option<String>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+    #[serde(default)]
+    pub auth_token: Option<String>,
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: u64,
+    #[serde(default = "default_max_connections")]
+    pub max_connections: usize,
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    #[serde(default = "default_compression")]
+    pub compression: Compression,
+    #[serde(default = "default_compression_min_bytes")]
+    pub compression_min_bytes: u64,
+    #[serde(default)]
+    pub tls_cert_path: Option<PathBuf>,
+    #[serde(default)]
+    pub tls_key_path: Option<PathBuf>,
     pub network: NetID,
+    #[serde(default = "default_coin_cache_secs")]
+    pub coin_cache_secs: u64,
+    #[serde(default = "default_retransmit_window_secs")]
+    pub retransmit_window_secs: u64,
+    #[serde(default = "default_confirm_interval_ms")]
+    pub confirm_interval_ms: u64,
+    #[serde(default = "default_confirm_concurrency")]
+    pub confirm_concurrency: usize,
+    #[serde(default = "default_coin_reservation_secs")]
+    pub coin_reservation_secs: u64,
+    #[serde(default = "default_wallet_name_pattern")]
+    pub wallet_name_pattern: String,
+    #[serde(default)]
+    pub trust_height: Option<u64>,
+    #[serde(default)]
+    pub trust_hash: Option<String>,
+    #[serde(default = "default_faucet_max_value")]
+    pub faucet_max_value: u128,
+    #[serde(default = "default_faucet_interval_secs")]
+    pub faucet_interval_secs: u64,
+    #[serde(default = "default_node_retry_attempts")]
+    pub node_retry_attempts: u32,
+    #[serde(default)]
+    pub commit_timeout_blocks: Option<u64>,
+    #[serde(default = "default_auto_bump_after_blocks")]
+    pub auto_bump_after_blocks: u64,
+    #[serde(default)]
+    pub coin_selection: CoinSelection,
+    #[serde(default = "default_fragmentation_threshold")]
+    pub fragmentation_threshold: usize,
+    #[serde(default)]
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub read_only: bool,
 }
+
 impl Config {
-    fn new(
-        wallet_dir: PathBuf,
-        listen: SocketAddr,
-        allowed_origins: Vec<String>,
-        network_addr: SocketAddr,
-        network: NetID,
-    ) -> Config {
-        Config {
-            wallet_dir,
-            listen,
-            network_addr,
-            allowed_origins,
-            network,
-        }
+    /// Reads a configuration from a YAML file.
+    pub fn load(filename: &str) -> anyhow::Result<Self> {
+        let mut config_file = File::open(filename)?;
+        let mut buf: String = "".into();
+        config_file.read_to_string(&mut buf)?;
+        Ok(serde_yaml::from_str(&buf)?)
+    }
+
+    /// Blocks a sent transaction may stay unconfirmed before it is given up on, falling back to a default for the network.
+    pub fn commit_timeout_blocks(&self) -> u64 {
+        self.commit_timeout_blocks
+            .unwrap_or_else(|| default_commit_timeout_blocks(self.network))
     }
 }
 
+fn default_allowed_methods() -> String {
+    "GET, POST, PUT".into()
+}
+
+fn default_max_body_bytes() -> u64 {
+    1 << 20
+}
+
+fn default_max_connections() -> usize {
+    512
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+fn default_compression() -> Compression {
+    Compression::Gzip
+}
+
+fn default_compression_min_bytes() -> u64 {
+    1024
+}
+
+fn default_coin_cache_secs() -> u64 {
+    5
+}
+
+fn default_retransmit_window_secs() -> u64 {
+    60
+}
+
+fn default_confirm_interval_ms() -> u64 {
+    15000
+}
+
+fn default_confirm_concurrency() -> usize {
+    8
+}
+
+fn default_coin_reservation_secs() -> u64 {
+    120
+}
+
+fn default_faucet_max_value() -> u128 {
+    1001000000
+}
+
+fn default_faucet_interval_secs() -> u64 {
+    60
+}
+
+fn default_node_retry_attempts() -> u32 {
+    3
+}
+
+fn default_auto_bump_after_blocks() -> u64 {
+    3
+}
+
+fn default_fragmentation_threshold() -> usize {
+    100
+}
+
+fn default_commit_timeout_blocks(network: NetID) -> u64 {
+    match network {
+        NetID::Mainnet | NetID::Testnet => 10,
+        // custom networks are often slow or bursty, so transactions get more time there
+        _ => 100,
+    }
+}
+
+fn default_wallet_name_pattern() -> String {
+    "^[A-Za-z0-9_]+$".into()
+}
+
 impl TryFrom<Args> for Config {
     type Error = anyhow::Error;
 
     fn try_from(cmd: Args) -> Result<Self, Self::Error> {
         match cmd.config {
-            Some(filename) => {
-                let mut config_file = File::open(filename)?;
-                let mut buf: String = "".into();
-                config_file.read_to_string(&mut buf)?;
-                let config: Config = serde_yaml::from_str(&buf)?;
-
-                anyhow::Ok(config)
-            }
+            Some(filename) => Config::load(&filename),
             None => {
                 let args = cmd;
                 let network = args.network;
@@ -106,13 +350,42 @@ impl TryFrom<Args> for Config {
                             "No bootstrap nodes available for network: {network:?}"
                         )
                     });
-                Ok(Config::new(
-                    args.wallet_dir.unwrap(),
-                    args.listen,
-                    args.allowed_origin,
+                Ok(Config {
+                    wallet_dir: args.wallet_dir.unwrap(),
+                    listen: args.listen,
                     network_addr,
+                    proxy: args.proxy,
+                    allowed_origins: args.allowed_origin,
+                    allowed_methods: args.allowed_methods,
+                    allowed_headers: args.allowed_headers,
+                    allow_credentials: args.allow_credentials,
+                    auth_token: args.auth_token,
+                    max_body_bytes: args.max_body_bytes,
+                    max_connections: args.max_connections,
+                    listen_backlog: args.listen_backlog,
+                    compression: args.compression,
+                    compression_min_bytes: args.compression_min_bytes,
+                    tls_cert_path: args.tls_cert_path,
+                    tls_key_path: args.tls_key_path,
                     network,
-                ))
+                    coin_cache_secs: args.coin_cache_secs,
+                    retransmit_window_secs: args.retransmit_window_secs,
+                    confirm_interval_ms: args.confirm_interval_ms,
+                    confirm_concurrency: args.confirm_concurrency,
+                    coin_reservation_secs: args.coin_reservation_secs,
+                    wallet_name_pattern: args.wallet_name_pattern,
+                    trust_height: args.trust_height,
+                    trust_hash: args.trust_hash,
+                    faucet_max_value: args.faucet_max_value,
+                    faucet_interval_secs: args.faucet_interval_secs,
+                    node_retry_attempts: args.node_retry_attempts,
+                    commit_timeout_blocks: args.commit_timeout_blocks,
+                    auto_bump_after_blocks: args.auto_bump_after_blocks,
+                    coin_selection: args.coin_selection,
+                    fragmentation_threshold: args.fragmentation_threshold,
+                    log_level: args.log_level,
+                    read_only: args.read_only,
+                })
             }
         }
     }
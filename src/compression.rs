@@ -0,0 +1,189 @@
+use std::{io::Write, str::FromStr};
+
+use serde::{Deserialize, Serialize};
+use tide::{Body, Middleware, Next, Request, StatusCode};
+
+/// How responses are compressed, for clients that accept it.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    /// Never compress.
+    Off,
+    Gzip,
+    /// Smaller than gzip for JSON, but slower to compress.
+    Brotli,
+}
+
+impl Compression {
+    /// The name of the encoding in `Accept-Encoding` and `Content-Encoding`.
+    fn token(self) -> &'static str {
+        match self {
+            Compression::Off => "identity",
+            Compression::Gzip => "gzip",
+            Compression::Brotli => "br",
+        }
+    }
+
+    fn compress(self, bytes: &[u8]) -> std::io::Result<Vec<u8>> {
+        match self {
+            Compression::Off => Ok(bytes.to_vec()),
+            Compression::Gzip => {
+                let mut encoder =
+                    flate2::write::GzEncoder::new(vec![], flate2::Compression::default());
+                encoder.write_all(bytes)?;
+                encoder.finish()
+            }
+            Compression::Brotli => {
+                // quality 5 of 11 gets most of the size reduction for a fraction of the time
+                let mut encoder = brotli::CompressorWriter::new(vec![], 4096, 5, 22);
+                encoder.write_all(bytes)?;
+                encoder.flush()?;
+                Ok(encoder.into_inner())
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Compression::Off),
+            "gzip" => Ok(Compression::Gzip),
+            "brotli" => Ok(Compression::Brotli),
+            other => anyhow::bail!(
+                "unknown compression {:?}; expected off, gzip, or brotli",
+                other
+            ),
+        }
+    }
+}
+
+/// Whether an `Accept-Encoding` header allows an encoding, going by its name or `*` and ignoring anything with a zero quality.
+fn accepts(accept_encoding: &str, token: &str) -> bool {
+    accept_encoding.split(',').any(|entry| {
+        let mut parts = entry.split(';').map(str::trim);
+        let name = parts.next().unwrap_or_default();
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|q| q.parse::<f32>().ok())
+                .map(|q| q == 0.0)
+                .unwrap_or(false)
+        });
+        (name.eq_ignore_ascii_case(token) || name == "*") && !refused
+    })
+}
+
+/// Middleware compressing response bodies of at least `min_bytes` for clients that accept the preferred encoding, or failing that the other one. Streamed bodies, such as server-sent events, are left alone, since they have no length up front and must reach the client as they are produced.
+pub struct Compress {
+    preferred: Compression,
+    min_bytes: u64,
+}
+
+impl Compress {
+    pub fn new(preferred: Compression, min_bytes: u64) -> Self {
+        Self {
+            preferred,
+            min_bytes,
+        }
+    }
+
+    /// Picks the encoding to use, given the request's `Accept-Encoding` header.
+    fn pick(&self, accept_encoding: &str) -> Option<Compression> {
+        let fallback = match self.preferred {
+            Compression::Off => return None,
+            Compression::Gzip => Compression::Brotli,
+            Compression::Brotli => Compression::Gzip,
+        };
+        [self.preferred, fallback]
+            .iter()
+            .copied()
+            .find(|encoding| accepts(accept_encoding, encoding.token()))
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Compress {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let accept_encoding = req
+            .header("Accept-Encoding")
+            .map(|values| values.as_str().to_string())
+            .unwrap_or_default();
+        let mut res = next.run(req).await;
+        let encoding = match self.pick(&accept_encoding) {
+            Some(encoding) => encoding,
+            None => return Ok(res),
+        };
+        let big_enough = res
+            .len()
+            .map(|len| len as u64 >= self.min_bytes)
+            .unwrap_or(false);
+        let streaming = res
+            .content_type()
+            .map(|mime| mime.essence() == "text/event-stream")
+            .unwrap_or(false);
+        let bodyless = matches!(
+            res.status(),
+            StatusCode::NoContent | StatusCode::NotModified
+        );
+        if !big_enough || streaming || bodyless || res.header("Content-Encoding").is_some() {
+            return Ok(res);
+        }
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let compressed = encoding.compress(&body.into_bytes().await?)?;
+        let mut body = Body::from_bytes(compressed);
+        body.set_mime(mime);
+        res.set_body(body);
+        res.insert_header("Content-Encoding", encoding.token());
+        res.append_header("Vary", "Accept-Encoding");
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use tide::http::{Method, Request as HttpRequest, Response as HttpResponse, Url};
+
+    use super::*;
+
+    fn get(path: &str, accept_encoding: &str) -> HttpRequest {
+        let mut req = HttpRequest::new(
+            Method::Get,
+            Url::parse(&format!("http://localhost{}", path)).unwrap(),
+        );
+        req.insert_header("Accept-Encoding", accept_encoding);
+        req
+    }
+
+    #[test]
+    fn compresses_large_bodies_only() {
+        let mut app = tide::new();
+        app.with(Compress::new(Compression::Brotli, 100));
+        app.at("/big")
+            .get(|_| async { Ok("[0,0,0,0,0,0,0,0,0,0]".repeat(100)) });
+        app.at("/small").get(|_| async { Ok("[]") });
+        smol::block_on(async {
+            let mut res: HttpResponse = app.respond(get("/big", "gzip, br;q=0")).await.unwrap();
+            assert_eq!(res.header("Content-Encoding").unwrap(), "gzip");
+            let compressed = res.body_bytes().await.unwrap();
+            assert!(compressed.len() < 100);
+            let mut decoded = String::new();
+            flate2::read::GzDecoder::new(&compressed[..])
+                .read_to_string(&mut decoded)
+                .unwrap();
+            assert_eq!(decoded, "[0,0,0,0,0,0,0,0,0,0]".repeat(100));
+
+            let res: HttpResponse = app.respond(get("/big", "br")).await.unwrap();
+            assert_eq!(res.header("Content-Encoding").unwrap(), "br");
+            let res: HttpResponse = app.respond(get("/small", "br")).await.unwrap();
+            assert!(res.header("Content-Encoding").is_none());
+            let res: HttpResponse = app.respond(get("/big", "identity")).await.unwrap();
+            assert!(res.header("Content-Encoding").is_none());
+        });
+    }
+}
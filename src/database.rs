@@ -1,25 +1,39 @@
 use std::{
-    collections::{BTreeMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashSet},
     future::Future,
     path::Path,
-    time::Instant,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::Context;
 use binary_search::Direction;
 use rusqlite::{params, OptionalExtension};
 use stdcode::StdcodeSerializeExt;
-use themelio_nodeprot::ValClientSnapshot;
+use themelio_nodeprot::{TrustedHeight, ValClientSnapshot};
 use themelio_stf::melvm::{covenant_weight_from_bytes, Covenant};
 use themelio_structs::{
-    Address, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, Transaction, TxHash,
-    TxKind,
+    Address, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, NetID, Transaction,
+    TxHash, TxKind, STAKE_EPOCH,
 };
+use tmelcrypt::Hashable;
 
 use self::pool::ConnPool;
+use crate::{
+    selection::CoinSelector,
+    subaddress::{derive_subaddress, MAX_SUBADDRESS_INDEX},
+    template::TxTemplate,
+};
 
 mod pool;
 
+/// The current time, in seconds since the unix epoch.
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("clock before unix epoch")
+        .as_secs()
+}
+
 /// A database that holds wallets.
 #[derive(Clone)]
 pub struct Database {
@@ -66,14 +80,101 @@ impl Database {
             "create table if not exists transactions (txhash primary key, txblob not null)",
             [],
         )?;
+        // the block height at which each transaction was sent
+        conn.execute(
+            "create table if not exists sent_heights (txhash primary key, height not null)",
+            [],
+        )?;
+        // transactions given up on by the user
+        conn.execute(
+            "create table if not exists abandoned (txhash primary key)",
+            [],
+        )?;
+        // user-given labels of transactions, per wallet
+        conn.execute(
+            "create table if not exists tx_labels (covhash not null, txhash not null, label not null, primary key (covhash, txhash))",
+            [],
+        )?;
+        // when each pending transaction was last rebroadcast, in unix seconds
+        conn.execute(
+            "create table if not exists retransmissions (txhash primary key, last_sent not null)",
+            [],
+        )?;
+        // coins set aside for prepared transactions, with expiration in unix seconds
+        conn.execute(
+            "create table if not exists reserved_coins (coinid primary key, expires not null)",
+            [],
+        )?;
+        // how many faucet transactions each address has drawn, for deriving unique nonces
+        conn.execute(
+            "create table if not exists faucet_counters (covhash primary key, counter not null)",
+            [],
+        )?;
+        // coins that automatic input selection must never spend
+        conn.execute(
+            "create table if not exists frozen_coins (coinid primary key)",
+            [],
+        )?;
+        // multisignature transactions still waiting on signatures
+        conn.execute(
+            "create table if not exists partial_transactions (txhash primary key, txblob not null)",
+            [],
+        )?;
         // wallets by name
         conn.execute(
             "create table if not exists wallet_names (name primary key, covhash not null, covenant not null)",
             [],
         )?;
+        // user-given tags of wallets, for organizing many of them
+        conn.execute(
+            "create table if not exists wallet_tags (name not null, tag not null, primary key (name, tag))",
+            [],
+        )?;
+        // wallets whose transactions stuck on a too-low fee get replaced by ones paying more
+        conn.execute(
+            "create table if not exists auto_bump_wallets (name primary key)",
+            [],
+        )?;
+        // transactions sent to replace ones paying too low a fee
+        conn.execute(
+            "create table if not exists replacements (txhash primary key, replaced not null)",
+            [],
+        )?;
+        // the latest verified header of each network, along with the trust root it was verified from
+        conn.execute(
+            "create table if not exists verified_headers (network primary key, height not null, header_hash not null, root_height not null, root_hash not null)",
+            [],
+        )?;
+        // the height each network's wallets were last synced at, for telling confirmations while the node is unreachable
+        conn.execute(
+            "create table if not exists synced_heights (network primary key, height not null)",
+            [],
+        )?;
+        // derived subaddresses of each wallet, all of which get watched
+        conn.execute(
+            "create table if not exists subaddresses (name not null, idx not null, covhash not null, covenant not null, primary key (name, idx))",
+            [],
+        )?;
+        // heights from which coins locked by a stake may be spent
+        conn.execute(
+            "create table if not exists coin_maturities (coinid primary key, spendable_at not null)",
+            [],
+        )?;
+        // named transaction templates of each wallet, as JSON
+        conn.execute(
+            "create table if not exists tx_templates (name not null, template not null, body not null, primary key (name, template))",
+            [],
+        )?;
         Ok(Database { pool })
     }
 
+    /// Opens an existing database without ever writing to it, e.g. while another daemon is using it.
+    pub async fn open_read_only(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        Ok(Database {
+            pool: ConnPool::open_read_only(path)?,
+        })
+    }
+
     /// List wallet names.
     pub async fn list_wallets(&self) -> Vec<String> {
         let conn = self.pool.get_conn().await;
@@ -115,29 +216,532 @@ impl Database {
         Ok(())
     }
 
-    /// Retransmit pending transactions
-    pub async fn retransmit_pending(&self, snapshot: ValClientSnapshot) -> anyhow::Result<()> {
+    /// Creates a wallet that only knows its address, not the covenant behind it.
+    pub async fn create_address_wallet(&self, name: &str, covhash: Address) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
+        conn.execute(
+            "insert into wallet_names values ($1, $2, $3)",
+            params![name, covhash.to_string(), Vec::<u8>::new()],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes a wallet. Returns false if no such wallet existed.
+    pub async fn delete_wallet(&self, name: &str) -> anyhow::Result<bool> {
+        let mut conn = self.pool.get_conn().await;
+        let conn = conn.transaction()?;
+        let deleted = conn.execute("delete from wallet_names where name = $1", params![name])?;
+        conn.execute("delete from wallet_tags where name = $1", params![name])?;
+        conn.execute(
+            "delete from auto_bump_wallets where name = $1",
+            params![name],
+        )?;
+        conn.execute("delete from subaddresses where name = $1", params![name])?;
+        conn.execute("delete from tx_templates where name = $1", params![name])?;
+        conn.commit()?;
+        Ok(deleted > 0)
+    }
+
+    /// Replaces any wallet of this name with one recreated from a dump, all at once. An empty covenant makes a wallet that only knows its address.
+    pub async fn restore_wallet(
+        &self,
+        name: &str,
+        covhash: Address,
+        covenant: Vec<u8>,
+        labels: &BTreeMap<TxHash, String>,
+        tags: &BTreeSet<String>,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.pool.get_conn().await;
+        let conn = conn.transaction()?;
+        conn.execute("delete from wallet_names where name = $1", params![name])?;
+        conn.execute("delete from wallet_tags where name = $1", params![name])?;
+        conn.execute(
+            "delete from auto_bump_wallets where name = $1",
+            params![name],
+        )?;
+        conn.execute("delete from subaddresses where name = $1", params![name])?;
+        conn.execute("delete from tx_templates where name = $1", params![name])?;
+        conn.execute(
+            "insert into wallet_names values ($1, $2, $3)",
+            params![name, covhash.to_string(), covenant],
+        )?;
+        for (txhash, label) in labels.iter().filter(|(_, label)| !label.is_empty()) {
+            conn.execute(
+                "insert into tx_labels values ($1, $2, $3) on conflict (covhash, txhash) do update set label = $3",
+                params![covhash.to_string(), txhash.to_string(), label],
+            )?;
+        }
+        for tag in tags {
+            conn.execute(
+                "insert into wallet_tags values ($1, $2) on conflict do nothing",
+                params![name, tag],
+            )?;
+        }
+        conn.commit()?;
+        Ok(())
+    }
+
+    /// Renames a wallet, keeping its tags and settings. Returns false if no such wallet existed; fails if the new name is taken.
+    pub async fn rename_wallet(&self, name: &str, new_name: &str) -> anyhow::Result<bool> {
+        let mut conn = self.pool.get_conn().await;
+        let conn = conn.transaction()?;
+        let renamed = conn.execute(
+            "update wallet_names set name = $1 where name = $2",
+            params![new_name, name],
+        )?;
+        conn.execute(
+            "update wallet_tags set name = $1 where name = $2",
+            params![new_name, name],
+        )?;
+        conn.execute(
+            "update auto_bump_wallets set name = $1 where name = $2",
+            params![new_name, name],
+        )?;
+        conn.execute(
+            "update subaddresses set name = $1 where name = $2",
+            params![new_name, name],
+        )?;
+        conn.execute(
+            "update tx_templates set name = $1 where name = $2",
+            params![new_name, name],
+        )?;
+        conn.commit()?;
+        Ok(renamed > 0)
+    }
+
+    /// Whether any coin this database knows of, spent or not, was ever sent to the given address.
+    pub async fn address_seen(&self, address: Address) -> bool {
+        let conn = self.pool.get_conn().await;
+        conn.query_row(
+            "select 1 from coins where covhash = $1 limit 1",
+            params![address.to_string()],
+            |_| Ok(()),
+        )
+        .optional()
+        .unwrap()
+        .is_some()
+    }
+
+    /// The latest header verified on a network, along with the trust root it was verified from.
+    pub async fn verified_header(&self, network: NetID) -> Option<VerifiedHeader> {
+        let conn = self.pool.get_conn().await;
+        let (height, header_hash, root_height, root_hash): (u64, String, u64, String) = conn
+            .query_row(
+                "select height, header_hash, root_height, root_hash from verified_headers where network = $1",
+                params![network as u8],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)),
+            )
+            .optional()
+            .unwrap()?;
+        Some(VerifiedHeader {
+            header: TrustedHeight {
+                height: BlockHeight(height),
+                header_hash: header_hash.parse().expect("malformed header hash in db"),
+            },
+            root: TrustedHeight {
+                height: BlockHeight(root_height),
+                header_hash: root_hash.parse().expect("malformed header hash in db"),
+            },
+        })
+    }
+
+    /// Records a newly verified header of a network, unless a later one is already recorded from the same root.
+    pub async fn save_verified_header(
+        &self,
+        network: NetID,
+        header: &TrustedHeight,
+        root: &TrustedHeight,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
+        conn.execute(
+            "insert into verified_headers values ($1, $2, $3, $4, $5) on conflict (network) do update set height = $2, header_hash = $3, root_height = $4, root_hash = $5 where height < $2 or root_height != $4 or root_hash != $5",
+            params![
+                network as u8,
+                header.height.0,
+                header.header_hash.to_string(),
+                root.height.0,
+                root.header_hash.to_string()
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// The height the wallets of a network were last synced at.
+    pub async fn synced_height(&self, network: NetID) -> Option<BlockHeight> {
+        let conn = self.pool.get_conn().await;
+        let height: Option<u64> = conn
+            .query_row(
+                "select height from synced_heights where network = $1",
+                params![network as u8],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+        height.map(BlockHeight)
+    }
+
+    /// Records the height the wallets of a network were synced at, unless a later one is already recorded.
+    pub async fn save_synced_height(
+        &self,
+        network: NetID,
+        height: BlockHeight,
+    ) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
+        conn.execute(
+            "insert into synced_heights values ($1, $2) on conflict (network) do update set height = $2 where height < $2",
+            params![network as u8, height.0],
+        )?;
+        Ok(())
+    }
+
+    /// Writes everything in the write-ahead log back into the database file.
+    pub async fn flush(&self) -> rusqlite::Result<()> {
+        let conn = self.pool.get_conn().await;
+        conn.query_row("pragma wal_checkpoint(TRUNCATE)", [], |_| Ok(()))
+    }
+
+    /// Counts the pending transactions across all wallets.
+    pub async fn count_pending(&self) -> u64 {
+        let conn = self.pool.get_conn().await;
+        conn.query_row("select count(txhash) from pending", params![], |r| r.get(0))
+            .unwrap()
+    }
+
+    /// Retransmit pending transactions that haven't been retransmitted within the given window.
+    pub async fn retransmit_pending(
+        &self,
+        snapshot: ValClientSnapshot,
+        window: Duration,
+    ) -> anyhow::Result<()> {
+        let now = unix_now();
         let mut conn = self.pool.get_conn().await;
         let txn = conn.transaction()?;
-        let mut stmt =
-            txn.prepare_cached("select txblob from pending natural join transactions")?;
-        let mut rows = stmt.query(params![]).unwrap();
-        while let Ok(Some(row)) = rows.next() {
-            let blob: Vec<u8> = row.get(0)?;
-            let txn: Transaction = stdcode::deserialize(&blob)?;
-            log::debug!("retransmit {}", txn.hash_nosigs());
+        // forget retransmissions that are stale, or of transactions no longer pending
+        txn.execute(
+            "delete from retransmissions where last_sent < $1 or not exists (select txhash from pending where pending.txhash = retransmissions.txhash)",
+            params![now.saturating_sub(window.as_secs())],
+        )?;
+        let mut to_send = vec![];
+        {
+            let mut stmt = txn.prepare_cached(
+                "select txblob from pending natural join transactions where not exists (select txhash from retransmissions where retransmissions.txhash = pending.txhash)",
+            )?;
+            let mut rows = stmt.query(params![]).unwrap();
+            while let Ok(Some(row)) = rows.next() {
+                let blob: Vec<u8> = row.get(0)?;
+                let tx: Transaction = stdcode::deserialize(&blob)?;
+                to_send.push(tx);
+            }
+        }
+        for tx in to_send.iter() {
+            txn.execute(
+                "insert into retransmissions values ($1, $2)",
+                params![tx.hash_nosigs().to_string(), now],
+            )?;
+        }
+        txn.commit()?;
+        for tx in to_send {
+            log::debug!("retransmit {}", tx.hash_nosigs());
             let snapshot = snapshot.clone();
             smolscale::spawn(async move {
-                if let Err(err) = snapshot.get_raw().send_tx(txn).await {
+                if let Err(err) = snapshot.get_raw().send_tx(tx).await {
                     log::warn!("error retransmitting: {:?}", err);
                 }
             })
             .detach();
         }
-        drop(rows);
-        drop(stmt);
         Ok(())
     }
+
+    /// Lists the transactions recently retransmitted, with when they were last sent, in unix seconds.
+    pub async fn list_inflight(&self) -> Vec<(TxHash, u64)> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached("select txhash, last_sent from retransmissions")
+            .unwrap();
+        let rows = stmt
+            .query_map(params![], |row| {
+                let txhash: String = row.get(0)?;
+                Ok((txhash.parse().expect("malformed txhash in db"), row.get(1)?))
+            })
+            .unwrap();
+        rows.collect::<Result<Vec<_>, _>>().unwrap()
+    }
+}
+
+/// Abandons a pending transaction within an open database transaction. Returns false if the transaction was not pending.
+fn abandon_pending(conn: &rusqlite::Connection, txhash: TxHash) -> anyhow::Result<bool> {
+    let deleted = conn.execute(
+        "delete from pending where txhash = $1",
+        params![txhash.to_string()],
+    )?;
+    if deleted == 0 {
+        return Ok(false);
+    }
+    conn.execute(
+        "delete from reserved_coins where exists (select coinid from spends where spends.coinid = reserved_coins.coinid and txhash = $1)",
+        params![txhash.to_string()],
+    )?;
+    conn.execute(
+        "delete from spends where txhash = $1",
+        params![txhash.to_string()],
+    )?;
+    conn.execute(
+        "delete from pending_coins where txhash = $1",
+        params![txhash.to_string()],
+    )?;
+    conn.execute(
+        "delete from retransmissions where txhash = $1",
+        params![txhash.to_string()],
+    )?;
+    conn.execute(
+        "insert into abandoned values ($1) on conflict do nothing",
+        params![txhash.to_string()],
+    )?;
+    Ok(true)
+}
+
+/// Records a transaction as sent within an open database transaction, spending its inputs and adding it to the pending transactions.
+fn record_sent(
+    conn: &rusqlite::Connection,
+    txn: &Transaction,
+    sent_height: BlockHeight,
+    timeout: BlockHeight,
+) -> anyhow::Result<()> {
+    // ensure that every input is available
+    for input in txn.inputs.iter() {
+        if conn
+            .query_row(
+                "select height from coin_confirmations where coinid = $1",
+                params![input.to_string()],
+                |_| Ok(()),
+            )
+            .optional()?
+            .is_none()
+        {
+            anyhow::bail!("input {} no longer in wallet", input)
+        }
+    }
+    // add the transaction to the cache
+    let txhash = txn.hash_nosigs();
+    conn.execute(
+        "insert into transactions values ($1, $2) on conflict do nothing",
+        params![txhash.to_string(), txn.stdcode()],
+    )?;
+    // spend everything, which makes any reservations moot
+    for input in txn.inputs.iter() {
+        conn.execute(
+            "insert into spends values ($1, $2)",
+            params![input.to_string(), txhash.to_string()],
+        )?;
+        conn.execute(
+            "delete from reserved_coins where coinid = $1",
+            params![input.to_string()],
+        )?;
+    }
+
+    // ONLY do this if this is a NORMAL transaction. Otherwise transmutation will invalidate these coins BADLY.
+    if txn.kind == TxKind::Normal {
+        for (i, output) in txn.outputs.iter().enumerate() {
+            let coinid = txn.output_coinid(i as u8);
+            let denom = if output.denom == Denom::NewCoin {
+                Denom::Custom(txn.hash_nosigs())
+            } else {
+                output.denom
+            };
+            conn.execute(
+                "insert into coins values ($1, $2, $3, $4, $5) on conflict do nothing",
+                params![
+                    coinid.to_string(),
+                    output.covhash.to_string(),
+                    output.value.0.to_string(),
+                    denom.to_bytes(),
+                    output.additional_data.clone()
+                ],
+            )?;
+            conn.execute(
+                "insert into pending_coins values ($1, $2)",
+                params![coinid.to_string(), txn.hash_nosigs().to_string()],
+            )?;
+        }
+    }
+    // add to pending
+    conn.execute(
+        "insert into pending values ($1, $2)",
+        params![txhash.to_string(), timeout.0],
+    )?;
+    conn.execute(
+        "insert into sent_heights values ($1, $2) on conflict do nothing",
+        params![txhash.to_string(), sent_height.0],
+    )?;
+    Ok(())
+}
+
+/// Picks inputs for every denomination in `output_sum` separately with `selector` among the coins at the `spendable` addresses, starting from the mandatory inputs, then makes change to `change_to` in each denomination spent. Denominations in `nobalance` are left alone.
+#[allow(clippy::too_many_arguments)]
+fn balance_per_denom(
+    spendable: &BTreeSet<Address>,
+    change_to: Address,
+    output_sum: &BTreeMap<Denom, CoinValue>,
+    mandatory_inputs: &BTreeMap<CoinID, CoinData>,
+    unspent_coins: &BTreeMap<CoinID, CoinData>,
+    nobalance: &[Denom],
+    single_change: bool,
+    selector: &dyn CoinSelector,
+) -> anyhow::Result<(Vec<CoinID>, Vec<CoinData>)> {
+    let mut inputs = vec![];
+    let mut input_sum: BTreeMap<Denom, CoinValue> = BTreeMap::new();
+    // first we add the "mandatory" inputs
+    for (coin, data) in mandatory_inputs.iter() {
+        inputs.push(*coin);
+        *input_sum.entry(data.denom).or_default() += data.value;
+    }
+
+    // then we add other inputs of each denomination until it has enough, as picked by the selector
+    for (denom, needed) in output_sum.iter() {
+        let existing_val = input_sum.get(denom).copied().unwrap_or_default();
+        if nobalance.contains(denom) || existing_val >= *needed {
+            continue;
+        }
+        let candidates: Vec<(CoinID, CoinData)> = unspent_coins
+            .iter()
+            .filter(|(coin, data)| {
+                !mandatory_inputs.contains_key(coin)
+                    && data.denom == *denom
+                    && spendable.contains(&data.covhash)
+            })
+            .map(|(coin, data)| (*coin, data.clone()))
+            .collect();
+        let picked: HashSet<CoinID> = selector
+            .select(&candidates, *needed - existing_val)
+            .into_iter()
+            .collect();
+        for (coin, data) in candidates {
+            if picked.contains(&coin) {
+                inputs.push(coin);
+                *input_sum.entry(*denom).or_default() += data.value;
+            }
+        }
+    }
+
+    // create change outputs, for every denomination going in or out
+    let mut change = Vec::new();
+    let denoms: BTreeSet<Denom> = output_sum
+        .keys()
+        .chain(input_sum.keys())
+        .copied()
+        .filter(|d| !nobalance.contains(d))
+        .collect();
+    for denom in denoms {
+        let needed = output_sum.get(&denom).copied().unwrap_or_default();
+        let have = input_sum.get(&denom).copied().unwrap_or_default();
+        let difference = have.checked_sub(needed).with_context(|| {
+            format!(
+                "not enough money for denomination {}: need {}, have {}",
+                denom, needed, have
+            )
+        })?;
+        if difference.0 > 0 || denom == Denom::Mel {
+            // We make TWO change outputs, to maximize parallelization
+            // TODO: does this create indefinitely many UTXOs? That'd be bad
+            if difference.0 >= 2 && !single_change {
+                let first_half = difference / 2;
+                let second_half = difference - first_half;
+                change.push(CoinData {
+                    covhash: change_to,
+                    value: first_half,
+                    denom,
+                    additional_data: vec![],
+                });
+                change.push(CoinData {
+                    covhash: change_to,
+                    value: second_half,
+                    denom,
+                    additional_data: vec![],
+                })
+            } else {
+                change.push(CoinData {
+                    covhash: change_to,
+                    value: difference,
+                    denom,
+                    additional_data: vec![],
+                })
+            }
+        }
+    }
+    Ok((inputs, change))
+}
+
+/// Finds the transaction that spent a coin confirmed at the given height, by binary searching for the first block in which the coin was gone.
+pub async fn find_spender(
+    snapshot: &ValClientSnapshot,
+    coin: CoinID,
+    confirmed_height: BlockHeight,
+) -> anyhow::Result<Transaction> {
+    let mut left = confirmed_height;
+    let mut right = snapshot.current_header().height;
+    while left < right {
+        let median = (left + right) / 2;
+        log::trace!("binary search at {} ({}..{})", median, left, right);
+        if snapshot
+            .get_older(median)
+            .await?
+            .get_coin(coin)
+            .await?
+            .is_some()
+        {
+            left = median + BlockHeight(1);
+        } else {
+            right = median;
+        }
+    }
+    let spend_block = left;
+    snapshot
+        .get_older(spend_block)
+        .await?
+        .current_block()
+        .await?
+        .transactions
+        .into_iter()
+        .find(|tx| tx.inputs.contains(&coin))
+        .context("bug: digged for the coin in the wrong place")
+}
+
+/// Finds which of the given coins are locked by a stake, and the height from which each may be spent. Every output of a staking transaction stays locked for as long as the stake is on record, which ends with the stake's last epoch; no other coin has to mature before it is spent.
+pub async fn find_stake_locks(
+    snapshot: &ValClientSnapshot,
+    coins: impl IntoIterator<Item = CoinID>,
+) -> anyhow::Result<BTreeMap<CoinID, BlockHeight>> {
+    let mut by_tx: BTreeMap<TxHash, Vec<CoinID>> = BTreeMap::new();
+    for coin in coins {
+        by_tx.entry(coin.txhash).or_default().push(coin);
+    }
+    let lookups: Vec<_> = by_tx
+        .into_iter()
+        .map(|(txhash, coins)| {
+            let snapshot = snapshot.clone();
+            smolscale::spawn(async move {
+                snapshot
+                    .get_stake(txhash.0)
+                    .await
+                    .map(|stake| (stake, coins))
+            })
+        })
+        .collect();
+    let mut locks = BTreeMap::new();
+    for lookup in lookups {
+        if let (Some(stake), coins) = lookup.await? {
+            let spendable_at = BlockHeight((stake.e_post_end + 1) * STAKE_EPOCH);
+            locks.extend(coins.into_iter().map(|coin| (coin, spendable_at)));
+        }
+    }
+    Ok(locks)
+}
+
+/// A header verified by the client, and the trust root that verification started from.
+pub struct VerifiedHeader {
+    pub header: TrustedHeight,
+    pub root: TrustedHeight,
 }
 
 /// A wallet within a database
@@ -154,6 +758,15 @@ impl Wallet {
         self.covhash
     }
 
+    /// Obtains the covenant of the wallet. Watch-only wallets created from an address alone don't know it.
+    pub fn covenant(&self) -> Option<Covenant> {
+        if self.covenant.is_empty() {
+            None
+        } else {
+            Some(Covenant(self.covenant.clone()))
+        }
+    }
+
     /// Obtains a transaction, whether cached or not. Must provide a snapshot to retrieve non-cached transactions.
     pub async fn get_transaction(
         &self,
@@ -191,40 +804,348 @@ impl Wallet {
             return Ok(None);
         };
         // now we can actually put it back into the cache so that next time we don't need to do all this.
+        // a read-only database can't keep the cache, which is fine, since it's only there to save work
+        let conn = self.pool.get_conn().await;
+        if let Err(err) = conn.execute(
+            "insert into transactions values ($1, $2) on conflict do nothing",
+            params![txhash.to_string(), txn.stdcode()],
+        ) {
+            log::debug!("could not cache transaction {}: {}", txhash, err);
+        }
+        Ok(Some(txn))
+    }
+
+    /// Obtains a cached transaction.
+    pub async fn get_cached_transaction(&self, txhash: TxHash) -> Option<Transaction> {
+        let conn = self.pool.get_conn().await;
+        let blob: Vec<u8> = conn
+            .query_row(
+                "select txblob from transactions where txhash = $1",
+                params![txhash.to_string()],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap()?;
+        let txn: Transaction = stdcode::deserialize(&blob).unwrap();
+        Some(txn)
+    }
+
+    /// Obtains a transaction that is still collecting signatures.
+    pub async fn get_partial_transaction(&self, txhash: TxHash) -> Option<Transaction> {
+        let conn = self.pool.get_conn().await;
+        let blob: Vec<u8> = conn
+            .query_row(
+                "select txblob from partial_transactions where txhash = $1",
+                params![txhash.to_string()],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap()?;
+        Some(stdcode::deserialize(&blob).unwrap())
+    }
+
+    /// Saves a transaction that is still collecting signatures, replacing any earlier version.
+    pub async fn store_partial_transaction(&self, txn: &Transaction) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
+        conn.execute(
+            "insert into partial_transactions values ($1, $2) on conflict(txhash) do update set txblob = excluded.txblob",
+            params![txn.hash_nosigs().to_string(), txn.stdcode()],
+        )?;
+        Ok(())
+    }
+
+    /// Forgets a transaction that was collecting signatures.
+    pub async fn remove_partial_transaction(&self, txhash: TxHash) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
+        conn.execute(
+            "delete from partial_transactions where txhash = $1",
+            params![txhash.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Obtains the pending transactions that involve this wallet, along with the height they were sent at, if known.
+    pub async fn get_pending_transactions(&self) -> Vec<(Transaction, Option<BlockHeight>)> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached(
+                r"select txblob, height from
+        pending natural join transactions left join sent_heights
+        on pending.txhash = sent_heights.txhash",
+            )
+            .unwrap();
+        let mut rows = stmt.query(params![]).unwrap();
+        let mut toret = vec![];
+        while let Ok(Some(row)) = rows.next() {
+            let blob: Vec<u8> = row.get(0).unwrap();
+            let height: Option<u64> = row.get(1).unwrap();
+            let txn: Transaction = stdcode::deserialize(&blob).unwrap();
+            let involves_us = txn.covenants.iter().any(|c| c.hash() == self.covhash.0)
+                || txn.outputs.iter().any(|o| o.covhash == self.covhash);
+            if involves_us {
+                toret.push((txn, height.map(|h| h.into())));
+            }
+        }
+        toret
+    }
+
+    /// Check whether a particular txhash is pending.
+    pub async fn is_pending(&self, txhash: TxHash) -> bool {
+        let conn = self.pool.get_conn().await;
+        conn.query_row(
+            "select txhash from pending where txhash = $1",
+            params![txhash.to_string()],
+            |_| Ok(()),
+        )
+        .optional()
+        .unwrap()
+        .is_some()
+    }
+
+    /// Check whether a particular txhash was abandoned.
+    pub async fn is_abandoned(&self, txhash: TxHash) -> bool {
+        let conn = self.pool.get_conn().await;
+        conn.query_row(
+            "select txhash from abandoned where txhash = $1",
+            params![txhash.to_string()],
+            |_| Ok(()),
+        )
+        .optional()
+        .unwrap()
+        .is_some()
+    }
+
+    /// Gives up on a pending transaction, freeing up its inputs and forgetting its outputs. Returns false if the transaction was not pending.
+    pub async fn abandon_transaction(&self, txhash: TxHash) -> anyhow::Result<bool> {
+        let mut conn = self.pool.get_conn().await;
+        let conn = conn.transaction()?;
+        if !abandon_pending(&conn, txhash)? {
+            return Ok(false);
+        }
+        conn.commit()?;
+        Ok(true)
+    }
+
+    /// Gets the label attached to a transaction, if any.
+    pub async fn get_label(&self, txhash: TxHash) -> Option<String> {
+        let conn = self.pool.get_conn().await;
+        conn.query_row(
+            "select label from tx_labels where covhash = $1 and txhash = $2",
+            params![self.covhash.to_string(), txhash.to_string()],
+            |r| r.get(0),
+        )
+        .optional()
+        .unwrap()
+    }
+
+    /// Gets the labels of every labeled transaction.
+    pub async fn get_labels(&self) -> BTreeMap<TxHash, String> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached("select txhash, label from tx_labels where covhash = $1")
+            .unwrap();
+        let rows = stmt
+            .query_map(params![self.covhash.to_string()], |row| {
+                let txhash: String = row.get(0)?;
+                Ok((txhash.parse().expect("malformed txhash in db"), row.get(1)?))
+            })
+            .unwrap();
+        rows.collect::<Result<BTreeMap<_, _>, _>>().unwrap()
+    }
+
+    /// Attaches a label to a transaction. An empty label clears it.
+    pub async fn set_label(&self, txhash: TxHash, label: &str) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
+        if label.is_empty() {
+            conn.execute(
+                "delete from tx_labels where covhash = $1 and txhash = $2",
+                params![self.covhash.to_string(), txhash.to_string()],
+            )?;
+        } else {
+            conn.execute(
+                "insert into tx_labels values ($1, $2, $3) on conflict (covhash, txhash) do update set label = $3",
+                params![self.covhash.to_string(), txhash.to_string(), label],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Gets the tags attached to this wallet.
+    pub async fn get_tags(&self) -> BTreeSet<String> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached("select tag from wallet_tags where name = $1")
+            .unwrap();
+        let rows = stmt
+            .query_map(params![self.name], |row| row.get(0))
+            .unwrap();
+        rows.collect::<Result<BTreeSet<_>, _>>().unwrap()
+    }
+
+    /// Gets this wallet's transaction templates by name.
+    pub async fn get_templates(&self) -> BTreeMap<String, TxTemplate> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached("select template, body from tx_templates where name = $1")
+            .unwrap();
+        let rows = stmt
+            .query_map(params![self.name], |row| {
+                let template: String = row.get(0)?;
+                let body: String = row.get(1)?;
+                Ok((
+                    template,
+                    serde_json::from_str(&body).expect("malformed template in db"),
+                ))
+            })
+            .unwrap();
+        rows.collect::<Result<BTreeMap<_, _>, _>>().unwrap()
+    }
+
+    /// Gets one of this wallet's transaction templates.
+    pub async fn get_template(&self, template: &str) -> Option<TxTemplate> {
+        let conn = self.pool.get_conn().await;
+        let body: Option<String> = conn
+            .query_row(
+                "select body from tx_templates where name = $1 and template = $2",
+                params![self.name, template],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+        body.map(|body| serde_json::from_str(&body).expect("malformed template in db"))
+    }
+
+    /// Registers a transaction template under a name, replacing any template already there.
+    pub async fn put_template(&self, template: &str, body: &TxTemplate) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
+        conn.execute(
+            "insert into tx_templates values ($1, $2, $3) on conflict do update set body = $3",
+            params![self.name, template, serde_json::to_string(body)?],
+        )?;
+        Ok(())
+    }
+
+    /// Deletes a transaction template. Returns false if there was no such template.
+    pub async fn delete_template(&self, template: &str) -> anyhow::Result<bool> {
+        let conn = self.pool.get_conn().await;
+        let deleted = conn.execute(
+            "delete from tx_templates where name = $1 and template = $2",
+            params![self.name, template],
+        )?;
+        Ok(deleted > 0)
+    }
+
+    /// Whether stuck transactions of this wallet get replaced by ones paying a higher fee.
+    pub async fn auto_bump(&self) -> bool {
+        let conn = self.pool.get_conn().await;
+        conn.query_row(
+            "select name from auto_bump_wallets where name = $1",
+            params![self.name],
+            |_| Ok(()),
+        )
+        .optional()
+        .unwrap()
+        .is_some()
+    }
+
+    /// Turns the replacement of stuck transactions on or off.
+    pub async fn set_auto_bump(&self, enabled: bool) -> anyhow::Result<()> {
         let conn = self.pool.get_conn().await;
+        if enabled {
+            conn.execute(
+                "insert into auto_bump_wallets values ($1) on conflict do nothing",
+                params![self.name],
+            )?;
+        } else {
+            conn.execute(
+                "delete from auto_bump_wallets where name = $1",
+                params![self.name],
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Swaps a pending transaction for one spending the same coins, which has already been sent.
+    pub async fn replace_transaction(
+        &self,
+        replaced: TxHash,
+        replacement: Transaction,
+        sent_height: BlockHeight,
+        timeout: BlockHeight,
+    ) -> anyhow::Result<()> {
+        let txhash = replacement.hash_nosigs();
+        let mut conn = self.pool.get_conn().await;
+        let conn = conn.transaction()?;
+        if !abandon_pending(&conn, replaced)? {
+            anyhow::bail!("transaction {} is no longer pending", replaced);
+        }
+        record_sent(&conn, &replacement, sent_height, timeout)?;
         conn.execute(
-            "insert into transactions values ($1, $2) on conflict do nothing",
-            params![txhash.to_string(), txn.stdcode()],
+            "insert into replacements values ($1, $2) on conflict do nothing",
+            params![txhash.to_string(), replaced.to_string()],
         )?;
-        Ok(Some(txn))
+        conn.commit()?;
+        Ok(())
     }
 
-    /// Obtains a cached transaction.
-    pub async fn get_cached_transaction(&self, txhash: TxHash) -> Option<Transaction> {
+    /// The transaction that the given one was sent to replace, if any.
+    pub async fn get_replaced(&self, txhash: TxHash) -> Option<TxHash> {
         let conn = self.pool.get_conn().await;
-        let blob: Vec<u8> = conn
+        let replaced: Option<String> = conn
             .query_row(
-                "select txblob from transactions where txhash = $1",
+                "select replaced from replacements where txhash = $1",
                 params![txhash.to_string()],
                 |row| row.get(0),
             )
             .optional()
-            .unwrap()?;
-        let txn: Transaction = stdcode::deserialize(&blob).unwrap();
-        Some(txn)
+            .unwrap();
+        replaced.map(|replaced| replaced.parse().expect("malformed txhash in db"))
     }
 
-    /// Check whether a particular txhash is pending.
-    pub async fn is_pending(&self, txhash: TxHash) -> bool {
-        let conn = self.pool.get_conn().await;
-        conn.query_row(
-            "select txhash from pending where txhash = $1",
-            params![txhash.to_string()],
-            |_| Ok(()),
-        )
-        .optional()
-        .unwrap()
-        .is_some()
+    /// Attaches some tags to this wallet and removes others, returning the resulting tags.
+    pub async fn update_tags(
+        &self,
+        add: &BTreeSet<String>,
+        remove: &BTreeSet<String>,
+    ) -> anyhow::Result<BTreeSet<String>> {
+        {
+            let mut conn = self.pool.get_conn().await;
+            let conn = conn.transaction()?;
+            for tag in remove {
+                conn.execute(
+                    "delete from wallet_tags where name = $1 and tag = $2",
+                    params![self.name, tag],
+                )?;
+            }
+            for tag in add {
+                conn.execute(
+                    "insert into wallet_tags values ($1, $2) on conflict do nothing",
+                    params![self.name, tag],
+                )?;
+            }
+            conn.commit()?;
+        }
+        Ok(self.get_tags().await)
+    }
+
+    /// Takes the next value of this wallet's faucet counter, which only ever goes up, even across restarts.
+    pub async fn next_faucet_counter(&self) -> anyhow::Result<u64> {
+        let mut conn = self.pool.get_conn().await;
+        let txn = conn.transaction()?;
+        let counter: u64 = txn
+            .query_row(
+                "select counter from faucet_counters where covhash = $1",
+                params![self.covhash.to_string()],
+                |row| row.get(0),
+            )
+            .optional()?
+            .unwrap_or_default();
+        txn.execute(
+            "insert into faucet_counters values ($1, $2) on conflict (covhash) do update set counter = $2",
+            params![self.covhash.to_string(), counter + 1],
+        )?;
+        txn.commit()?;
+        Ok(counter)
     }
 
     /// Gets the balance by denomination.
@@ -237,7 +1158,16 @@ impl Wallet {
         toret
     }
 
-    /// Obtains transaction history.
+    /// Like `get_balances`, but leaves out coins from transactions that are still pending.
+    pub async fn get_confirmed_balances(&self) -> BTreeMap<Denom, CoinValue> {
+        let mut toret = BTreeMap::new();
+        for (_, data) in self.get_coin_mapping(true, false).await {
+            *toret.entry(data.denom).or_default() += data.value;
+        }
+        toret
+    }
+
+    /// Obtains transaction history, including payments to subaddresses, newest first. Pending transactions, which have no height, come before everything else.
     pub async fn get_transaction_history(&self) -> Vec<(TxHash, Option<BlockHeight>)> {
         // We infer the transaction history through our coin confirmations
         let conn = self.pool.get_conn().await;
@@ -246,10 +1176,12 @@ impl Wallet {
                 r"select coins.coinid, height from 
         coins left join coin_confirmations
         on coins.coinid = coin_confirmations.coinid
-        where covhash = $1",
+        where (covhash = $1 or covhash in (select covhash from subaddresses where name = $2))",
             )
             .unwrap();
-        let mut rows = stmt.query(params![self.covhash.to_string()]).unwrap();
+        let mut rows = stmt
+            .query(params![self.covhash.to_string(), self.name])
+            .unwrap();
         let mut toret = BTreeMap::new();
         while let Ok(Some(row)) = rows.next() {
             let coinid: String = row.get(0).unwrap();
@@ -264,11 +1196,11 @@ impl Wallet {
         }
         // we then go through le pending
         let mut out = toret.into_iter().collect::<Vec<_>>();
-        out.sort_by_key(|x| x.1);
+        out.sort_by_key(|x| (x.1.is_some(), std::cmp::Reverse(x.1)));
         out
     }
 
-    /// Gets all the coins in the wallet, filtered by confirmation and spent status.
+    /// Gets all the coins in the wallet and its subaddresses, filtered by confirmation and spent status.
     pub async fn get_coin_mapping(
         &self,
         confirmed: bool,
@@ -279,46 +1211,49 @@ impl Wallet {
         let conn = self.pool.get_conn().await;
         let stmt = match (confirmed, ignore_pending) {
             (true, true) => {
-                r"select coinid, value, denom, additional_data from coins where 
-                covhash = $1
+                r"select coinid, value, denom, additional_data, covhash from coins where 
+                (covhash = $1 or covhash in (select covhash from subaddresses where name = $2))
                 and exists (select height from coin_confirmations where coin_confirmations.coinid = coins.coinid)
                 and not exists (select txhash from spends where spends.coinid = coins.coinid 
                     and not exists (select txhash from pending where spends.txhash = pending.txhash))"
             }
             (true, false) => {
-                r"select coinid,  value, denom, additional_data from coins where 
-                covhash = $1
+                r"select coinid, value, denom, additional_data, covhash from coins where 
+                (covhash = $1 or covhash in (select covhash from subaddresses where name = $2))
                 and exists (select height from coin_confirmations where coin_confirmations.coinid = coins.coinid)
                 and not exists (select txhash from spends where spends.coinid = coins.coinid)"
             }
             (false, true) => {
-                r"select coinid,  value, denom, additional_data from coins where 
-                covhash = $1
+                r"select coinid, value, denom, additional_data, covhash from coins where 
+                (covhash = $1 or covhash in (select covhash from subaddresses where name = $2))
                 and (exists (select coinid from coin_confirmations where coin_confirmations.coinid = coins.coinid)
                     or exists (select coinid from pending_coins where pending_coins.coinid = coins.coinid))
                 and not exists (select txhash from spends where spends.coinid = coins.coinid 
                     and not exists (select txhash from pending where spends.txhash = pending.txhash))"
             }
             (false, false) => {
-                r"select coinid,  value, denom, additional_data from coins where 
-                covhash = $1
+                r"select coinid, value, denom, additional_data, covhash from coins where 
+                (covhash = $1 or covhash in (select covhash from subaddresses where name = $2))
                 and (exists (select coinid from coin_confirmations where coin_confirmations.coinid = coins.coinid)
                      or exists (select coinid from pending_coins where pending_coins.coinid = coins.coinid))
                 and not exists (select txhash from spends where spends.coinid = coins.coinid)"
             }
         };
         let mut stmt = conn.prepare_cached(stmt).unwrap();
-        let mut rows = stmt.query(params![self.covhash.to_string()]).unwrap();
+        let mut rows = stmt
+            .query(params![self.covhash.to_string(), self.name])
+            .unwrap();
         let mut toret = BTreeMap::new();
         while let Ok(Some(row)) = rows.next() {
             let coinid: String = row.get(0).unwrap();
             let value: String = row.get(1).unwrap();
             let denom: Vec<u8> = row.get(2).unwrap();
             let additional_data: Vec<u8> = row.get(3).unwrap();
+            let covhash: String = row.get(4).unwrap();
             let value: CoinValue = CoinValue(value.parse().unwrap());
             let denom: Denom = Denom::from_bytes(&denom).unwrap();
             let cdata = CoinData {
-                covhash: self.covhash,
+                covhash: covhash.parse().expect("malformed covhash in db"),
                 value,
                 denom,
                 additional_data,
@@ -329,7 +1264,8 @@ impl Wallet {
         toret
     }
 
-    /// Prepares transactions
+    /// Prepares transactions, picking inputs with `selector`. Change in each denomination is split in two, unless `single_change` is set, and goes back to the wallet unless `change_address` is given.
+    #[allow(clippy::too_many_arguments)]
     pub async fn prepare(
         &self,
         inputs: Vec<CoinID>,
@@ -337,9 +1273,12 @@ impl Wallet {
         fee_multiplier: u128,
         sign: impl Fn(Transaction) -> anyhow::Result<Transaction>,
         nobalance: Vec<Denom>,
-
+        single_change: bool,
+        change_address: Option<Address>,
+        selector: &dyn CoinSelector,
         snap: ValClientSnapshot,
     ) -> anyhow::Result<Transaction> {
+        let change_to = change_address.unwrap_or(self.covhash);
         let mut nobalance = nobalance;
         nobalance.push(Denom::NewCoin);
         let nobalance = nobalance;
@@ -347,15 +1286,47 @@ impl Wallet {
         // first we add the "mandatory" inputs
         for input in inputs {
             if let Some(coindata) = self.get_coin_confirmation(input).await {
-                mandatory_inputs.insert(input, coindata.clone());
+                mandatory_inputs.insert(input, coindata.coin_data);
             } else {
                 log::warn!("processing out-of-wallet coin {}", input);
                 let coindata = snap.get_coin(input).await?.context("cannot find coin")?;
-                mandatory_inputs.insert(input, coindata.clone());
+                mandatory_inputs.insert(input, coindata.coin_data);
+            }
+        }
+        // the transaction can go into the next block at the earliest
+        let next_height = snap.current_header().height + BlockHeight(1);
+        let mut locks = self.get_coin_maturities().await;
+        // explicit inputs are few, so we ask the network about them rather than trust what we recorded
+        locks.extend(find_stake_locks(&snap, mandatory_inputs.keys().copied()).await?);
+        let locks = locks;
+        for input in mandatory_inputs.keys() {
+            if let Some(spendable_at) = locks.get(input).filter(|h| **h > next_height) {
+                anyhow::bail!(
+                    "coin {} is locked by a stake until height {}",
+                    input,
+                    spendable_at
+                )
             }
         }
         log::trace!("calling get_coin_mapping from prepare");
-        let unspent_coins = self.get_coin_mapping(true, false).await;
+        let reserved = self.get_reserved_coins().await;
+        let frozen = self.get_frozen_coins().await;
+        let mut unspent_coins = self.get_coin_mapping(true, false).await;
+        unspent_coins.retain(|coin, _| {
+            !reserved.contains(coin)
+                && !frozen.contains(coin)
+                && locks.get(coin).is_none_or(|h| *h <= next_height)
+        });
+        // coins at a subaddress are spent by attaching its covenant alongside the wallet's own
+        let subaddress_covenants: BTreeMap<Address, Vec<u8>> = self
+            .subaddress_wallets()
+            .await
+            .into_iter()
+            .map(|subaddress| (subaddress.covhash, subaddress.covenant))
+            .collect();
+        let spendable: BTreeSet<Address> = std::iter::once(self.covhash)
+            .chain(subaddress_covenants.keys().copied())
+            .collect();
         let gen_transaction = |fee| {
             log::debug!("trying with a fee of {} MEL", fee);
             let start = Instant::now();
@@ -371,97 +1342,38 @@ impl Wallet {
             };
 
             // compute output sum
-            let mut output_sum = txn.total_outputs();
-
-            let mut input_sum: BTreeMap<Denom, CoinValue> = BTreeMap::new();
-            // first we add the "mandatory" inputs
-            for (coin, data) in mandatory_inputs.iter() {
-                txn.inputs.push(*coin);
-                let existing_val = input_sum
-                    .get(&data.coin_data.denom)
-                    .cloned()
-                    .unwrap_or(CoinValue(0));
-                input_sum.insert(data.coin_data.denom, existing_val + data.coin_data.value);
-            }
-
-            // don't try to balance the nobalance stuff
-            for denom in nobalance.iter() {
-                output_sum.remove(denom);
-                input_sum.remove(denom);
-            }
-
-            log::trace!("before unspent coins: {:?}", start.elapsed());
-
-            // then we add random other inputs until enough.
-            // we filter out everything that is in the stake list.
-
-            log::trace!("after shuffling unspent coins: {:?}", start.elapsed());
-
-            for (coin, data) in unspent_coins.iter() {
-                // blacklist of coins
-                if mandatory_inputs.contains_key(coin)
-                    || nobalance.contains(&data.denom)
-                    || data.covhash != self.covhash
-                {
-                    // do not consider it
-                    continue;
-                }
-                let existing_val = input_sum.get(&data.denom).cloned().unwrap_or(CoinValue(0));
-                if existing_val < output_sum.get(&data.denom).cloned().unwrap_or(CoinValue(0)) {
-                    txn.inputs.push(*coin);
-                    input_sum.insert(data.denom, existing_val + data.value);
-                }
-            }
+            let output_sum: BTreeMap<Denom, CoinValue> = txn.total_outputs().into_iter().collect();
 
-            log::trace!("after going through unspent coins: {:?}", start.elapsed());
-
-            // create change outputs
-            let change = {
-                let mut change = Vec::new();
-                for (cointype, sum) in output_sum.iter() {
-                    let difference = input_sum
-                        .get(cointype)
-                        .cloned()
-                        .unwrap_or(CoinValue(0))
-                        .checked_sub(*sum);
-                    if let Some(difference) = difference {
-                        if difference.0 > 0 || *cointype == Denom::Mel {
-                            // We make TWO change outputs, to maximize parallelization
-                            // TODO: does this create indefinitely many UTXOs? That'd be bad
-                            if difference.0 >= 2 {
-                                let first_half = difference / 2;
-                                let second_half = difference - first_half;
-                                change.push(CoinData {
-                                    covhash: self.covhash,
-                                    value: first_half,
-                                    denom: *cointype,
-                                    additional_data: vec![],
-                                });
-                                change.push(CoinData {
-                                    covhash: self.covhash,
-                                    value: second_half,
-                                    denom: *cointype,
-                                    additional_data: vec![],
-                                })
-                            } else {
-                                change.push(CoinData {
-                                    covhash: self.covhash,
-                                    value: difference,
-                                    denom: *cointype,
-                                    additional_data: vec![],
-                                })
-                            }
-                        }
-                    } else {
-                        return Direction::High(Err(anyhow::anyhow!(
-                            "not enough money for denomination {}",
-                            cointype
-                        )));
-                    }
-                }
-                change
+            log::trace!("before balancing: {:?}", start.elapsed());
+            let (inputs, change) = match balance_per_denom(
+                &spendable,
+                change_to,
+                &output_sum,
+                &mandatory_inputs,
+                &unspent_coins,
+                &nobalance,
+                single_change,
+                selector,
+            ) {
+                Ok(res) => res,
+                Err(err) => return Direction::High(Err(err)),
             };
-            txn.outputs.extend(change.into_iter());
+            let input_covhashes: BTreeSet<Address> = inputs
+                .iter()
+                .filter_map(|input| {
+                    mandatory_inputs
+                        .get(input)
+                        .or_else(|| unspent_coins.get(input))
+                        .map(|data| data.covhash)
+                })
+                .collect();
+            txn.covenants.extend(
+                input_covhashes
+                    .iter()
+                    .filter_map(|covhash| subaddress_covenants.get(covhash).cloned()),
+            );
+            txn.inputs = inputs;
+            txn.outputs.extend(change);
 
             log::trace!("before signing: {:?}", start.elapsed());
             log::debug!("candidate with {} inputs", txn.inputs.len());
@@ -489,6 +1401,21 @@ impl Wallet {
                 Err(err) => Direction::Low(Err(err)),
             }
         };
+        // catch underfunded denominations upfront, since the fee search would bury the error
+        let mut output_sum: BTreeMap<Denom, CoinValue> = BTreeMap::new();
+        for output in outputs.iter() {
+            *output_sum.entry(output.denom).or_default() += output.value;
+        }
+        balance_per_denom(
+            &spendable,
+            change_to,
+            &output_sum,
+            &mandatory_inputs,
+            &unspent_coins,
+            &nobalance,
+            single_change,
+            selector,
+        )?;
         let max_fee: CoinValue = unspent_coins
             .values()
             .filter(|cdh| cdh.denom == Denom::Mel)
@@ -512,69 +1439,106 @@ impl Wallet {
         val.context("preparation failed")
     }
 
-    /// Sets transactions as sent
-    pub async fn commit_sent(&self, txn: Transaction, timeout: BlockHeight) -> anyhow::Result<()> {
+    /// Gets the coins currently reserved by prepared transactions.
+    pub async fn get_reserved_coins(&self) -> HashSet<CoinID> {
+        let now = unix_now();
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached("select coinid from reserved_coins where expires >= $1")
+            .unwrap();
+        let rows = stmt
+            .query_map(params![now], |row| {
+                let coinid: String = row.get(0)?;
+                Ok(coinid.parse().expect("malformed coinid in db"))
+            })
+            .unwrap();
+        rows.collect::<Result<HashSet<_>, _>>().unwrap()
+    }
+
+    /// Reserves coins until the given unix time, so that other preparations don't spend them too. Returns false, reserving nothing, if any of them is already reserved.
+    pub async fn reserve_coins(&self, coins: &[CoinID], expires: u64) -> anyhow::Result<bool> {
         let mut conn = self.pool.get_conn().await;
-        let conn = conn.transaction()?;
-        // ensure that every input is available
-        for input in txn.inputs.iter() {
-            if conn
+        let txn = conn.transaction()?;
+        txn.execute(
+            "delete from reserved_coins where expires < $1",
+            params![unix_now()],
+        )?;
+        for coin in coins {
+            if txn
                 .query_row(
-                    "select height from coin_confirmations where coinid = $1",
-                    params![input.to_string()],
+                    "select expires from reserved_coins where coinid = $1",
+                    params![coin.to_string()],
                     |_| Ok(()),
                 )
                 .optional()?
-                .is_none()
+                .is_some()
             {
-                anyhow::bail!("input {} no longer in wallet", input)
+                return Ok(false);
             }
-        }
-        // add the transaction to the cache
-        let txhash = txn.hash_nosigs();
-        conn.execute(
-            "insert into transactions values ($1, $2) on conflict do nothing",
-            params![txhash.to_string(), txn.stdcode()],
-        )?;
-        // spend everything
-        for input in txn.inputs.iter() {
-            conn.execute(
-                "insert into spends values ($1, $2)",
-                params![input.to_string(), txhash.to_string()],
+            txn.execute(
+                "insert into reserved_coins values ($1, $2)",
+                params![coin.to_string(), expires],
             )?;
         }
+        txn.commit()?;
+        Ok(true)
+    }
 
-        // ONLY do this if this is a NORMAL transaction. Otherwise transmutation will invalidate these coins BADLY.
-        if txn.kind == TxKind::Normal {
-            for (i, output) in txn.outputs.iter().enumerate() {
-                let coinid = txn.output_coinid(i as u8);
-                let denom = if output.denom == Denom::NewCoin {
-                    Denom::Custom(txn.hash_nosigs())
-                } else {
-                    output.denom
-                };
-                conn.execute(
-                    "insert into coins values ($1, $2, $3, $4, $5) on conflict do nothing",
-                    params![
-                        coinid.to_string(),
-                        output.covhash.to_string(),
-                        output.value.0.to_string(),
-                        denom.to_bytes(),
-                        output.additional_data.clone()
-                    ],
-                )?;
-                conn.execute(
-                    "insert into pending_coins values ($1, $2)",
-                    params![coinid.to_string(), txn.hash_nosigs().to_string()],
-                )?;
-            }
-        }
-        // add to pending
+    /// Releases a coin's reservation. Returns false if it wasn't reserved.
+    pub async fn release_coin(&self, coin: CoinID) -> anyhow::Result<bool> {
+        let conn = self.pool.get_conn().await;
+        let released = conn.execute(
+            "delete from reserved_coins where coinid = $1",
+            params![coin.to_string()],
+        )?;
+        Ok(released > 0)
+    }
+
+    /// Gets the coins excluded from automatic input selection.
+    pub async fn get_frozen_coins(&self) -> HashSet<CoinID> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached("select coinid from frozen_coins")
+            .unwrap();
+        let rows = stmt
+            .query_map(params![], |row| {
+                let coinid: String = row.get(0)?;
+                Ok(coinid.parse().expect("malformed coinid in db"))
+            })
+            .unwrap();
+        rows.collect::<Result<HashSet<_>, _>>().unwrap()
+    }
+
+    /// Excludes a coin from automatic input selection, so that it is only spent when explicitly asked for.
+    pub async fn freeze_coin(&self, coin: CoinID) -> anyhow::Result<()> {
+        let conn = self.pool.get_conn().await;
         conn.execute(
-            "insert into pending values ($1, $2)",
-            params![txhash.to_string(), timeout.0],
+            "insert into frozen_coins values ($1) on conflict do nothing",
+            params![coin.to_string()],
+        )?;
+        Ok(())
+    }
+
+    /// Lets automatic input selection spend a coin again. Returns false if it wasn't frozen.
+    pub async fn unfreeze_coin(&self, coin: CoinID) -> anyhow::Result<bool> {
+        let conn = self.pool.get_conn().await;
+        let unfrozen = conn.execute(
+            "delete from frozen_coins where coinid = $1",
+            params![coin.to_string()],
         )?;
-        // commit
+        Ok(unfrozen > 0)
+    }
+
+    /// Sets transactions as sent, at the given height
+    pub async fn commit_sent(
+        &self,
+        txn: Transaction,
+        sent_height: BlockHeight,
+        timeout: BlockHeight,
+    ) -> anyhow::Result<()> {
+        let mut conn = self.pool.get_conn().await;
+        let conn = conn.transaction()?;
+        record_sent(&conn, &txn, sent_height, timeout)?;
         conn.commit()?;
         Ok(())
     }
@@ -617,8 +1581,222 @@ impl Wallet {
         })
     }
 
-    /// Updates the list of coins, given a network snapshot.
-    pub async fn network_sync(&self, snapshot: ValClientSnapshot) -> anyhow::Result<()> {
+    /// Gets the confirmation height of every confirmed coin in the wallet and its subaddresses.
+    pub async fn get_coin_heights(&self) -> BTreeMap<CoinID, BlockHeight> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached(
+                r"select coins.coinid, height from
+        coins inner join coin_confirmations
+        on coins.coinid = coin_confirmations.coinid
+        where (covhash = $1 or covhash in (select covhash from subaddresses where name = $2))",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map(params![self.covhash.to_string(), self.name], |row| {
+                let coinid: String = row.get(0)?;
+                let height: u64 = row.get(1)?;
+                Ok((
+                    coinid.parse().expect("malformed coinid in db"),
+                    height.into(),
+                ))
+            })
+            .unwrap();
+        rows.collect::<Result<BTreeMap<_, _>, _>>().unwrap()
+    }
+
+    /// Gets the heights from which the stake-locked coins of this wallet and its subaddresses may be spent. Coins missing from the map never needed to mature.
+    pub async fn get_coin_maturities(&self) -> BTreeMap<CoinID, BlockHeight> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached(
+                r"select coins.coinid, spendable_at from
+        coins inner join coin_maturities
+        on coins.coinid = coin_maturities.coinid
+        where (covhash = $1 or covhash in (select covhash from subaddresses where name = $2))",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map(params![self.covhash.to_string(), self.name], |row| {
+                let coinid: String = row.get(0)?;
+                let spendable_at: u64 = row.get(1)?;
+                Ok((
+                    coinid.parse().expect("malformed coinid in db"),
+                    spendable_at.into(),
+                ))
+            })
+            .unwrap();
+        rows.collect::<Result<BTreeMap<_, _>, _>>().unwrap()
+    }
+
+    /// Gets the confirmed coins of this wallet and its subaddresses, spent or not, that were confirmed after the given height, oldest first.
+    pub async fn get_coins_since(&self, height: BlockHeight) -> Vec<(CoinID, CoinDataHeight)> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached(
+                r"select coins.coinid, value, denom, additional_data, height, covhash from
+        coins inner join coin_confirmations
+        on coins.coinid = coin_confirmations.coinid
+        where (covhash = $1 or covhash in (select covhash from subaddresses where name = $2)) and height > $3
+        order by height",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map(
+                params![self.covhash.to_string(), self.name, height.0],
+                |row| {
+                    let coinid: String = row.get(0)?;
+                    let value: String = row.get(1)?;
+                    let denom: Vec<u8> = row.get(2)?;
+                    let height: u64 = row.get(4)?;
+                    let covhash: String = row.get(5)?;
+                    Ok((
+                        coinid.parse().expect("malformed coinid in db"),
+                        CoinDataHeight {
+                            coin_data: CoinData {
+                                covhash: covhash.parse().expect("malformed covhash in db"),
+                                value: CoinValue(value.parse().expect("malformed value in db")),
+                                denom: Denom::from_bytes(&denom).expect("malformed denom in db"),
+                                additional_data: row.get(3)?,
+                            },
+                            height: height.into(),
+                        },
+                    ))
+                },
+            )
+            .unwrap();
+        rows.collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    /// Gets the `index`th subaddress of this wallet, if it is already tracked.
+    pub async fn tracked_subaddress(&self, index: u64) -> Option<Address> {
+        let conn = self.pool.get_conn().await;
+        let existing: Option<String> = conn
+            .query_row(
+                "select covhash from subaddresses where name = $1 and idx = $2",
+                params![self.name, index],
+                |row| row.get(0),
+            )
+            .optional()
+            .unwrap();
+        existing.map(|covhash| covhash.parse().expect("malformed covhash in db"))
+    }
+
+    /// Gets the `index`th subaddress of this wallet, deriving and tracking it, along with every lower index not yet tracked, if need be.
+    pub async fn subaddress(&self, index: u64) -> anyhow::Result<Address> {
+        if let Some(address) = self.tracked_subaddress(index).await {
+            return Ok(address);
+        }
+        if index > MAX_SUBADDRESS_INDEX {
+            anyhow::bail!("subaddress index cannot exceed {}", MAX_SUBADDRESS_INDEX)
+        }
+        let covenant = self
+            .covenant()
+            .context("wallet only knows its address, so it has no subaddresses")?;
+        let mut conn = self.pool.get_conn().await;
+        let txn = conn.transaction()?;
+        let tracked: Option<u64> = txn.query_row(
+            "select max(idx) from subaddresses where name = $1",
+            params![self.name],
+            |row| row.get(0),
+        )?;
+        let mut address = self.covhash;
+        for idx in tracked.map(|max| max + 1).unwrap_or(0)..=index {
+            let subaddress = derive_subaddress(&covenant, idx)?;
+            address = subaddress.hash();
+            txn.execute(
+                "insert into subaddresses values ($1, $2, $3, $4)",
+                params![self.name, idx, address.to_string(), subaddress.0],
+            )?;
+        }
+        txn.commit()?;
+        Ok(address)
+    }
+
+    /// Gets views of this wallet's tracked subaddresses, each syncing the coins of one subaddress as [Wallet::network_sync] does for the wallet itself.
+    pub async fn subaddress_wallets(&self) -> Vec<Wallet> {
+        let conn = self.pool.get_conn().await;
+        let mut stmt = conn
+            .prepare_cached(
+                "select covhash, covenant from subaddresses where name = $1 order by idx",
+            )
+            .unwrap();
+        let rows = stmt
+            .query_map(params![self.name], |row| {
+                let covhash: String = row.get(0)?;
+                Ok(Wallet {
+                    name: self.name.clone(),
+                    covhash: covhash.parse().expect("malformed covhash in db"),
+                    covenant: row.get(1)?,
+                    pool: self.pool.clone(),
+                })
+            })
+            .unwrap();
+        rows.collect::<Result<Vec<_>, _>>().unwrap()
+    }
+
+    /// Whether a coin is change, i.e. came back to this wallet from a transaction it sent.
+    pub async fn is_change(&self, coin_id: CoinID) -> bool {
+        self.get_cached_transaction(coin_id.txhash)
+            .await
+            .map(|tx| tx.covenants.iter().any(|c| c.hash() == self.covhash.0))
+            .unwrap_or_default()
+    }
+
+    /// Gets the height at which a transaction was confirmed, judging by its outputs.
+    pub async fn get_tx_confirmation(&self, txn: &Transaction) -> Option<BlockHeight> {
+        let mut confirmed_height = None;
+        for idx in 0..txn.outputs.len() {
+            if let Some(cdh) = self
+                .get_coin_confirmation(txn.output_coinid(idx as u8))
+                .await
+            {
+                confirmed_height = Some(cdh.height);
+            }
+        }
+        confirmed_height
+    }
+
+    /// Inserts confirmed coins into the wallet, along with the heights from which the stake-locked ones may be spent. Returns how many of them were not already known.
+    pub async fn insert_coins(
+        &self,
+        coins: &[(CoinID, CoinDataHeight)],
+        locks: &BTreeMap<CoinID, BlockHeight>,
+    ) -> anyhow::Result<usize> {
+        let mut conn = self.pool.get_conn().await;
+        let txn = conn.transaction()?;
+        let mut inserted = 0;
+        for (coin, cdh) in coins {
+            txn.execute(
+                "insert into coins values ($1, $2, $3, $4, $5) on conflict do nothing",
+                params![
+                    coin.to_string(),
+                    cdh.coin_data.covhash.to_string(),
+                    cdh.coin_data.value.0.to_string(),
+                    cdh.coin_data.denom.to_bytes(),
+                    cdh.coin_data.additional_data.clone()
+                ],
+            )?;
+            inserted += txn.execute(
+                "insert into coin_confirmations values ($1, $2) on conflict do nothing",
+                params![coin.to_string(), cdh.height.0],
+            )?;
+        }
+        for (coin, spendable_at) in locks {
+            txn.execute(
+                "insert into coin_maturities values ($1, $2) on conflict do nothing",
+                params![coin.to_string(), spendable_at.0],
+            )?;
+        }
+        txn.commit()?;
+        Ok(inserted)
+    }
+
+    /// Updates the list of coins, given a network snapshot. Returns the coins that were newly confirmed.
+    pub async fn network_sync(
+        &self,
+        snapshot: ValClientSnapshot,
+    ) -> anyhow::Result<Vec<(CoinID, CoinDataHeight)>> {
         // The basic idea is that we get the list of coins from the remote, then add them all to the wallet.
         // However, we also need to take care of "disappearing" coins. If we have a confirmed coin that is no longer in the latest set, it must have been spent somewhere along the way. If we don't already have the transactions that spends it in the "spends", we must find that transaction through a binary search between the block where that coin was confirmed and the current block --- otherwise we cannot mark that coin as spent.
 
@@ -637,13 +1815,15 @@ impl Wallet {
             .unwrap_or_default();
         // Then, we compare with the coins we already have
         log::trace!("calling coin_mapping from sync");
-        let existing_coins = self.get_coin_mapping(true, false).await;
+        let mut existing_coins = self.get_coin_mapping(true, false).await;
+        // the remote only knows the coins at this one address, not those at the other addresses of the wallet
+        existing_coins.retain(|_, data| data.covhash == self.covhash);
         if existing_coins.len() == remote_coin_count as usize
             && pending_count == 0
             && fastrand::f64() < 0.95
         // occasionally do a full sync
         {
-            return Ok(());
+            return Ok(vec![]);
         }
         // reconstruct the coin list
         let remote_coin_list = snapshot
@@ -670,11 +1850,13 @@ impl Wallet {
                 potential_coins.push((coinid, task));
             }
         }
+        let new_coin_ids: Vec<CoinID> = potential_coins.iter().map(|(coinid, _)| *coinid).collect();
         for (coinid, task) in potential_coins {
             log::debug!("resolving coinid {} => {}", coinid, coin_list.len());
             let cdh = task.await?;
             coin_list.insert(coinid, cdh);
         }
+        let locks = find_stake_locks(&snapshot, new_coin_ids).await?;
 
         // We take care of all disappearing coins
         let mut new_spenders = HashSet::new();
@@ -687,40 +1869,12 @@ impl Wallet {
                 continue;
             }
             if let Some(cdh) = self.get_coin_confirmation(*disappeared_coin).await {
-                let height = cdh.height;
                 log::debug!(
                     "wallet {} lost coin {}, finding out why",
                     self.name,
                     disappeared_coin
                 );
-                // binary search for the first block in which this was gone
-                let mut left = height;
-                let mut right = snapshot.current_header().height;
-                while left < right {
-                    let median = (left + right) / 2;
-                    log::trace!("binary search at {} ({}..{})", median, left, right);
-                    if snapshot
-                        .get_older(median)
-                        .await?
-                        .get_coin(*disappeared_coin)
-                        .await?
-                        .is_some()
-                    {
-                        left = median + BlockHeight(1);
-                    } else {
-                        right = median;
-                    }
-                }
-                let spend_block = left;
-                let spender_tx = snapshot
-                    .get_older(spend_block)
-                    .await?
-                    .current_block()
-                    .await?
-                    .transactions
-                    .into_iter()
-                    .find(|tx| tx.inputs.contains(disappeared_coin))
-                    .context("bug: digged for the coin in the wrong place")?;
+                let spender_tx = find_spender(&snapshot, *disappeared_coin, cdh.height).await?;
                 log::debug!("found spender: {}", spender_tx.hash_nosigs());
                 // we don't have to revisit other coins this same spender spends
                 for input in spender_tx.inputs.iter() {
@@ -732,6 +1886,7 @@ impl Wallet {
         // we insert the coins and spenders in one atomic  transaction
         let mut conn = self.pool.get_conn().await;
         let txn = conn.transaction()?;
+        let mut new_coins = vec![];
         for (coin, cdh) in coin_list {
             txn.execute(
                 "insert into coins values ($1, $2, $3, $4, $5) on conflict do nothing",
@@ -740,13 +1895,24 @@ impl Wallet {
                     cdh.coin_data.covhash.to_string(),
                     cdh.coin_data.value.0.to_string(),
                     cdh.coin_data.denom.to_bytes(),
-                    cdh.coin_data.additional_data
+                    cdh.coin_data.additional_data.clone()
                 ],
             )
             .unwrap();
+            let inserted = txn
+                .execute(
+                    "insert into coin_confirmations values ($1, $2) on conflict do nothing",
+                    params![coin.to_string(), cdh.height.0],
+                )
+                .unwrap();
+            if inserted > 0 {
+                new_coins.push((coin, cdh));
+            }
+        }
+        for (coin, spendable_at) in locks {
             txn.execute(
-                "insert into coin_confirmations values ($1, $2) on conflict do nothing",
-                params![coin.to_string(), cdh.height.0],
+                "insert into coin_maturities values ($1, $2) on conflict do nothing",
+                params![coin.to_string(), spendable_at.0],
             )
             .unwrap();
         }
@@ -782,6 +1948,136 @@ impl Wallet {
         txn.execute("delete from pending_coins where not exists (select expires from pending where pending.txhash = pending_coins.txhash)", params![])?;
 
         txn.commit()?;
-        Ok(())
+        Ok(new_coins)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::selection::LargestFirst;
+
+    fn coin(covhash: Address, value: u128, denom: Denom) -> CoinData {
+        CoinData {
+            covhash,
+            value: CoinValue(value),
+            denom,
+            additional_data: vec![],
+        }
+    }
+
+    fn coin_id(idx: u8) -> CoinID {
+        CoinID {
+            txhash: TxHash(tmelcrypt::hash_single([idx])),
+            index: 0,
+        }
+    }
+
+    #[test]
+    fn balances_each_denom_separately() {
+        let ours = Address(tmelcrypt::hash_single(b"ours"));
+        let token = Denom::Custom(TxHash(tmelcrypt::hash_single(b"token")));
+        let unspent: BTreeMap<CoinID, CoinData> = vec![
+            (coin_id(0), coin(ours, 1000, Denom::Mel)),
+            (coin_id(1), coin(ours, 30, token)),
+            (coin_id(2), coin(ours, 30, token)),
+        ]
+        .into_iter()
+        .collect();
+
+        let output_sum = vec![(Denom::Mel, CoinValue(100)), (token, CoinValue(50))]
+            .into_iter()
+            .collect();
+        let (inputs, change) = balance_per_denom(
+            &BTreeSet::from([ours]),
+            ours,
+            &output_sum,
+            &BTreeMap::new(),
+            &unspent,
+            &[],
+            false,
+            &LargestFirst,
+        )
+        .unwrap();
+        assert_eq!(inputs.len(), 3);
+        let change_in = |denom| -> u128 {
+            change
+                .iter()
+                .filter(|c| c.denom == denom)
+                .map(|c| c.value.0)
+                .sum()
+        };
+        assert_eq!(change_in(Denom::Mel), 900);
+        assert_eq!(change_in(token), 10);
+        assert!(change.iter().all(|c| c.covhash == ours));
+
+        let output_sum = vec![(Denom::Mel, CoinValue(100)), (token, CoinValue(61))]
+            .into_iter()
+            .collect();
+        let err = balance_per_denom(
+            &BTreeSet::from([ours]),
+            ours,
+            &output_sum,
+            &BTreeMap::new(),
+            &unspent,
+            &[],
+            false,
+            &LargestFirst,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains(&token.to_string()), "{}", err);
+    }
+
+    #[test]
+    fn sends_change_elsewhere() {
+        let ours = Address(tmelcrypt::hash_single(b"ours"));
+        let elsewhere = Address(tmelcrypt::hash_single(b"elsewhere"));
+        let unspent: BTreeMap<CoinID, CoinData> = vec![(coin_id(0), coin(ours, 1000, Denom::Mel))]
+            .into_iter()
+            .collect();
+        let output_sum = vec![(Denom::Mel, CoinValue(100))].into_iter().collect();
+        let (inputs, change) = balance_per_denom(
+            &BTreeSet::from([ours]),
+            elsewhere,
+            &output_sum,
+            &BTreeMap::new(),
+            &unspent,
+            &[],
+            true,
+            &LargestFirst,
+        )
+        .unwrap();
+        assert_eq!(inputs, vec![coin_id(0)]);
+        assert_eq!(change, vec![coin(elsewhere, 900, Denom::Mel)]);
+    }
+
+    #[test]
+    fn spends_subaddress_coins() {
+        let ours = Address(tmelcrypt::hash_single(b"ours"));
+        let subaddress = Address(tmelcrypt::hash_single(b"subaddress"));
+        let stranger = Address(tmelcrypt::hash_single(b"stranger"));
+        let unspent: BTreeMap<CoinID, CoinData> = vec![
+            (coin_id(0), coin(ours, 60, Denom::Mel)),
+            (coin_id(1), coin(subaddress, 60, Denom::Mel)),
+            (coin_id(2), coin(stranger, 1000, Denom::Mel)),
+        ]
+        .into_iter()
+        .collect();
+        let output_sum = vec![(Denom::Mel, CoinValue(100))].into_iter().collect();
+        let (mut inputs, change) = balance_per_denom(
+            &BTreeSet::from([ours, subaddress]),
+            ours,
+            &output_sum,
+            &BTreeMap::new(),
+            &unspent,
+            &[],
+            true,
+            &LargestFirst,
+        )
+        .unwrap();
+        inputs.sort();
+        assert_eq!(inputs, vec![coin_id(0), coin_id(1)]);
+        assert_eq!(change, vec![coin(ours, 20, Denom::Mel)]);
     }
 }
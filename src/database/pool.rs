@@ -3,7 +3,7 @@ use std::{
     path::Path,
 };
 
-use rusqlite::Connection;
+use rusqlite::{Connection, OpenFlags};
 use smol::channel::{Receiver, Sender};
 
 /// A pool of connections to a particular SQL database.
@@ -29,6 +29,24 @@ impl ConnPool {
         })
     }
 
+    /// Creates a new connection pool to the SQLite database at the specified path, through which nothing can be written.
+    pub fn open_read_only(path: impl AsRef<Path>) -> rusqlite::Result<Self> {
+        let (send_conn, recv_conn) = smol::channel::bounded(64);
+        for _ in 0..8 {
+            let conn = Connection::open_with_flags(
+                path.as_ref(),
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?;
+            send_conn.try_send(conn).unwrap();
+        }
+        Ok(Self {
+            send_conn,
+            recv_conn,
+        })
+    }
+
     /// Gets a connection.
     pub async fn get_conn(&self) -> impl DerefMut<Target = Connection> {
         PooledConnection {
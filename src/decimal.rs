@@ -0,0 +1,117 @@
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Signed, ToPrimitive, Zero};
+use serde::{Deserialize, Serialize};
+use themelio_structs::Denom;
+
+/// Decimal places of the native denominations, whose values are all counted in millionths.
+pub const NATIVE_DECIMALS: u8 = 6;
+
+/// Fractional digits that ratios, like prices, keep when written as decimal strings. Amounts are always written in full.
+const RATIO_DIGITS: u32 = 18;
+
+/// Decimal places of a denomination, if known. Custom tokens don't declare any.
+pub fn decimals_of(denom: Denom) -> Option<u8> {
+    match denom {
+        Denom::Custom(_) => None,
+        _ => Some(NATIVE_DECIMALS),
+    }
+}
+
+/// A number in a response: a JSON number normally, or an exact decimal string for clients that ask for one.
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(untagged)]
+pub enum Num {
+    Int(u128),
+    Float(f64),
+    Exact(String),
+}
+
+/// How a response writes its numbers, given by the `precise` query parameter.
+#[derive(Deserialize, Default, Clone, Copy)]
+pub struct Precision {
+    #[serde(default)]
+    pub precise: bool,
+}
+
+impl Precision {
+    /// An amount in the smallest units of a denomination. Precise amounts are in whole units, except for denominations with unknown decimals.
+    pub fn amount(&self, value: u128, denom: Denom) -> Num {
+        match (self.precise, decimals_of(denom)) {
+            (false, _) => Num::Int(value),
+            (true, Some(decimals)) => Num::Exact(format_amount(value, decimals)),
+            (true, None) => Num::Exact(value.to_string()),
+        }
+    }
+
+    /// A ratio, such as a price.
+    pub fn ratio(&self, ratio: &BigRational) -> Num {
+        if self.precise {
+            Num::Exact(format_ratio(ratio))
+        } else {
+            Num::Float(ratio.to_f64().unwrap_or(f64::NAN))
+        }
+    }
+}
+
+/// The ratio between two amounts.
+pub fn ratio_of(numer: u128, denom: u128) -> BigRational {
+    if denom == 0 {
+        // an empty pool has no meaningful price
+        return BigRational::zero();
+    }
+    BigRational::new(BigInt::from(numer), BigInt::from(denom))
+}
+
+/// Writes an amount counted in units of 10^-decimals, keeping every decimal place.
+pub fn format_amount(value: u128, decimals: u8) -> String {
+    let scale = 10u128.pow(decimals as u32);
+    if decimals == 0 {
+        return value.to_string();
+    }
+    format!(
+        "{}.{:0width$}",
+        value / scale,
+        value % scale,
+        width = decimals as usize
+    )
+}
+
+/// Writes a ratio as a decimal, cut off after `RATIO_DIGITS` fractional digits and without trailing zeros.
+pub fn format_ratio(ratio: &BigRational) -> String {
+    let abs = ratio.abs();
+    let whole = abs.trunc().to_integer();
+    let fraction = (abs.fract() * BigInt::from(10u32).pow(RATIO_DIGITS)).to_integer();
+    let mut out = whole.to_string();
+    if !fraction.is_zero() {
+        let digits = format!("{:0>width$}", fraction, width = RATIO_DIGITS as usize);
+        out.push('.');
+        out.push_str(digits.trim_end_matches('0'));
+    }
+    if ratio.is_negative() && out != "0" {
+        out.insert(0, '-');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use themelio_structs::TxHash;
+
+    use super::*;
+
+    #[test]
+    fn formats_exactly() {
+        assert_eq!(format_amount(1001000000, 6), "1001.000000");
+        assert_eq!(format_amount(5, 6), "0.000005");
+        assert_eq!(format_amount(u128::MAX, 0), u128::MAX.to_string());
+        assert_eq!(format_ratio(&ratio_of(3, 2)), "1.5");
+        assert_eq!(format_ratio(&ratio_of(1, 3)), "0.333333333333333333");
+        assert_eq!(format_ratio(&(ratio_of(1, 4) - ratio_of(1, 2))), "-0.25");
+        assert_eq!(format_ratio(&ratio_of(7, 1)), "7");
+        assert_eq!(
+            Precision { precise: true }.amount(1, Denom::Custom(TxHash(Default::default()))),
+            Num::Exact("1".into())
+        );
+    }
+}
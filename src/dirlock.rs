@@ -0,0 +1,56 @@
+use std::{fs::File, io::Write, os::unix::io::AsRawFd, path::Path};
+
+use anyhow::Context;
+
+/// An exclusive advisory lock on a wallet directory, held until dropped or until the process exits. Locks within a process only keep its own threads apart, so without this, two daemons pointed at one directory would silently overwrite each other's secrets.
+pub struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks the wallet directory, failing right away if another process holds it.
+    pub fn acquire(dir: &Path) -> anyhow::Result<Self> {
+        let path = dir.join(".lock");
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .write(true)
+            // a holder's pid stays in place until we hold the lock ourselves
+            .truncate(false)
+            .open(&path)
+            .with_context(|| format!("cannot open lock file {:?}", path))?;
+        // SAFETY: flock only takes a file descriptor, which the file keeps open for as long as we use it.
+        if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() == Some(libc::EWOULDBLOCK) {
+                let holder = std::fs::read_to_string(&path).unwrap_or_default();
+                anyhow::bail!(
+                    "wallet directory {:?} is in use by another process (pid {}); pass --read-only to serve it alongside that process",
+                    dir,
+                    holder.trim()
+                )
+            }
+            return Err(err).with_context(|| format!("cannot lock {:?}", path));
+        }
+        // the pid is only for telling users who holds the lock
+        file.set_len(0)?;
+        write!(file, "{}", std::process::id())?;
+        Ok(Self { _file: file })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_a_second_holder() {
+        let dir = std::env::temp_dir().join(format!("dirlock-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let lock = DirLock::acquire(&dir).unwrap();
+        // every acquisition opens the file anew, so it conflicts with the first just like another process would
+        assert!(DirLock::acquire(&dir).is_err());
+        drop(lock);
+        assert!(DirLock::acquire(&dir).is_ok());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    sync::{Arc, Weak},
+    time::{Duration, Instant},
+};
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use tide::{
+    http::{
+        headers::{HeaderName, HeaderValues},
+        Method, Mime,
+    },
+    Body, Middleware, Next, Request, StatusCode,
+};
+use tmelcrypt::HashVal;
+
+/// How long the response to a request carrying an `Idempotency-Key` is replayed for.
+const IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(300);
+
+/// How often keys past the window are forgotten.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(30);
+
+type SeenMap = DashMap<(String, Method, String), Seen>;
+
+/// Middleware making requests that carry an `Idempotency-Key` header safe to retry. A successful response is remembered for the key, method, and path, and a retry within the window gets that response again instead of running the request twice. Failed responses aren't remembered, since a failed request is safe to run again.
+pub struct Idempotency {
+    seen: Arc<SeenMap>,
+}
+
+impl Idempotency {
+    /// Creates the middleware, along with a background task forgetting keys past the window, which ends once the middleware is dropped.
+    pub fn new() -> Self {
+        let seen = Arc::new(SeenMap::default());
+        let weak: Weak<SeenMap> = Arc::downgrade(&seen);
+        smolscale::spawn(async move {
+            loop {
+                smol::Timer::after(PRUNE_INTERVAL).await;
+                match weak.upgrade() {
+                    Some(seen) => seen.retain(|_, seen| seen.at.elapsed() < IDEMPOTENCY_WINDOW),
+                    None => break,
+                }
+            }
+        })
+        .detach();
+        Self { seen }
+    }
+}
+
+/// Forgets a key whose request never got a response saved, such as one dropped when its client went away, so that a retry runs rather than being refused as still in progress.
+struct InFlight<'a> {
+    seen: &'a SeenMap,
+    id: (String, Method, String),
+    finished: bool,
+}
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.seen.remove(&self.id);
+        }
+    }
+}
+
+struct Seen {
+    at: Instant,
+    body_hash: HashVal,
+    /// The response, or nothing while the first request is still running.
+    response: Option<SavedResponse>,
+}
+
+struct SavedResponse {
+    status: StatusCode,
+    headers: Vec<(HeaderName, HeaderValues)>,
+    mime: Mime,
+    body: Vec<u8>,
+}
+
+impl SavedResponse {
+    fn replay(&self) -> tide::Response {
+        let mut res = tide::Response::new(self.status);
+        for (name, values) in self.headers.iter() {
+            res.insert_header(name.clone(), values);
+        }
+        res.insert_header("Idempotent-Replayed", "true");
+        res.set_body(body_of(self.body.clone(), self.mime.clone()));
+        res
+    }
+}
+
+/// A body with the given bytes, keeping the content type of the body they were taken from.
+fn body_of(bytes: Vec<u8>, mime: Mime) -> Body {
+    let mut body = Body::from_bytes(bytes);
+    body.set_mime(mime);
+    body
+}
+
+fn refuse(status: StatusCode, why: &str) -> tide::Response {
+    let mut res = tide::Response::new(status);
+    res.set_body(why.to_string());
+    res
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for Idempotency {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let key = match req.header("Idempotency-Key") {
+            Some(key) if req.method() != Method::Get => key.last().as_str().to_string(),
+            _ => return Ok(next.run(req).await),
+        };
+        // a reused key only counts as a retry if the body is the same too
+        let body = req.take_body();
+        let mime = body.mime().clone();
+        let body = body.into_bytes().await?;
+        let body_hash = tmelcrypt::hash_single(&body);
+        req.set_body(body_of(body, mime));
+
+        let id = (key, req.method(), req.url().path().to_string());
+        let fresh = Seen {
+            at: Instant::now(),
+            body_hash,
+            response: None,
+        };
+        match self.seen.entry(id.clone()) {
+            // keys past the window may linger until the next pruning, but count as unused
+            Entry::Occupied(mut seen) if seen.get().at.elapsed() >= IDEMPOTENCY_WINDOW => {
+                seen.insert(fresh);
+            }
+            Entry::Occupied(seen) => {
+                let seen = seen.get();
+                if seen.body_hash != body_hash {
+                    return Ok(refuse(
+                        StatusCode::UnprocessableEntity,
+                        "idempotency key was already used for a different request",
+                    ));
+                }
+                return Ok(match seen.response.as_ref() {
+                    Some(saved) => saved.replay(),
+                    None => refuse(
+                        StatusCode::Conflict,
+                        "a request with this idempotency key is still in progress",
+                    ),
+                });
+            }
+            Entry::Vacant(vacant) => {
+                vacant.insert(fresh);
+            }
+        }
+
+        let mut in_flight = InFlight {
+            seen: &self.seen,
+            id,
+            finished: false,
+        };
+        let mut res = next.run(req).await;
+        if !res.status().is_success() || res.error().is_some() {
+            return Ok(res);
+        }
+        let body = res.take_body();
+        let mime = body.mime().clone();
+        let body = body.into_bytes().await?;
+        let saved = SavedResponse {
+            status: res.status(),
+            headers: res
+                .iter()
+                .map(|(name, values)| (name.clone(), values.clone()))
+                .collect(),
+            mime: mime.clone(),
+            body: body.clone(),
+        };
+        res.set_body(body_of(body, mime));
+        if let Some(mut seen) = self.seen.get_mut(&in_flight.id) {
+            seen.response = Some(saved);
+            in_flight.finished = true;
+        }
+        Ok(res)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    };
+
+    use tide::http::{Request as HttpRequest, Response as HttpResponse, Url};
+
+    use super::*;
+
+    fn post(key: &str, body: &str) -> HttpRequest {
+        let mut req = HttpRequest::new(Method::Post, Url::parse("http://localhost/send").unwrap());
+        req.insert_header("Idempotency-Key", key);
+        req.set_body(body);
+        req
+    }
+
+    #[test]
+    fn replays_instead_of_rerunning() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let mut app = tide::with_state(runs.clone());
+        app.with(Idempotency::new());
+        app.at("/send")
+            .post(|req: Request<Arc<AtomicU32>>| async move {
+                Ok(req.state().fetch_add(1, Ordering::SeqCst).to_string())
+            });
+        smol::block_on(async {
+            let mut first: HttpResponse = app.respond(post("a", "x")).await.unwrap();
+            let mut again: HttpResponse = app.respond(post("a", "x")).await.unwrap();
+            assert_eq!(again.header("Idempotent-Replayed").unwrap(), "true");
+            assert_eq!(
+                first.body_string().await.unwrap(),
+                again.body_string().await.unwrap()
+            );
+            let other: HttpResponse = app.respond(post("a", "y")).await.unwrap();
+            assert_eq!(other.status(), StatusCode::UnprocessableEntity);
+            let _: HttpResponse = app.respond(post("b", "x")).await.unwrap();
+        });
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn dropped_request_frees_its_key() {
+        let runs = Arc::new(AtomicU32::new(0));
+        let mut app = tide::with_state(runs.clone());
+        app.with(Idempotency::new());
+        app.at("/send")
+            .post(|req: Request<Arc<AtomicU32>>| async move {
+                if req.state().fetch_add(1, Ordering::SeqCst) == 0 {
+                    smol::future::pending::<()>().await;
+                }
+                Ok("done")
+            });
+        smol::block_on(async {
+            let gave_up = smol::future::or(
+                async {
+                    let _: HttpResponse = app.respond(post("a", "x")).await.unwrap();
+                    false
+                },
+                async {
+                    smol::Timer::after(Duration::from_millis(50)).await;
+                    true
+                },
+            )
+            .await;
+            assert!(gave_up);
+            let retry: HttpResponse = app.respond(post("a", "x")).await.unwrap();
+            assert_eq!(retry.status(), StatusCode::Ok);
+        });
+        assert_eq!(runs.load(Ordering::SeqCst), 2);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use themelio_structs::{BlockHeight, Transaction};
+
+/// A transaction that reached the network, but whose sending could not be recorded in the wallet database.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct UncommittedSend {
+    pub wallet_name: String,
+    pub transaction: Transaction,
+    pub sent_height: BlockHeight,
+    pub timeout: BlockHeight,
+}
+
+/// Append-only file of uncommitted sends, one JSON object per line, kept so that they can be recorded once the database works again. Plain appends are used rather than an `AcidJson`, since the journal matters most exactly when the disk is misbehaving.
+pub struct SendJournal {
+    path: PathBuf,
+}
+
+impl SendJournal {
+    /// Uses the journal at the given path, which need not exist yet.
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_owned(),
+        }
+    }
+
+    /// Appends a send to the journal.
+    pub fn record(&self, send: &UncommittedSend) -> anyhow::Result<()> {
+        let mut line = serde_json::to_vec(send)?;
+        line.push(b'\n');
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        file.write_all(&line)?;
+        file.sync_all()?;
+        Ok(())
+    }
+
+    /// Every send in the journal. Lines that don't parse, such as one cut short by a full disk, are skipped.
+    pub fn entries(&self) -> anyhow::Result<Vec<UncommittedSend>> {
+        let contents = match std::fs::read_to_string(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        Ok(contents
+            .lines()
+            .filter_map(|line| match serde_json::from_str(line) {
+                Ok(send) => Some(send),
+                Err(err) => {
+                    log::warn!("skipping unreadable line of the send journal: {}", err);
+                    None
+                }
+            })
+            .collect())
+    }
+
+    /// Replaces the journal's contents with the given sends, removing the file once none are left.
+    pub fn replace(&self, sends: &[UncommittedSend]) -> anyhow::Result<()> {
+        if sends.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            };
+        }
+        let mut contents = vec![];
+        for send in sends {
+            contents.extend(serde_json::to_vec(send)?);
+            contents.push(b'\n');
+        }
+        std::fs::write(&self.path, contents)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trips_sends() {
+        let path = std::env::temp_dir().join(format!("send-journal-{}", std::process::id()));
+        let journal = SendJournal::new(&path);
+        assert!(journal.entries().unwrap().is_empty());
+        let send = UncommittedSend {
+            wallet_name: "alice".into(),
+            transaction: Transaction::empty_test(),
+            sent_height: BlockHeight(5),
+            timeout: BlockHeight(15),
+        };
+        journal.record(&send).unwrap();
+        journal.record(&send).unwrap();
+        assert_eq!(journal.entries().unwrap().len(), 2);
+        journal.replace(&[send]).unwrap();
+        assert_eq!(journal.entries().unwrap().len(), 1);
+        journal.replace(&[]).unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,45 @@
+use smol::io::AsyncReadExt;
+use tide::{http::Method, Body, Middleware, Next, Request, StatusCode};
+
+/// Middleware refusing request bodies larger than a limit, before any handler gets to buffer them.
+pub struct BodyLimit {
+    max_bytes: u64,
+}
+
+impl BodyLimit {
+    pub fn new(max_bytes: u64) -> Self {
+        Self { max_bytes }
+    }
+
+    fn too_large(&self) -> tide::Response {
+        let mut res = tide::Response::new(StatusCode::PayloadTooLarge);
+        res.set_body(format!(
+            "request body is larger than the limit of {} bytes",
+            self.max_bytes
+        ));
+        res
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for BodyLimit {
+    async fn handle(&self, mut req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if let Some(len) = req.len() {
+            if len as u64 > self.max_bytes {
+                return Ok(self.too_large());
+            }
+        } else if req.method() != Method::Get {
+            // no declared length, so we read just past the limit to find out
+            let mut buf = vec![];
+            req.take_body()
+                .take(self.max_bytes + 1)
+                .read_to_end(&mut buf)
+                .await?;
+            if buf.len() as u64 > self.max_bytes {
+                return Ok(self.too_large());
+            }
+            req.set_body(Body::from_bytes(buf));
+        }
+        Ok(next.run(req).await)
+    }
+}
@@ -0,0 +1,217 @@
+use std::{
+    fmt::{self, Debug, Display, Formatter},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use async_std::{io, net::TcpListener, prelude::*};
+use socket2::{Domain, Protocol, Socket, Type};
+use tide::{
+    listener::{ListenInfo, Listener, ToListener},
+    Server,
+};
+use tide_rustls::async_rustls::TlsAcceptor;
+
+/// A listener, optionally speaking TLS, that serves at most `max_connections` connections at once. Past that, new connections are closed right away, instead of piling up until the daemon runs out of file descriptors.
+pub struct LimitedListener<State> {
+    addr: SocketAddr,
+    backlog: u32,
+    max_connections: usize,
+    tls: Option<TlsAcceptor>,
+    listener: Option<TcpListener>,
+    server: Option<Server<State>>,
+}
+
+impl<State> LimitedListener<State> {
+    /// Creates a listener for an address, with the given accept backlog and connection limit.
+    pub fn new(addr: SocketAddr, backlog: u32, max_connections: usize) -> Self {
+        Self {
+            addr,
+            backlog,
+            max_connections,
+            tls: None,
+            listener: None,
+            server: None,
+        }
+    }
+
+    /// Serves HTTPS, handshaking with this acceptor, rather than plain HTTP.
+    pub fn tls(mut self, acceptor: TlsAcceptor) -> Self {
+        self.tls = Some(acceptor);
+        self
+    }
+}
+
+/// One of a limited number of connection slots, given back when dropped.
+struct ConnectionSlot(Arc<AtomicUsize>);
+
+impl ConnectionSlot {
+    /// Takes a slot, unless all `max` of them are in use.
+    fn take(active: &Arc<AtomicUsize>, max: usize) -> Option<Self> {
+        active
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+                (n < max).then(|| n + 1)
+            })
+            .ok()
+            .map(|_| Self(active.clone()))
+    }
+}
+
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+async fn serve<State: Clone + Send + Sync + 'static>(
+    server: Server<State>,
+    stream: async_std::net::TcpStream,
+    tls: Option<TlsAcceptor>,
+) {
+    let local_addr = stream.local_addr().ok();
+    let peer_addr = stream.peer_addr().ok();
+    let respond = |https: bool| {
+        move |mut req: http_types::Request| {
+            let server = server.clone();
+            async move {
+                if https && req.url_mut().set_scheme("https").is_err() {
+                    log::error!("cannot set the https scheme on {}", req.url());
+                }
+                req.set_local_addr(local_addr);
+                req.set_peer_addr(peer_addr);
+                server.respond(req).await
+            }
+        }
+    };
+    let result = match tls {
+        None => async_h1::accept(stream, respond(false)).await,
+        Some(acceptor) => match acceptor.accept(stream).await {
+            // the HTTP codec needs a stream it can clone
+            Ok(stream) => {
+                let stream = async_dup::Arc::new(async_dup::Mutex::new(stream));
+                async_h1::accept(stream, respond(true)).await
+            }
+            Err(err) => {
+                log::debug!("tls handshake with {:?} failed: {}", peer_addr, err);
+                return;
+            }
+        },
+    };
+    if let Err(err) = result {
+        log::debug!("connection from {:?} failed: {}", peer_addr, err);
+    }
+}
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Listener<State> for LimitedListener<State> {
+    async fn bind(&mut self, server: Server<State>) -> io::Result<()> {
+        let socket = Socket::new(
+            Domain::for_address(self.addr),
+            Type::STREAM,
+            Some(Protocol::TCP),
+        )?;
+        socket.set_reuse_address(true)?;
+        socket.bind(&self.addr.into())?;
+        socket.listen(self.backlog.min(i32::MAX as u32) as i32)?;
+        socket.set_nonblocking(true)?;
+        self.listener = Some(TcpListener::from(std::net::TcpListener::from(socket)));
+        self.server = Some(server);
+        Ok(())
+    }
+
+    async fn accept(&mut self) -> io::Result<()> {
+        let listener = self
+            .listener
+            .take()
+            .expect("`Listener::bind` must be called before `Listener::accept`");
+        let server = self
+            .server
+            .take()
+            .expect("`Listener::bind` must be called before `Listener::accept`");
+        let active = Arc::new(AtomicUsize::new(0));
+        let mut incoming = listener.incoming();
+        while let Some(stream) = incoming.next().await {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(err) => {
+                    // running out of file descriptors is no reason to stop listening, so we wait for some to free up
+                    log::warn!("cannot accept a connection: {}", err);
+                    smol::Timer::after(Duration::from_millis(100)).await;
+                    continue;
+                }
+            };
+            let slot = match ConnectionSlot::take(&active, self.max_connections) {
+                Some(slot) => slot,
+                None => {
+                    log::debug!(
+                        "closing a connection from {:?}, as {} are open already",
+                        stream.peer_addr().ok(),
+                        self.max_connections
+                    );
+                    continue;
+                }
+            };
+            let server = server.clone();
+            let tls = self.tls.clone();
+            smolscale::spawn(async move {
+                let _slot = slot;
+                serve(server, stream, tls).await
+            })
+            .detach();
+        }
+        Ok(())
+    }
+
+    fn info(&self) -> Vec<ListenInfo> {
+        vec![ListenInfo::new(
+            self.to_string(),
+            "tcp".into(),
+            self.tls.is_some(),
+        )]
+    }
+}
+
+impl<State: Clone + Send + Sync + 'static> ToListener<State> for LimitedListener<State> {
+    type Listener = Self;
+
+    fn to_listener(self) -> io::Result<Self::Listener> {
+        Ok(self)
+    }
+}
+
+impl<State> Debug for LimitedListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LimitedListener")
+            .field("addr", &self.addr)
+            .field("backlog", &self.backlog)
+            .field("max_connections", &self.max_connections)
+            .field("tls", &self.tls.is_some())
+            .finish()
+    }
+}
+
+impl<State> Display for LimitedListener<State> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let scheme = if self.tls.is_some() { "https" } else { "http" };
+        write!(f, "{}://{}", scheme, self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_run_out_and_come_back() {
+        let active = Arc::new(AtomicUsize::new(0));
+        let first = ConnectionSlot::take(&active, 2).unwrap();
+        let _second = ConnectionSlot::take(&active, 2).unwrap();
+        assert!(ConnectionSlot::take(&active, 2).is_none());
+        drop(first);
+        assert!(ConnectionSlot::take(&active, 2).is_some());
+    }
+}
@@ -0,0 +1,38 @@
+use std::sync::Arc;
+
+use tide::{Middleware, Next, Request};
+use tracing::Instrument;
+use uuid::Uuid;
+
+use crate::state::AppState;
+
+/// Middleware giving every request an ID, logging it within a span carrying that ID, and turning errors into response bodies that mention the ID. The ID is also returned in the `X-Request-Id` header.
+pub struct LogRequests;
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<AppState>> for LogRequests {
+    async fn handle(
+        &self,
+        req: Request<Arc<AppState>>,
+        next: Next<'_, Arc<AppState>>,
+    ) -> tide::Result {
+        let request_id = Uuid::new_v4();
+        let span = tracing::info_span!("request", id = %request_id);
+        async move {
+            log::info!("{}", req.url());
+            req.state().metrics.record_request(req.url().path());
+            let mut res = next.run(req).await;
+            if let Some(err) = res.error() {
+                // put the error string in the response
+                let err_str = format!("ERROR: {:?} (request {})", err, request_id);
+                log::warn!("{}", err_str);
+                res.set_body(err_str);
+                res.set_content_type(tide::http::mime::PLAIN);
+            }
+            res.insert_header("X-Request-Id", request_id.to_string());
+            Ok(res)
+        }
+        .instrument(span)
+        .await
+    }
+}
@@ -1,61 +1,124 @@
+mod auth;
 mod cli;
+mod compression;
 mod database;
+mod decimal;
+mod dirlock;
+mod idempotency;
+mod journal;
+mod limits;
+mod listener;
+mod logging;
+mod metrics;
+mod openapi;
+mod proxy;
+mod readonly;
+mod reload;
+mod retry;
 mod secrets;
+mod selection;
+mod shutdown;
 mod signer;
 mod state;
+mod subaddress;
+mod template;
 
 mod walletdata;
 use std::convert::TryFrom;
 
-use std::{collections::BTreeMap, ffi::CString, sync::Arc};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::CString,
+    fs::File,
+    io::BufReader,
+    path::Path,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
 use anyhow::Context;
 use base32::Alphabet;
-use http_types::headers::HeaderValue;
-use serde::{Deserialize, Serialize};
-use state::AppState;
+use futures_util::{StreamExt, TryStreamExt};
+use parking_lot::RwLock;
+use regex::Regex;
+use reload::Reloader;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use state::{AppState, FaucetSettings, StillSyncing};
+use stdcode::StdcodeSerializeExt;
 use tap::Tap;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
 use clap::Parser;
 
+use num_rational::BigRational;
+use num_traits::{One, Zero};
 use std::fmt::Debug;
-use themelio_nodeprot::ValClient;
-use themelio_structs::PoolKey;
+use themelio_nodeprot::{TrustedHeight, ValClient, ValClientSnapshot};
+use themelio_stf::melvm::{covenant_weight_from_bytes, Covenant, CovenantEnv};
 use themelio_structs::{
-    BlockHeight, CoinData, CoinID, CoinValue, Denom, NetID, Transaction, TxKind,
+    Address, BlockHeight, CoinData, CoinID, CoinValue, Denom, Header, NetID, StakeDoc, Transaction,
+    TxHash, TxKind,
 };
-use tide::security::CorsMiddleware;
+use themelio_structs::{PoolKey, PoolState};
 use tide::{Body, Request, StatusCode};
-use tmelcrypt::{Ed25519SK, HashVal, Hashable};
-use walletdata::{AnnCoinID, TransactionStatus};
+use tide_rustls::async_rustls::TlsAcceptor;
+use tide_rustls::rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+use tide_rustls::rustls::{NoClientAuth, ServerConfig};
+use tide_websockets::{WebSocket, WebSocketConnection};
+use tmelcrypt::{Ed25519PK, Ed25519SK, HashVal, Hashable};
+use walletdata::{AnnCoinID, AnnInput, CoinEvent, TransactionStatus, WalletDump};
 
 use crate::cli::*;
-use crate::{database::Database, secrets::SecretStore, signer::Signer};
-
-fn generate_cors(origins: Vec<String>) -> CorsMiddleware {
-    let cors = origins
-        .iter()
-        .fold(CorsMiddleware::new(), |cors, val| {
-            let s: &str = val;
-            cors.allow_origin(s)
-        })
-        .allow_methods("GET, POST, PUT".parse::<HeaderValue>().unwrap())
-        .allow_credentials(false);
+use crate::{
+    database::{find_spender, find_stake_locks, Database, Wallet},
+    decimal::{ratio_of, Num, Precision},
+    dirlock::DirLock,
+    journal::SendJournal,
+    listener::LimitedListener,
+    retry::RetryPolicy,
+    secrets::{PersistentSecret, SecretStore},
+    selection::CoinSelection,
+    signer::{ExternalSigner, Signer, ThresholdSigner},
+    template::{valid_template_name, TxTemplate, MAX_TEMPLATES},
+};
 
-    cors
+/// Loads a certificate chain and its private key from PEM files, checking that they go together.
+fn load_tls_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<ServerConfig> {
+    let certs = certs(&mut BufReader::new(
+        File::open(cert_path).context("cannot open tls_cert_path")?,
+    ))
+    .map_err(|_| anyhow::anyhow!("tls_cert_path is not a PEM certificate chain"))?;
+    let key_pem = std::fs::read(key_path).context("cannot open tls_key_path")?;
+    let mut keys = pkcs8_private_keys(&mut key_pem.as_slice()).unwrap_or_default();
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut key_pem.as_slice()).unwrap_or_default();
+    }
+    let key = keys
+        .into_iter()
+        .next()
+        .context("tls_key_path contains no PEM private key")?;
+    let mut tls_config = ServerConfig::new(NoClientAuth::new());
+    tls_config
+        .set_single_cert(certs, key)
+        .context("TLS certificate and key do not go together")?;
+    Ok(tls_config)
 }
 
 fn main() -> anyhow::Result<()> {
     smolscale::block_on(async {
-        let log_conf = std::env::var("RUST_LOG").unwrap_or_else(|_| "melwalletd=debug,warn".into());
-        std::env::set_var("RUST_LOG", log_conf);
-        tracing_subscriber::fmt::init();
+        let (log_filter, log_filter_handle) =
+            tracing_subscriber::reload::Layer::new(EnvFilter::new(reload::default_log_filter()));
+        tracing_subscriber::registry()
+            .with(log_filter)
+            .with(tracing_subscriber::fmt::layer())
+            .init();
 
         // let clap = __clap;
         let cmd_args = Args::from_args();
 
         let output_config = cmd_args.output_config;
         let dry_run = cmd_args.dry_run;
+        let config_path = cmd_args.config.clone();
 
         let config = match Config::try_from(cmd_args) {
             Ok(i) => anyhow::Ok(i),
@@ -65,8 +128,27 @@ fn main() -> anyhow::Result<()> {
             }
         }?;
 
+        let wallet_name_pattern = Regex::new(&config.wallet_name_pattern)
+            .context("invalid wallet_name_pattern in configuration")?;
+
+        let tls_config = match (&config.tls_cert_path, &config.tls_key_path) {
+            (Some(cert), Some(key)) => Some(load_tls_config(cert, key)?),
+            (None, None) => None,
+            _ => anyhow::bail!("tls_cert_path and tls_key_path must be given together"),
+        };
+
         let network = config.network;
         let addr = config.network_addr;
+        let trusted_height = match (config.trust_height, config.trust_hash.as_ref()) {
+            (Some(height), Some(hash)) => Some(TrustedHeight {
+                height: BlockHeight(height),
+                header_hash: hash
+                    .parse()
+                    .context("trust_hash is not a valid hash in configuration")?,
+            }),
+            (None, None) => themelio_bootstrap::checkpoint_height(network),
+            _ => anyhow::bail!("trust_height and trust_hash must be given together"),
+        };
 
         let db_name = format!("{network:?}-wallets.db").to_ascii_lowercase();
         if output_config {
@@ -81,107 +163,408 @@ fn main() -> anyhow::Result<()> {
             return Ok(());
         }
 
-        std::fs::create_dir_all(&config.wallet_dir).context("cannot create wallet_dir")?;
-
-        // SAFETY: this is perfectly safe because chmod cannot lead to memory unsafety.
-        unsafe {
-            libc::chmod(
-                CString::new(config.wallet_dir.to_string_lossy().as_bytes().to_vec())?.as_ptr(),
-                0o700,
-            );
-        }
-        let db = Database::open(config.wallet_dir.clone().tap_mut(|p| p.push(db_name))).await?;
-
+        let db_path = config.wallet_dir.clone().tap_mut(|p| p.push(db_name));
+        // read-only daemons serve another daemon's directory, so only writers take the lock
+        let (db, _dir_lock) = if config.read_only {
+            log::info!("serving {:?} read-only", config.wallet_dir);
+            let db = Database::open_read_only(db_path)
+                .await
+                .context("cannot open the wallet database read-only")?;
+            (db, None)
+        } else {
+            std::fs::create_dir_all(&config.wallet_dir).context("cannot create wallet_dir")?;
+
+            // SAFETY: this is perfectly safe because chmod cannot lead to memory unsafety.
+            unsafe {
+                libc::chmod(
+                    CString::new(config.wallet_dir.to_string_lossy().as_bytes().to_vec())?.as_ptr(),
+                    0o700,
+                );
+            }
+            let dir_lock = DirLock::acquire(&config.wallet_dir)?;
+            (Database::open(db_path).await?, Some(dir_lock))
+        };
+
+        // the client only knows how to dial the node directly, so with a proxy it dials a local forwarder instead
+        let (addr, _forwarder) = match config.proxy {
+            Some(proxy) => {
+                proxy::socks5_connect(proxy, addr)
+                    .await
+                    .with_context(|| format!("cannot reach {} through proxy {}", addr, proxy))?;
+                let (local, forwarder) = proxy::forward_through(proxy, addr).await?;
+                log::info!("connecting to {} through proxy {}", addr, proxy);
+                (local, Some(forwarder))
+            }
+            None => (addr, None),
+        };
+        // a header verified by an earlier run spares re-verifying the chain up to it, as long as that run started from the same root
+        let start_from = match (trusted_height.as_ref(), db.verified_header(network).await) {
+            (Some(root), Some(verified))
+                if verified.root.height == root.height
+                    && verified.root.header_hash == root.header_hash
+                    && verified.header.height > root.height =>
+            {
+                Some(verified.header)
+            }
+            (Some(_), Some(_)) => {
+                log::warn!("ignoring the saved verified header, which was verified from a different checkpoint");
+                trusted_height.clone()
+            }
+            _ => trusted_height.clone(),
+        };
         let client = ValClient::new(network, addr);
-        if network == NetID::Mainnet || network == NetID::Testnet {
-            client.trust(themelio_bootstrap::checkpoint_height(network).unwrap());
+        if let Some(trusted_height) = start_from {
+            log::info!(
+                "trusting block {} with hash {}",
+                trusted_height.height,
+                trusted_height.header_hash
+            );
+            client.trust(trusted_height);
         } else {
-            log::warn!("** BLINDLY TRUSTING FULL NODE due to custom network **");
+            log::error!("** BLINDLY TRUSTING FULL NODE due to custom network **");
+            log::error!("** set trust_height and trust_hash to supply a trust anchor **");
+            #[allow(deprecated)]
             client.insecure_latest_snapshot().await?;
         }
 
         let mut secret_path = config.wallet_dir.clone();
         secret_path.push(".secrets.json");
-        let secrets = SecretStore::open(&secret_path)?;
-
-        let state = AppState::new(db, network, secrets, addr, client);
-
-        let mut app = tide::with_state(Arc::new(state));
-
-        async fn log_request<T>(req: Request<T>) -> Request<T> {
-            log::info!("{}", req.url());
-            req
+        let secrets = if config.read_only {
+            SecretStore::open_read_only(&secret_path)?
+        } else {
+            SecretStore::open(&secret_path)?
+        };
+
+        let confirm_settings = Arc::new(RwLock::new(reload::confirm_settings_of(&config)));
+        let reloader = Reloader::new(
+            config_path,
+            config.clone(),
+            log_filter_handle,
+            confirm_settings.clone(),
+        )?;
+
+        let state = AppState::new(
+            db,
+            network,
+            secrets,
+            client,
+            trusted_height,
+            Duration::from_secs(config.coin_cache_secs),
+            Duration::from_secs(config.coin_reservation_secs),
+            wallet_name_pattern,
+            confirm_settings,
+            FaucetSettings {
+                max_value: CoinValue(config.faucet_max_value),
+                interval: Duration::from_secs(config.faucet_interval_secs),
+            },
+            RetryPolicy {
+                max_attempts: config.node_retry_attempts,
+            },
+            BlockHeight(config.commit_timeout_blocks()),
+            config.coin_selection,
+            config.fragmentation_threshold,
+            SendJournal::new(&config.wallet_dir.join(".send-journal.jsonl")),
+            reloader,
+            config.read_only,
+        );
+
+        if !config.read_only {
+            state
+                .reconcile_sends()
+                .await
+                .context("cannot reconcile the send journal")?;
+        }
+        let state = Arc::new(state);
+        let shutdown = state.shutdown.clone();
+        {
+            let shutdown = shutdown.clone();
+            ctrlc::set_handler(move || shutdown.trigger())?;
         }
-        app.with(tide::utils::Before(log_request));
 
-        // interpret errors
-        app.with(tide::utils::After(|mut res: tide::Response| async move {
-            if let Some(err) = res.error() {
-                // put the error string in the response
-                let err_str = format!("ERROR: {:?}", err);
-                log::warn!("{}", err_str);
-                res.set_body(err_str);
-            }
-            Ok(res)
-        }));
+        let mut app = tide::with_state(state.clone());
+        app.with(shutdown::TrackRequests(shutdown.clone()));
+        app.with(logging::LogRequests);
+        app.with(limits::BodyLimit::new(config.max_body_bytes));
+        app.with(compression::Compress::new(
+            config.compression,
+            config.compression_min_bytes,
+        ));
+        app.at("/health").get(get_health);
+        // stopping the daemon is only offered to callers who prove themselves with the token; without one, signals are the only way
+        if config.auth_token.is_some() {
+            app.at("/shutdown").post(shutdown_daemon);
+        }
+        app.at("/reload-config").post(reload_config);
+        app.at("/ready").get(get_ready);
+        app.at("/metrics").get(get_metrics);
+        app.at("/openapi.json").get(get_openapi);
         app.at("/summary").get(get_summary);
+        app.at("/debug/inflight").get(get_inflight);
+        app.at("/denoms").get(get_denoms);
         app.at("/pools/:pair").get(get_pool);
+        app.at("/pools/:pair/history").get(get_pool_history);
+        app.at("/pools/:pair/simulate").post(simulate_pool);
         app.at("/pool_info").post(get_pool_info);
+        app.at("/swap-route").post(get_swap_route);
+        app.at("/estimate-confirmation").post(estimate_confirmation);
+        app.at("/fee-stats").get(get_fee_stats);
+        app.at("/broadcast").post(broadcast_tx);
+        app.at("/decode-tx").post(decode_tx);
+        app.at("/coins").get(dump_all_coins);
+        app.at("/transactions").get(dump_all_transactions);
+        app.at("/coins/by-address/:address")
+            .get(dump_coins_by_address);
+        app.at("/address/:address/used").get(get_address_used);
         app.at("/wallets").get(list_wallets);
         app.at("/wallets/:name").get(summarize_wallet);
         app.at("/wallets/:name").put(create_wallet);
+        app.at("/wallets/:name").delete(delete_wallet);
+        app.at("/wallets/:name/rename").post(rename_wallet);
+        app.at("/wallets/:name/dump").post(dump_wallet);
+        app.at("/wallets/:name/restore").post(restore_wallet);
         app.at("/wallets/:name/lock").post(lock_wallet);
         app.at("/wallets/:name/unlock").post(unlock_wallet);
+        app.at("/wallets/:name/lock-status").get(lock_status);
+        app.at("/wallets/:name/change-password")
+            .post(change_password);
         app.at("/wallets/:name/export-sk")
             .post(export_sk_from_wallet);
+        app.at("/wallets/:name/export-mnemonic")
+            .post(export_mnemonic_from_wallet);
+        app.at("/wallets/:name/covenant").get(get_covenant);
+        app.at("/wallets/:name/address").get(get_address);
+        app.at("/wallets/:name/tags").post(tag_wallet);
+        app.at("/wallets/:name/auto-bump").post(set_auto_bump);
+        app.at("/wallets/:name/balance").get(get_balance);
         app.at("/wallets/:name/coins").get(dump_coins);
+        app.at("/wallets/:name/utxo-count").get(get_utxo_count);
+        app.at("/wallets/:name/coins/since/:height")
+            .get(dump_coins_since);
+        app.at("/wallets/:name/subaddress/:index")
+            .get(get_subaddress);
+        app.at("/wallets/:name/import-coins").post(import_coins);
+        app.at("/wallets/:name/coins/:coinid/unlock")
+            .post(unlock_coin);
+        app.at("/wallets/:name/coins/:coinid/freeze")
+            .post(freeze_coin);
+        app.at("/wallets/:name/coins/:coinid/unfreeze")
+            .post(unfreeze_coin);
         app.at("/wallets/:name/prepare-tx").post(prepare_tx);
+        app.at("/wallets/:name/prepare-batch").post(prepare_batch);
+        app.at("/wallets/:name/templates").get(list_templates);
+        app.at("/wallets/:name/templates").post(put_template);
+        app.at("/wallets/:name/templates/:tpl")
+            .delete(delete_template);
+        app.at("/wallets/:name/templates/:tpl/prepare")
+            .post(prepare_from_template);
+        app.at("/wallets/:name/estimate-fee").post(estimate_fee);
+        app.at("/wallets/:name/preview-tx").post(preview_tx);
+        app.at("/wallets/:name/prepare-swap").post(prepare_swap);
+        app.at("/wallets/:name/prepare-stake").post(prepare_stake);
+        app.at("/wallets/:name/consolidate").post(consolidate);
+        app.at("/wallets/:name/sweep").post(sweep);
+        app.at("/wallets/:name/add-signature").post(add_signature);
         app.at("/wallets/:name/send-tx").post(send_tx);
+        app.at("/wallets/:name/prepare-and-send")
+            .post(prepare_and_send);
         app.at("/wallets/:name/send-faucet").post(send_faucet);
+        app.at("/wallets/:name/subscribe")
+            .get(WebSocket::new(subscribe_coins));
         app.at("/wallets/:name/transactions").get(dump_transactions);
+        app.at("/wallets/:name/pending").get(dump_pending);
         app.at("/wallets/:name/transactions/:txhash").get(get_tx);
+        app.at("/wallets/:name/transactions/:txhash/rebroadcast")
+            .post(rebroadcast_tx);
+        app.at("/wallets/:name/transactions/:txhash/label")
+            .post(label_tx);
+        app.at("/wallets/:name/transactions/:txhash/abandon")
+            .post(abandon_tx);
+        app.at("/wallets/:name/transactions/:txhash/stream")
+            .get(tide::sse::endpoint(stream_tx));
         app.at("/wallets/:name/transactions/:txhash/balance")
             .get(get_tx_balance);
 
-        let cors = generate_cors(config.allowed_origins);
+        app.with(reload::ReloadableCors);
 
-        app.with(cors);
+        if let Some(token) = config.auth_token.clone() {
+            log::info!("requiring a bearer token for API access");
+            app.with(auth::BearerAuth::new(token));
+        }
+        if config.read_only {
+            app.with(readonly::RefuseWrites);
+        }
+        app.with(idempotency::Idempotency::new());
 
         log::info!("Starting server at {}", config.listen);
-        app.listen(config.listen).await?;
+        // dropping the listener stops accepting connections, leaving the ones in progress alone
+        let listener =
+            LimitedListener::new(config.listen, config.listen_backlog, config.max_connections);
+        let listener = match tls_config {
+            Some(tls_config) => listener.tls(TlsAcceptor::from(Arc::new(tls_config))),
+            None => listener,
+        };
+        smol::future::or(
+            async {
+                app.listen(listener).await?;
+                Ok(())
+            },
+            async {
+                shutdown.wait().await;
+                anyhow::Ok(())
+            },
+        )
+        .await?;
 
+        log::info!("shutting down, waiting for in-flight requests");
+        if !shutdown.drain(Duration::from_secs(30)).await {
+            log::warn!("gave up waiting for in-flight requests");
+        }
+        state.stop_confirm_task().await;
+        if !config.read_only {
+            state.database.flush().await?;
+        }
+        log::info!("shut down cleanly");
         Ok(())
     })
 }
 
 async fn summarize_wallet(req: Request<Arc<AppState>>) -> tide::Result<Body> {
     let wallet_name = req.param("name")?;
-    let wallet_list = req.state().list_wallets().await;
+    let wallet_list = req.state().list_wallets(None).await;
     let wallets = wallet_list
         .get(wallet_name)
         .cloned()
         .context("wallet not found")
         .map_err(to_notfound)?;
-    Body::from_json(&wallets)
+    negotiated_body(&req, &wallets)
+}
+
+#[derive(Serialize)]
+struct HealthStatus {
+    uptime_secs: u64,
+    checkpoint_height: Option<BlockHeight>,
+    verified_height: Option<BlockHeight>,
+}
+
+impl HealthStatus {
+    async fn new(state: &AppState) -> Self {
+        Self {
+            uptime_secs: state.started.elapsed().as_secs(),
+            checkpoint_height: state.trusted_height.as_ref().map(|t| t.height),
+            verified_height: state
+                .database
+                .verified_header(state.network)
+                .await
+                .map(|verified| verified.header.height),
+        }
+    }
+}
+
+async fn get_health(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    // only touches the local database, so that upstream hiccups don't fail liveness
+    req.state().database.list_wallets().await;
+    Body::from_json(&HealthStatus::new(req.state()).await)
+}
+
+async fn shutdown_daemon(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    log::info!("shutdown requested over the API");
+    req.state().shutdown.trigger();
+    Ok(plain_text(""))
+}
+
+async fn reload_config(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        applied: Vec<String>,
+    }
+    let applied = req.state().reloader.reload().map_err(to_badreq)?;
+    log::info!("reloaded configuration, applying {:?}", applied);
+    Body::from_json(&Resp { applied })
+}
+
+async fn get_ready(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    req.state().database.list_wallets().await;
+    req.state().fresh_snapshot().await.map_err(to_unavailable)?;
+    Body::from_json(&HealthStatus::new(req.state()).await)
+}
+
+async fn get_metrics(req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    let state = req.state();
+    let wallet_count = state.database.list_wallets().await.len();
+    let pending_count = state.database.count_pending().await;
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.set_body(state.metrics.render(wallet_count, pending_count));
+    res.set_content_type("text/plain; version=0.0.4");
+    Ok(res)
+}
+
+async fn get_openapi(_req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    Body::from_json(&openapi::spec())
+}
+
+async fn get_inflight(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Inflight {
+        txhash: TxHash,
+        last_sent: u64,
+    }
+    let inflight: Vec<Inflight> = req
+        .state()
+        .database
+        .list_inflight()
+        .await
+        .into_iter()
+        .map(|(txhash, last_sent)| Inflight { txhash, last_sent })
+        .collect();
+    Body::from_json(&inflight)
 }
 
+/// How many blocks the verified height may trail the full node's tip before balances are flagged as possibly stale.
+const MAX_SYNC_LAG: u64 = 5;
+
 async fn get_summary(req: Request<Arc<AppState>>) -> tide::Result<Body> {
-    let client = req.state().client.clone();
-    let snap = client.snapshot().await.map_err(to_badgateway)?;
-    Body::from_json(&snap.current_header())
+    #[derive(Serialize)]
+    struct Resp {
+        #[serde(flatten)]
+        header: Header,
+        verified_height: BlockHeight,
+        node_height: BlockHeight,
+        lag: u64,
+        stale: bool,
+    }
+    let snap = req.state().snapshot().await.map_err(to_badgateway)?;
+    let header = snap.current_header();
+    // the node's own claim, which unlike the snapshot is not verified
+    let node_height = snap
+        .get_raw()
+        .get_summary()
+        .await
+        .map_err(to_badgateway)?
+        .height;
+    let lag = node_height.0.saturating_sub(header.height.0);
+    Body::from_json(&Resp {
+        verified_height: header.height,
+        node_height,
+        lag,
+        stale: lag > MAX_SYNC_LAG,
+        header,
+    })
 }
 
 async fn get_pool(req: Request<Arc<AppState>>) -> tide::Result<Body> {
-    let client = req.state().client.clone();
-    let pool_key: PoolKey = req
-        .param("pair")?
-        .replace(':', "/")
-        .parse()
-        .map_err(to_badreq)?;
-    let pool_key = pool_key
-        .to_canonical()
-        .ok_or_else(|| to_badreq(anyhow::anyhow!("bad pool key")))?;
-    let pool_state = client
+    #[derive(Serialize)]
+    struct Resp {
+        #[serde(flatten)]
+        pool_state: PoolState,
+        pair: String,
+        poolkey: String,
+        price_left_per_right: Num,
+        price_right_per_left: Num,
+    }
+    let precision: Precision = req.query()?;
+    let pool_key = parse_pool_pair(req.param("pair")?)?;
+    let pool_state = req
+        .state()
         .snapshot()
         .await
         .map_err(to_badgateway)?
@@ -189,7 +572,95 @@ async fn get_pool(req: Request<Arc<AppState>>) -> tide::Result<Body> {
         .await
         .map_err(to_badgateway)?
         .ok_or_else(|| to_badreq(anyhow::anyhow!("pool not found")))?;
-    Body::from_json(&pool_state)
+    Body::from_json(&Resp {
+        pair: pool_key.to_string(),
+        poolkey: hex::encode(pool_key.to_bytes()),
+        price_left_per_right: precision.ratio(&ratio_of(pool_state.lefts, pool_state.rights)),
+        price_right_per_left: precision.ratio(&ratio_of(pool_state.rights, pool_state.lefts)),
+        pool_state,
+    })
+}
+
+/// Most points a single pool history request may ask for.
+const MAX_POOL_HISTORY_POINTS: u64 = 500;
+
+/// How many historical snapshots a pool history request fetches at once.
+const POOL_HISTORY_CONCURRENCY: usize = 16;
+
+async fn get_pool_history(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Query {
+        from: Option<u64>,
+        to: Option<u64>,
+        #[serde(default = "default_resolution")]
+        resolution: u64,
+        #[serde(default)]
+        precise: bool,
+    }
+    fn default_resolution() -> u64 {
+        100
+    }
+    #[derive(Serialize)]
+    struct Point {
+        height: BlockHeight,
+        lefts: Num,
+        rights: Num,
+        price: Num,
+    }
+    let query: Query = req.query()?;
+    let pool_key = parse_pool_pair(req.param("pair")?)?;
+    if query.resolution == 0 {
+        return Err(to_badreq(anyhow::anyhow!("resolution must be positive")));
+    }
+    let snapshot = req.state().snapshot().await.map_err(to_badgateway)?;
+    let current_height = snapshot.current_header().height.0;
+    let to = query.to.unwrap_or(current_height).min(current_height);
+    let from = query.from.unwrap_or_else(|| {
+        to.saturating_sub(query.resolution.saturating_mul(MAX_POOL_HISTORY_POINTS - 1))
+    });
+    if from > to {
+        return Err(to_badreq(anyhow::anyhow!("from is after to")));
+    }
+    if (to - from) / query.resolution + 1 > MAX_POOL_HISTORY_POINTS {
+        return Err(to_badreq(anyhow::anyhow!(
+            "too many points requested; at most {} are allowed",
+            MAX_POOL_HISTORY_POINTS
+        )));
+    }
+
+    let semaphore = Arc::new(smol::lock::Semaphore::new(POOL_HISTORY_CONCURRENCY));
+    let fetches: Vec<_> = (from..=to)
+        .step_by(query.resolution as usize)
+        .map(|height| {
+            let snapshot = snapshot.clone();
+            let semaphore = semaphore.clone();
+            smolscale::spawn(async move {
+                let _permit = semaphore.acquire().await;
+                snapshot
+                    .get_older(BlockHeight(height))
+                    .await?
+                    .get_pool(pool_key)
+                    .await
+                    .map(|pool| (BlockHeight(height), pool))
+            })
+        })
+        .collect();
+    let precision = Precision {
+        precise: query.precise,
+    };
+    let mut points = vec![];
+    for fetch in fetches {
+        // heights before the pool existed are simply left out
+        if let (height, Some(pool)) = fetch.await.map_err(to_badgateway)? {
+            points.push(Point {
+                height,
+                lefts: precision.amount(pool.lefts, pool_key.left),
+                rights: precision.amount(pool.rights, pool_key.right),
+                price: precision.ratio(&ratio_of(pool.lefts, pool.rights)),
+            });
+        }
+    }
+    Body::from_json(&points)
 }
 
 async fn get_pool_info(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
@@ -201,24 +672,25 @@ async fn get_pool_info(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
     }
     #[derive(Serialize)]
     struct Resp {
-        result: u128,
-        price_impact: f64,
+        result: Num,
+        price_impact: Num,
         poolkey: String,
     }
 
+    let precision: Precision = req.query()?;
     let query: Req = req.body_json().await?;
 
-    let from = Denom::from_bytes(&hex::decode(&query.from)?).context("oh no")?;
-    let to = Denom::from_bytes(&hex::decode(&query.to)?).context("oh no")?;
+    let from = parse_denom(&query.from)?;
+    let to = parse_denom(&query.to)?;
 
-    let client = req.state().client.clone();
     if from == to {
         return Err(to_badreq(anyhow::anyhow!(
             "cannot swap between identical denoms"
         )));
     }
     let pool_key = PoolKey::new(from, to);
-    let pool_state = client
+    let pool_state = req
+        .state()
         .snapshot()
         .await
         .map_err(to_badgateway)?
@@ -227,225 +699,2656 @@ async fn get_pool_info(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
         .map_err(to_badgateway)?
         .ok_or_else(|| to_badreq(anyhow::anyhow!("pool not found")))?;
 
-    let left_to_right = pool_key.left == from;
+    let (result, price_impact) = simulate_swap(pool_key, pool_state, from, query.value);
+    let r = Resp {
+        result: precision.amount(result, to),
+        price_impact: precision.ratio(&price_impact),
+        poolkey: hex::encode(pool_key.to_bytes()),
+    };
 
-    let r = if left_to_right {
-        let old_price = pool_state.lefts as f64 / pool_state.rights as f64;
-        let mut new_pool_state = pool_state;
-        let (_, new) = new_pool_state.swap_many(query.value, 0);
-        let new_price = new_pool_state.lefts as f64 / new_pool_state.rights as f64;
-        Resp {
-            result: new,
-            price_impact: (new_price / old_price - 1.0),
-            poolkey: hex::encode(pool_key.to_bytes()),
-        }
-    } else {
-        let old_price = pool_state.rights as f64 / pool_state.lefts as f64;
-        let mut new_pool_state = pool_state;
-        let (new, _) = new_pool_state.swap_many(0, query.value);
-        let new_price = new_pool_state.rights as f64 / new_pool_state.lefts as f64;
-        Resp {
-            result: new,
-            price_impact: (new_price / old_price - 1.0),
-            poolkey: hex::encode(pool_key.to_bytes()),
+    Body::from_json(&r)
+}
+
+/// Most hypothetical swaps a single simulation may run ahead of the caller's own.
+const MAX_SIMULATED_SWAPS: usize = 1000;
+
+/// Runs a sequence of hypothetical swaps, such as a front-runner's, through a pool, then the caller's own swap against what is left. Nothing is sent anywhere.
+async fn simulate_pool(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Swap {
+        from: String,
+        value: u128,
+    }
+    #[derive(Deserialize)]
+    struct Req {
+        #[serde(default)]
+        swaps: Vec<Swap>,
+        swap: Swap,
+    }
+    #[derive(Serialize)]
+    struct Resp {
+        /// What each hypothetical swap received, in order.
+        results: Vec<Num>,
+        result: Num,
+        price_impact: Num,
+        /// The pool after every swap, the caller's included.
+        pool_state: PoolState,
+    }
+    let precision: Precision = req.query()?;
+    let pool_key = parse_pool_pair(req.param("pair")?)?;
+    let request: Req = req.body_json().await?;
+    if request.swaps.len() > MAX_SIMULATED_SWAPS {
+        return Err(to_badreq(anyhow::anyhow!(
+            "simulations are limited to {} swaps",
+            MAX_SIMULATED_SWAPS
+        )));
+    }
+    // resolves which way a swap goes, rejecting denominations the pool doesn't trade
+    let direction = |swap: &Swap| -> tide::Result<(Denom, Denom)> {
+        let from = parse_denom(&swap.from)?;
+        if from == pool_key.left {
+            Ok((from, pool_key.right))
+        } else if from == pool_key.right {
+            Ok((from, pool_key.left))
+        } else {
+            Err(to_badreq(anyhow::anyhow!(
+                "pool {} does not trade {}",
+                pool_key,
+                swap.from
+            )))
         }
     };
+    let directions = request
+        .swaps
+        .iter()
+        .map(direction)
+        .collect::<tide::Result<Vec<_>>>()?;
+    let (from, to) = direction(&request.swap)?;
+    let mut pool_state = req
+        .state()
+        .snapshot()
+        .await
+        .map_err(to_badgateway)?
+        .get_pool(pool_key)
+        .await
+        .map_err(to_badgateway)?
+        .ok_or_else(|| to_badreq(anyhow::anyhow!("pool not found")))?;
 
-    Body::from_json(&r)
+    let mut results = vec![];
+    for (swap, (swap_from, swap_to)) in request.swaps.iter().zip(directions) {
+        let out = swap_in_place(pool_key, &mut pool_state, swap_from, swap.value);
+        results.push(precision.amount(out, swap_to));
+    }
+    let (result, price_impact) = simulate_swap(pool_key, pool_state, from, request.swap.value);
+    swap_in_place(pool_key, &mut pool_state, from, request.swap.value);
+    Body::from_json(&Resp {
+        results,
+        result: precision.amount(result, to),
+        price_impact: precision.ratio(&price_impact),
+        pool_state,
+    })
+}
+
+/// Denominations a multi-hop swap may pass through.
+const ROUTE_INTERMEDIATES: [Denom; 3] = [Denom::Mel, Denom::Sym, Denom::Erg];
+
+async fn get_swap_route(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Req {
+        from: String,
+        to: String,
+        value: u128,
+    }
+    #[derive(Serialize)]
+    struct Resp {
+        route: Vec<String>,
+        poolkeys: Vec<String>,
+        result: Num,
+        price_impact: Num,
+    }
+
+    let precision: Precision = req.query()?;
+    let query: Req = req.body_json().await?;
+    let from = parse_denom(&query.from)?;
+    let to = parse_denom(&query.to)?;
+    if from == to {
+        return Err(to_badreq(anyhow::anyhow!(
+            "cannot swap between identical denoms"
+        )));
+    }
+    let snapshot = req.state().snapshot().await.map_err(to_badgateway)?;
+
+    // the direct route, then every route through one intermediate denomination
+    let mut routes = vec![vec![from, to]];
+    routes.extend(
+        ROUTE_INTERMEDIATES
+            .iter()
+            .filter(|mid| **mid != from && **mid != to)
+            .map(|mid| vec![from, *mid, to]),
+    );
+    // the best route, the amount it returns, and how much it moves prices
+    let mut best: Option<(Vec<Denom>, Vec<String>, u128, BigRational)> = None;
+    'routes: for route in routes {
+        let mut result = query.value;
+        let mut price_factor = BigRational::one();
+        let mut poolkeys = vec![];
+        for hop in route.windows(2) {
+            let pool_key = PoolKey::new(hop[0], hop[1]);
+            let pool_state = match snapshot.get_pool(pool_key).await.map_err(to_badgateway)? {
+                Some(pool_state) => pool_state,
+                None => continue 'routes,
+            };
+            let (out, price_impact) = simulate_swap(pool_key, pool_state, hop[0], result);
+            result = out;
+            price_factor *= BigRational::one() + price_impact;
+            poolkeys.push(hex::encode(pool_key.to_bytes()));
+        }
+        if best.as_ref().map(|b| result > b.2).unwrap_or(true) {
+            best = Some((route, poolkeys, result, price_factor - BigRational::one()));
+        }
+    }
+    let (route, poolkeys, result, price_impact) =
+        best.ok_or_else(|| to_badreq(anyhow::anyhow!("no route between these denoms")))?;
+    Body::from_json(&Resp {
+        route: route.iter().map(|d| d.to_string()).collect(),
+        poolkeys,
+        result: precision.amount(result, to),
+        price_impact: precision.ratio(&price_impact),
+    })
+}
+
+async fn get_denoms(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct DenomInfo {
+        hex: String,
+        symbol: String,
+        decimals: Option<u8>,
+    }
+    let mut denoms = vec![Denom::Mel, Denom::Sym, Denom::Erg];
+    // custom tokens we know about are the ones our wallets hold
+    let mut custom: Vec<Denom> = req
+        .state()
+        .all_unspent_coins()
+        .await
+        .into_values()
+        .flat_map(|coins| coins.into_values().map(|data| data.denom))
+        .filter(|denom| matches!(denom, Denom::Custom(_)))
+        .collect();
+    custom.sort_by_key(|denom| denom.to_bytes());
+    custom.dedup();
+    denoms.extend(custom);
+    let infos: Vec<DenomInfo> = denoms
+        .into_iter()
+        .map(|denom| DenomInfo {
+            hex: hex::encode(denom.to_bytes()),
+            symbol: denom.to_string(),
+            decimals: decimal::decimals_of(denom),
+        })
+        .collect();
+    Body::from_json(&infos)
+}
+
+/// Symbols accepted wherever a denomination is expected, besides hex-encoded denomination bytes.
+const KNOWN_DENOM_SYMBOLS: [&str; 3] = ["MEL", "SYM", "ERG"];
+
+/// Resolves a denomination given either as a ticker symbol, as `CUSTOM-<txhash>`, or as hex-encoded bytes.
+fn parse_denom(s: &str) -> tide::Result<Denom> {
+    let upper = s.to_ascii_uppercase();
+    if KNOWN_DENOM_SYMBOLS.contains(&upper.as_str()) || upper.starts_with("CUSTOM-") {
+        return upper.parse().map_err(to_badreq);
+    }
+    hex::decode(s)
+        .ok()
+        .and_then(|bytes| Denom::from_bytes(&bytes))
+        .ok_or_else(|| {
+            to_badreq(anyhow::anyhow!(
+                "unknown denomination {:?}; use one of {} or hex-encoded denomination bytes",
+                s,
+                KNOWN_DENOM_SYMBOLS.join(", ")
+            ))
+        })
+}
+
+/// Resolves a pool from a pair of denominations, separated by `:` or `/`.
+fn parse_pool_pair(pair: &str) -> tide::Result<PoolKey> {
+    let (left, right) = pair
+        .split_once([':', '/'])
+        .ok_or_else(|| to_badreq(anyhow::anyhow!("pool pair must look like MEL:SYM")))?;
+    let (left, right) = (parse_denom(left)?, parse_denom(right)?);
+    if left == right {
+        return Err(to_badreq(anyhow::anyhow!("bad pool key")));
+    }
+    Ok(PoolKey::new(left, right))
+}
+
+/// Simulates swapping `value` units of `from` through a pool, returning the amount received and the price impact.
+fn simulate_swap(
+    pool_key: PoolKey,
+    pool_state: PoolState,
+    from: Denom,
+    value: u128,
+) -> (u128, BigRational) {
+    let mut new_pool_state = pool_state;
+    let new = swap_in_place(pool_key, &mut new_pool_state, from, value);
+    if pool_key.left == from {
+        let old_price = ratio_of(pool_state.lefts, pool_state.rights);
+        let new_price = ratio_of(new_pool_state.lefts, new_pool_state.rights);
+        (new, price_change(old_price, new_price))
+    } else {
+        let old_price = ratio_of(pool_state.rights, pool_state.lefts);
+        let new_price = ratio_of(new_pool_state.rights, new_pool_state.lefts);
+        (new, price_change(old_price, new_price))
+    }
+}
+
+/// Swaps `value` units of `from` through a pool, updating its state, and returns the amount received.
+fn swap_in_place(pool_key: PoolKey, pool_state: &mut PoolState, from: Denom, value: u128) -> u128 {
+    if pool_key.left == from {
+        pool_state.swap_many(value, 0).1
+    } else {
+        pool_state.swap_many(0, value).0
+    }
+}
+
+/// The relative change from one price to another.
+fn price_change(old_price: BigRational, new_price: BigRational) -> BigRational {
+    if old_price.is_zero() {
+        return BigRational::zero();
+    }
+    new_price / old_price - BigRational::one()
 }
 
 async fn list_wallets(req: Request<Arc<AppState>>) -> tide::Result<Body> {
-    Body::from_json(&req.state().list_wallets().await)
+    #[derive(Deserialize)]
+    struct Query {
+        tag: Option<String>,
+    }
+    let query: Query = req.query()?;
+    Body::from_json(&req.state().list_wallets(query.tag.as_deref()).await)
 }
 
 async fn create_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Multisig {
+        public_keys: Vec<Ed25519PK>,
+        threshold: usize,
+    }
+    #[derive(Deserialize)]
+    struct External {
+        public_key: Ed25519PK,
+        callback_url: String,
+    }
     #[derive(Deserialize)]
     struct Query {
         password: Option<String>,
         secret: Option<String>,
+        mnemonic: Option<String>,
+        multisig: Option<Multisig>,
+        external: Option<External>,
+        #[serde(alias = "covenant_hex")]
+        covenant: Option<String>,
+        address: Option<String>,
     }
     let query: Query = req.body_json().await?;
     let wallet_name = req.param("name").map(|v| v.to_string())?;
-    let sk = if let Some(secret) = query.secret {
-        // We must reconstruct the secret key using the ed25519-dalek library
-        let secret =
-            base32::decode(Alphabet::Crockford, &secret).context("cannot decode secret key")?;
-        let secret = ed25519_dalek::SecretKey::from_bytes(&secret)?;
-        let public: ed25519_dalek::PublicKey = (&secret).into();
-        let mut vv = [0u8; 64];
-        vv[0..32].copy_from_slice(&secret.to_bytes());
-        vv[32..].copy_from_slice(&public.to_bytes());
-        Ed25519SK(vv)
-    } else {
-        tmelcrypt::ed25519_keygen().1
+    if !req.state().valid_wallet_name(&wallet_name) {
+        return Err(to_badreq(anyhow::anyhow!("invalid wallet name")));
+    }
+    if req.state().get_wallet(&wallet_name).await.is_some() {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow::anyhow!("wallet {} already exists", wallet_name),
+        ));
+    }
+    if req.state().name_used_elsewhere(&wallet_name).await {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow::anyhow!("wallet {} belongs to another network", wallet_name),
+        ));
+    }
+    if query.covenant.is_some() || query.address.is_some() {
+        if query.password.is_some()
+            || query.secret.is_some()
+            || query.mnemonic.is_some()
+            || query.multisig.is_some()
+            || query.external.is_some()
+        {
+            return Err(to_badreq(anyhow::anyhow!(
+                "watch-only wallets cannot have a password, secret, multisig, or external signer"
+            )));
+        }
+        let covenant = match query.covenant {
+            Some(covenant) => {
+                let covenant = Covenant(hex::decode(covenant).map_err(to_badreq)?);
+                covenant
+                    .to_ops()
+                    .context("covenant does not parse")
+                    .map_err(to_badreq)?;
+                Some(covenant)
+            }
+            None => None,
+        };
+        let address: Address = match (query.address, covenant.as_ref()) {
+            (Some(address), _) => address
+                .parse()
+                .map_err(|_| to_badreq(anyhow::anyhow!("cannot parse address")))?,
+            (None, Some(covenant)) => covenant.hash(),
+            (None, None) => unreachable!(),
+        };
+        req.state()
+            .create_watch_wallet(&wallet_name, address, covenant)
+            .await
+            .map_err(to_badreq)?;
+        return Ok(plain_text(""));
+    }
+    if let Some(external) = query.external {
+        if query.password.is_some()
+            || query.secret.is_some()
+            || query.mnemonic.is_some()
+            || query.multisig.is_some()
+        {
+            return Err(to_badreq(anyhow::anyhow!(
+                "externally signed wallets cannot have a password, secret, or multisig"
+            )));
+        }
+        let signer =
+            ExternalSigner::new(external.public_key, external.callback_url).map_err(to_badreq)?;
+        req.state()
+            .create_external_wallet(&wallet_name, signer)
+            .await
+            .context("cannot create wallet")?;
+        return Ok(plain_text(""));
+    }
+    if let Some(multisig) = query.multisig {
+        if query.password.is_some() || query.secret.is_some() || query.mnemonic.is_some() {
+            return Err(to_badreq(anyhow::anyhow!(
+                "multisig wallets cannot have a password or secret"
+            )));
+        }
+        let signer =
+            ThresholdSigner::new(multisig.public_keys, multisig.threshold).map_err(to_badreq)?;
+        req.state()
+            .create_threshold_wallet(&wallet_name, signer)
+            .await
+            .context("cannot create wallet")?;
+        return Ok(plain_text(""));
+    }
+    let sk = match (query.secret, query.mnemonic) {
+        (Some(_), Some(_)) => {
+            return Err(to_badreq(anyhow::anyhow!(
+                "cannot give both a secret and a mnemonic"
+            )))
+        }
+        (Some(secret), None) => {
+            let secret =
+                base32::decode(Alphabet::Crockford, &secret).context("cannot decode secret key")?;
+            sk_from_seed(&secret)?
+        }
+        (None, Some(mnemonic)) => {
+            let mnemonic = bip39::Mnemonic::parse_normalized(&mnemonic)
+                .context("invalid mnemonic")
+                .map_err(to_badreq)?;
+            sk_from_seed(&mnemonic.to_entropy()).map_err(to_badreq)?
+        }
+        (None, None) => tmelcrypt::ed25519_keygen().1,
     };
     req.state()
         .create_wallet(&wallet_name, sk, query.password)
         .await
         .context("cannot create wallet")?;
-    Ok("".into())
+    Ok(plain_text(""))
 }
 
-async fn dump_coins(req: Request<Arc<AppState>>) -> tide::Result<Body> {
-    let wallet_name = req.param("name").map(|v| v.to_string())?;
-    let wallet = req
-        .state()
-        .get_wallet(&wallet_name)
-        .await
-        .context("not found")
-        .map_err(to_notfound)?;
-    let coins = wallet.get_coin_mapping(true, false).await;
-    Body::from_json(&coins.into_iter().collect::<Vec<_>>())
+/// Reconstructs a full ed25519 secret key from its 32-byte seed.
+fn sk_from_seed(seed: &[u8]) -> anyhow::Result<Ed25519SK> {
+    // We must reconstruct the secret key using the ed25519-dalek library
+    let secret = ed25519_dalek::SecretKey::from_bytes(seed)?;
+    let public: ed25519_dalek::PublicKey = (&secret).into();
+    let mut vv = [0u8; 64];
+    vv[0..32].copy_from_slice(&secret.to_bytes());
+    vv[32..].copy_from_slice(&public.to_bytes());
+    Ok(Ed25519SK(vv))
 }
 
-async fn dump_transactions(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+async fn delete_wallet(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Query {
+        #[serde(default)]
+        force: bool,
+    }
+    let query: Query = req.query()?;
     let wallet_name = req.param("name").map(|v| v.to_string())?;
-    let wallet = req
+    if req.state().get_wallet(&wallet_name).await.is_none() {
+        return Err(to_notfound(anyhow::anyhow!("wallet not found")));
+    }
+    if req.state().unlocked_signers.contains_key(&wallet_name) && !query.force {
+        return Err(to_forbidden(anyhow::anyhow!(
+            "wallet is unlocked; lock it first or pass force=true"
+        )));
+    }
+    if !req
         .state()
-        .get_wallet(&wallet_name)
+        .delete_wallet(&wallet_name)
         .await
-        .context("not found")
-        .map_err(to_notfound)?;
-    let transactions = wallet.get_transaction_history().await;
-    Body::from_json(&transactions)
-}
-
-async fn lock_wallet(req: Request<Arc<AppState>>) -> tide::Result<Body> {
-    let wallet_name = req.param("name").map(|v| v.to_string())?;
-    req.state().lock(&wallet_name);
-    Ok("".into())
+        .context("cannot delete wallet")?
+    {
+        return Err(to_notfound(anyhow::anyhow!("wallet not found")));
+    }
+    Ok(plain_text(""))
 }
 
-async fn unlock_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+async fn rename_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
     #[derive(Deserialize)]
     struct Req {
-        password: Option<String>,
+        new_name: String,
     }
-    let wallet_name = req.param("name").map(|v| v.to_string())?;
     let request: Req = req.body_json().await?;
-    // attempt to unlock
-    req.state()
-        .unlock(&wallet_name, request.password)
-        .context("incorrect password")
-        .map_err(to_forbidden)?;
-    Ok("".into())
-}
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    if !req.state().valid_wallet_name(&request.new_name) {
+        return Err(to_badreq(anyhow::anyhow!("invalid wallet name")));
+    }
+    if req.state().get_wallet(&wallet_name).await.is_none() {
+        return Err(to_notfound(anyhow::anyhow!("wallet not found")));
+    }
+    if req.state().get_wallet(&request.new_name).await.is_some() {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow::anyhow!("wallet {} already exists", request.new_name),
+        ));
+    }
+    if req.state().name_used_elsewhere(&request.new_name).await {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow::anyhow!("wallet {} belongs to another network", request.new_name),
+        ));
+    }
+    if !req
+        .state()
+        .rename_wallet(&wallet_name, &request.new_name)
+        .await
+        .context("cannot rename wallet")?
+    {
+        return Err(to_notfound(anyhow::anyhow!("wallet not found")));
+    }
+    Ok(plain_text(""))
+}
+
+/// Dumps a wallet. Its secret is only included for a caller who passes the same password check as exporting the secret key, since even an encrypted secret can be brute-forced offline, away from the unlock throttling.
+async fn dump_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        #[serde(default)]
+        include_secret: bool,
+        password: Option<String>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = req.body_json().await?;
+    if req.state().get_wallet(&wallet_name).await.is_none() {
+        return Err(to_notfound(anyhow::anyhow!("wallet not found")));
+    }
+    if request.include_secret {
+        if let Some(wait) = req.state().unlock_backoff(&wallet_name) {
+            return Ok(too_many_attempts(wait));
+        }
+        if !req
+            .state()
+            .secret_exportable(&wallet_name, request.password)
+        {
+            return Err(to_forbidden(anyhow::anyhow!("incorrect password")));
+        }
+    }
+    let dump = req
+        .state()
+        .dump_wallet(&wallet_name, request.include_secret)
+        .await
+        .context("wallet not found")
+        .map_err(to_notfound)?;
+    Ok(Body::from_json(&dump)?.into())
+}
+
+async fn restore_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Query {
+        #[serde(default)]
+        overwrite: bool,
+    }
+    let query: Query = req.query()?;
+    let dump: WalletDump = req.body_json().await?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    if !req.state().valid_wallet_name(&wallet_name) {
+        return Err(to_badreq(anyhow::anyhow!("invalid wallet name")));
+    }
+    if req.state().get_wallet(&wallet_name).await.is_some() && !query.overwrite {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow::anyhow!("wallet already exists; pass overwrite=true to replace it"),
+        ));
+    }
+    if req.state().name_used_elsewhere(&wallet_name).await {
+        return Err(tide::Error::new(
+            StatusCode::Conflict,
+            anyhow::anyhow!("wallet {} belongs to another network", wallet_name),
+        ));
+    }
+    req.state()
+        .restore_wallet(&wallet_name, dump)
+        .await
+        .map_err(to_badreq)?;
+    Ok(plain_text(""))
+}
+
+async fn get_covenant(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        #[serde(with = "stdcode::asstr")]
+        address: Address,
+        covenant: String,
+        disassembly: Option<Vec<String>>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let covenant = wallet
+        .covenant()
+        .context("covenant of this watch-only wallet is unknown")
+        .map_err(to_notfound)?;
+    let disassembly = covenant
+        .to_ops()
+        .ok()
+        .map(|ops| ops.iter().map(|op| op.to_string()).collect());
+    Body::from_json(&Resp {
+        address: wallet.address(),
+        covenant: hex::encode(&covenant.0),
+        disassembly,
+    })
+}
+
+async fn get_address(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        #[serde(with = "stdcode::asstr")]
+        address: Address,
+        hash: String,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let address = wallet.address();
+    Body::from_json(&Resp {
+        address,
+        // an address is the hash of its covenant, so this is also the covenant hash
+        hash: hex::encode(address.0),
+    })
+}
+
+async fn get_balance(req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Serialize)]
+    struct Resp {
+        total_micromel: Num,
+        detailed_balance: BTreeMap<String, Num>,
+    }
+    let precision: Precision = req.query()?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let coins = wallet.get_coin_mapping(false, false).await;
+    let etag = format!("\"{}\"", coins.stdcode().hash());
+    if req
+        .header("If-None-Match")
+        .map(|v| v.as_str() == etag)
+        .unwrap_or(false)
+    {
+        let mut res = tide::Response::new(StatusCode::NotModified);
+        res.insert_header("ETag", etag);
+        return Ok(res);
+    }
+    let mut balance: BTreeMap<Denom, CoinValue> = BTreeMap::new();
+    for data in coins.values() {
+        *balance.entry(data.denom).or_default() += data.value;
+    }
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.insert_header("ETag", etag);
+    res.set_body(Body::from_json(&Resp {
+        total_micromel: precision.amount(
+            balance.get(&Denom::Mel).copied().unwrap_or_default().0,
+            Denom::Mel,
+        ),
+        detailed_balance: balance
+            .iter()
+            .map(|(k, v)| (hex::encode(k.to_bytes()), precision.amount(v.0, *k)))
+            .collect(),
+    })?);
+    Ok(res)
+}
+
+async fn dump_coins(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Query {
+        #[serde(default)]
+        min_confirmations: u64,
+        min_value: Option<u128>,
+        max_value: Option<u128>,
+        denom: Option<String>,
+    }
+    #[derive(Serialize)]
+    struct CoinInfo {
+        #[serde(flatten)]
+        coin_data: CoinData,
+        /// Counted from the last sync, so unknown until the first one.
+        confirmations: Option<u64>,
+        frozen: bool,
+        spendable_at_height: BlockHeight,
+    }
+    let query: Query = req.query()?;
+    let denom = query.denom.as_deref().map(parse_denom).transpose()?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    // listing coins is local, so it counts confirmations from the last sync rather than asking the node
+    let tip = req
+        .state()
+        .database
+        .synced_height(req.state().network)
+        .await;
+    let mut coins = wallet.get_coin_mapping(true, false).await;
+    coins.retain(|_, data| {
+        denom.map(|d| data.denom == d).unwrap_or(true)
+            && query.min_value.map(|v| data.value.0 >= v).unwrap_or(true)
+            && query.max_value.map(|v| data.value.0 <= v).unwrap_or(true)
+    });
+    let heights = wallet.get_coin_heights().await;
+    let frozen = wallet.get_frozen_coins().await;
+    let maturities = wallet.get_coin_maturities().await;
+    let coins: Vec<(CoinID, CoinInfo)> = coins
+        .into_iter()
+        .filter_map(|(coin_id, coin_data)| {
+            let height = *heights.get(&coin_id)?;
+            let confirmations = tip.map(|tip| tip.0.saturating_sub(height.0));
+            // a coin can be spent from the block after its own, unless a stake holds it longer
+            let spendable_at_height = maturities
+                .get(&coin_id)
+                .copied()
+                .unwrap_or_default()
+                .max(height + BlockHeight(1));
+            Some((
+                coin_id,
+                CoinInfo {
+                    coin_data,
+                    confirmations,
+                    frozen: frozen.contains(&coin_id),
+                    spendable_at_height,
+                },
+            ))
+        })
+        .filter(|(_, info)| info.confirmations.unwrap_or_default() >= query.min_confirmations)
+        .collect();
+    negotiated_body(&req, &coins)
+}
+
+/// Counts the wallet's unspent coins per denomination, flagging the denominations with more than the fragmentation threshold, so monitoring can tell when a wallet is due for consolidation.
+async fn get_utxo_count(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        counts: BTreeMap<String, usize>,
+        fragmented: Vec<String>,
+        threshold: usize,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+    for data in wallet.get_coin_mapping(true, false).await.values() {
+        *counts
+            .entry(hex::encode(data.denom.to_bytes()))
+            .or_default() += 1;
+    }
+    let threshold = req.state().fragmentation_threshold;
+    let fragmented = counts
+        .iter()
+        .filter(|(_, count)| **count > threshold)
+        .map(|(denom, _)| denom.clone())
+        .collect();
+    Body::from_json(&Resp {
+        counts,
+        fragmented,
+        threshold,
+    })
+}
+
+async fn get_subaddress(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let index: u64 = req
+        .param("index")?
+        .parse()
+        .map_err(|_| to_badreq(anyhow::anyhow!("cannot parse index")))?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    // tracking a new subaddress writes to the database, which a read-only daemon must leave to its owner
+    let address = if req.state().read_only {
+        wallet
+            .tracked_subaddress(index)
+            .await
+            .with_context(|| {
+                format!(
+                    "subaddress {} is not tracked yet, and the daemon is read-only",
+                    index
+                )
+            })
+            .map_err(to_forbidden)?
+    } else {
+        wallet.subaddress(index).await.map_err(to_badreq)?
+    };
+    Body::from_json(&address.to_string())
+}
+
+async fn dump_coins_since(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let height: u64 = req
+        .param("height")?
+        .parse()
+        .map_err(|_| to_badreq(anyhow::anyhow!("cannot parse height")))?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let mut coins = vec![];
+    for (coin_id, coin_data_height) in wallet.get_coins_since(BlockHeight(height)).await {
+        coins.push(CoinEvent {
+            coin_id: coin_id.to_string(),
+            coin_data_height,
+            is_change: wallet.is_change(coin_id).await,
+        });
+    }
+    Body::from_json(&coins)
+}
+
+/// Most coins a single import request may look up.
+const MAX_IMPORTED_COINS: usize = 1000;
+
+async fn import_coins(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize, Default)]
+    struct Resp {
+        imported: usize,
+        not_found: Vec<CoinID>,
+        not_ours: Vec<CoinID>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let coins: Vec<CoinID> = req.body_json().await?;
+    if coins.len() > MAX_IMPORTED_COINS {
+        return Err(to_badreq(anyhow::anyhow!(
+            "at most {} coins can be imported at once",
+            MAX_IMPORTED_COINS
+        )));
+    }
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let snapshot = req.state().snapshot().await.map_err(to_badgateway)?;
+    let lookups: Vec<_> = coins
+        .into_iter()
+        .map(|coin| {
+            let snapshot = snapshot.clone();
+            smolscale::spawn(async move { snapshot.get_coin(coin).await.map(|cdh| (coin, cdh)) })
+        })
+        .collect();
+    let mut resp = Resp::default();
+    let mut found = vec![];
+    for lookup in lookups {
+        match lookup.await.map_err(to_badgateway)? {
+            (coin, None) => resp.not_found.push(coin),
+            (coin, Some(cdh)) if cdh.coin_data.covhash != wallet.address() => {
+                resp.not_ours.push(coin)
+            }
+            (coin, Some(cdh)) => found.push((coin, cdh)),
+        }
+    }
+    let locks = find_stake_locks(&snapshot, found.iter().map(|(coin, _)| *coin))
+        .await
+        .map_err(to_badgateway)?;
+    resp.imported = wallet.insert_coins(&found, &locks).await?;
+    log::info!("imported {} coins into {}", resp.imported, wallet_name);
+    Body::from_json(&resp)
+}
+
+async fn dump_all_coins(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Query {
+        denom: Option<String>,
+    }
+    let query: Query = req.query()?;
+    let denom = query.denom.as_deref().map(parse_denom).transpose()?;
+    let coins: BTreeMap<String, Vec<(CoinID, CoinData)>> = req
+        .state()
+        .all_unspent_coins()
+        .await
+        .into_iter()
+        .map(|(name, coins)| {
+            let coins = coins
+                .into_iter()
+                .filter(|(_, cd)| denom.map(|d| cd.denom == d).unwrap_or(true))
+                .collect();
+            (name, coins)
+        })
+        .collect();
+    Body::from_json(&coins)
+}
+
+/// Unspent coins locked by the given address, in whichever wallets track them, keyed by wallet name.
+async fn dump_coins_by_address(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let address: Address = req
+        .param("address")?
+        .parse()
+        .map_err(|_| to_badreq(anyhow::anyhow!("cannot parse address")))?;
+    let coins: BTreeMap<String, Vec<(CoinID, CoinData)>> = req
+        .state()
+        .all_unspent_coins()
+        .await
+        .into_iter()
+        .map(|(name, coins)| {
+            let coins: Vec<_> = coins
+                .into_iter()
+                .filter(|(_, cd)| cd.covhash == address)
+                .collect();
+            (name, coins)
+        })
+        .filter(|(_, coins)| !coins.is_empty())
+        .collect();
+    Body::from_json(&coins)
+}
+
+/// Whether an address has any on-chain history, for clients avoiding address reuse. The chain only counts the coins an address holds now, so an address that was emptied out counts as used only if one of our wallets saw its coins.
+async fn get_address_used(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        used: bool,
+        coins: u64,
+    }
+    let address: Address = req
+        .param("address")?
+        .parse()
+        .map_err(|_| to_badreq(anyhow::anyhow!("cannot parse address")))?;
+    let coins = req
+        .state()
+        .snapshot()
+        .await
+        .map_err(to_badgateway)?
+        .get_coin_count(address)
+        .await
+        .map_err(to_badgateway)?
+        .unwrap_or_default();
+    let used = coins > 0 || req.state().address_seen(address).await;
+    Body::from_json(&Resp { used, coins })
+}
+
+async fn dump_pending(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Pending {
+        raw: Transaction,
+        sent_height: Option<BlockHeight>,
+        age: Option<u64>,
+        replaces: Option<TxHash>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let height = req
+        .state()
+        .snapshot()
+        .await
+        .map_err(to_badgateway)?
+        .current_header()
+        .height;
+    let mut pending: BTreeMap<TxHash, Pending> = BTreeMap::new();
+    for (raw, sent_height) in wallet.get_pending_transactions().await {
+        let txhash = raw.hash_nosigs();
+        pending.insert(
+            txhash,
+            Pending {
+                raw,
+                sent_height,
+                age: sent_height.map(|h| height.0.saturating_sub(h.0)),
+                replaces: wallet.get_replaced(txhash).await,
+            },
+        );
+    }
+    Body::from_json(&pending)
+}
+
+async fn rebroadcast_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let txhash: HashVal = req.param("txhash")?.parse().map_err(to_badreq)?;
+    if !wallet.is_pending(txhash.into()).await {
+        return Err(to_notfound(anyhow::anyhow!("transaction is not pending")));
+    }
+    let tx = wallet
+        .get_cached_transaction(txhash.into())
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let snapshot = req.state().snapshot().await.map_err(to_badgateway)?;
+    req.state()
+        .node_retry
+        .run("sending a transaction", || {
+            snapshot.get_raw().send_tx(tx.clone())
+        })
+        .await
+        .map_err(to_badgateway)?;
+    log::info!("manually rebroadcast transaction with hash {}", txhash);
+    Ok(plain_text(""))
+}
+
+async fn unlock_coin(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let coinid: CoinID = req.param("coinid")?.parse().map_err(to_badreq)?;
+    if !wallet.release_coin(coinid).await? {
+        return Err(to_notfound(anyhow::anyhow!("coin is not reserved")));
+    }
+    Ok(plain_text(""))
+}
+
+async fn freeze_coin(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let coinid: CoinID = req.param("coinid")?.parse().map_err(to_badreq)?;
+    if wallet.get_one_coin(coinid).await.map(|coin| coin.covhash) != Some(wallet.address()) {
+        return Err(to_notfound(anyhow::anyhow!("coin is not in this wallet")));
+    }
+    wallet.freeze_coin(coinid).await?;
+    Ok(plain_text(""))
+}
+
+async fn unfreeze_coin(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let coinid: CoinID = req.param("coinid")?.parse().map_err(to_badreq)?;
+    if !wallet.unfreeze_coin(coinid).await? {
+        return Err(to_notfound(anyhow::anyhow!("coin is not frozen")));
+    }
+    Ok(plain_text(""))
+}
+
+async fn subscribe_coins(
+    req: Request<Arc<AppState>>,
+    conn: WebSocketConnection,
+) -> tide::Result<()> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    req.state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let events = req.state().coin_subscribers.subscribe(&wallet_name);
+    while let Ok(event) = events.recv().await {
+        conn.send_json(&event).await?;
+    }
+    Ok(())
+}
+
+async fn dump_transactions(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Query {
+        #[serde(default = "default_limit")]
+        limit: usize,
+        #[serde(default)]
+        offset: usize,
+        format: Option<String>,
+    }
+    fn default_limit() -> usize {
+        100
+    }
+    #[derive(Serialize)]
+    struct Resp {
+        transactions: Vec<(TxHash, Option<BlockHeight>)>,
+        total_count: usize,
+        labels: BTreeMap<TxHash, String>,
+    }
+    let query: Query = req.query()?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let wants_csv = query.format.as_deref() == Some("csv")
+        || req
+            .header("Accept")
+            .map(|accept| accept.as_str().contains("text/csv"))
+            .unwrap_or_default();
+    if wants_csv {
+        return Ok(transactions_csv(wallet));
+    }
+    let transactions = wallet.get_transaction_history().await;
+    let total_count = transactions.len();
+    let transactions = transactions
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit)
+        .collect::<Vec<_>>();
+    let mut labels = wallet.get_labels().await;
+    labels.retain(|txhash, _| transactions.iter().any(|(t, _)| t == txhash));
+    negotiated_body(
+        &req,
+        &Resp {
+            transactions,
+            total_count,
+            labels,
+        },
+    )
+}
+
+/// The transaction histories of every wallet merged into one, ordered like a single wallet's history.
+async fn dump_all_transactions(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Query {
+        #[serde(default = "default_limit")]
+        limit: usize,
+        #[serde(default)]
+        offset: usize,
+    }
+    fn default_limit() -> usize {
+        100
+    }
+    #[derive(Serialize)]
+    struct Entry {
+        wallet: String,
+        txhash: TxHash,
+        height: Option<BlockHeight>,
+        label: Option<String>,
+    }
+    #[derive(Serialize)]
+    struct Resp {
+        transactions: Vec<Entry>,
+        total_count: usize,
+    }
+    let query: Query = req.query()?;
+    let state = req.state();
+    let mut transactions = vec![];
+    for name in state.database.list_wallets().await {
+        if let Some(wallet) = state.database.get_wallet(&name).await {
+            let mut labels = wallet.get_labels().await;
+            for (txhash, height) in wallet.get_transaction_history().await {
+                transactions.push(Entry {
+                    wallet: name.clone(),
+                    txhash,
+                    height,
+                    label: labels.remove(&txhash),
+                });
+            }
+        }
+    }
+    // pending first, then newest first; the sort is stable, so each wallet's own order survives ties
+    transactions
+        .sort_by_key(|entry| std::cmp::Reverse(entry.height.map(|h| h.0).unwrap_or(u64::MAX)));
+    let total_count = transactions.len();
+    let transactions = transactions
+        .into_iter()
+        .skip(query.offset)
+        .take(query.limit)
+        .collect();
+    Body::from_json(&Resp {
+        transactions,
+        total_count,
+    })
+}
+
+/// Streams the whole transaction history of a wallet as CSV, one row at a time.
+fn transactions_csv(wallet: Wallet) -> Body {
+    let (send_row, recv_row) = smol::channel::bounded::<Vec<u8>>(16);
+    smolscale::spawn(async move {
+        let history = wallet.get_transaction_history().await;
+        let labels = wallet.get_labels().await;
+        let _ = send_row
+            .send(b"txhash,kind,height,mel_delta,label\n".to_vec())
+            .await;
+        for (txhash, height) in history {
+            let (kind, mel_delta) = match wallet.get_cached_transaction(txhash).await {
+                Some(raw) => {
+                    let (_, balance) = tx_balance(&wallet, &raw).await;
+                    let mel_delta = balance
+                        .get(&hex::encode(Denom::Mel.to_bytes()))
+                        .copied()
+                        .unwrap_or_default();
+                    (raw.kind.to_string(), mel_delta.to_string())
+                }
+                None => (String::new(), String::new()),
+            };
+            let row = format!(
+                "{},{},{},{},{}\n",
+                txhash,
+                kind,
+                height
+                    .map(|h| h.to_string())
+                    .unwrap_or_else(|| "pending".into()),
+                mel_delta,
+                csv_field(labels.get(&txhash).map(|l| l.as_str()).unwrap_or_default())
+            );
+            // the client went away
+            if send_row.send(row.into_bytes()).await.is_err() {
+                return;
+            }
+        }
+    })
+    .detach();
+    let reader = recv_row.map(Ok::<_, std::io::Error>).into_async_read();
+    let mut body = Body::from_reader(reader, None);
+    body.set_mime("text/csv");
+    body
+}
+
+/// Quotes a CSV field if it contains anything that would otherwise break the row.
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+async fn label_tx(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Req {
+        label: String,
+    }
+    let request: Req = req.body_json().await?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let txhash: HashVal = req.param("txhash")?.parse().map_err(to_badreq)?;
+    wallet.set_label(txhash.into(), &request.label).await?;
+    Ok(plain_text(""))
+}
+
+/// Turns on or off the replacement of the wallet's transactions that are stuck on too low a fee. Only unlocked wallets can have theirs replaced.
+async fn set_auto_bump(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Req {
+        enabled: bool,
+    }
+    let request: Req = req.body_json().await?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    wallet.set_auto_bump(request.enabled).await?;
+    Ok(plain_text(""))
+}
+
+async fn tag_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Req {
+        #[serde(default)]
+        add: BTreeSet<String>,
+        #[serde(default)]
+        remove: BTreeSet<String>,
+    }
+    let request: Req = req.body_json().await?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    if request.add.iter().any(|tag| tag.is_empty()) {
+        return Err(to_badreq(anyhow::anyhow!("tags cannot be empty")));
+    }
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let tags = wallet.update_tags(&request.add, &request.remove).await?;
+    Body::from_json(&tags)
+}
+
+async fn lock_wallet(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    req.state().lock(&wallet_name);
+    Ok(plain_text(""))
+}
+
+async fn unlock_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        password: Option<String>,
+        #[serde(default)]
+        ttl_seconds: Option<u64>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = req.body_json().await?;
+    if request.ttl_seconds == Some(0) {
+        return Err(to_badreq(anyhow::anyhow!("ttl_seconds must be positive")));
+    }
+    if let Some(wait) = req.state().unlock_backoff(&wallet_name) {
+        return Ok(too_many_attempts(wait));
+    }
+    // attempt to unlock
+    req.state()
+        .unlock(
+            &wallet_name,
+            request.password,
+            request.ttl_seconds.map(Duration::from_secs),
+        )
+        .context("incorrect password")
+        .map_err(to_forbidden)?;
+    Ok(Body::from_json(&UnlockExpiry {
+        expires_at: unix_secs(req.state().unlock_expiry(&wallet_name)),
+    })?
+    .into())
+}
+
+#[derive(Serialize)]
+struct UnlockExpiry {
+    expires_at: Option<u64>,
+}
+
+fn unix_secs(time: Option<SystemTime>) -> Option<u64> {
+    time.and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|since| since.as_secs())
+}
+
+async fn lock_status(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        unlocked: bool,
+        expires_at: Option<u64>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    if req.state().get_wallet(&wallet_name).await.is_none() {
+        return Err(to_notfound(anyhow::anyhow!("wallet not found")));
+    }
+    Body::from_json(&Resp {
+        unlocked: req.state().get_signer(&wallet_name).is_some(),
+        expires_at: unix_secs(req.state().unlock_expiry(&wallet_name)),
+    })
+}
+
+async fn change_password(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        old_password: Option<String>,
+        new_password: Option<String>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = req.body_json().await?;
+    match req.state().secrets.load(&wallet_name) {
+        Some(PersistentSecret::Plaintext(_)) | Some(PersistentSecret::PasswordEncrypted(_)) => {}
+        Some(PersistentSecret::Threshold(_)) | Some(PersistentSecret::External(_)) | None => {
+            return Err(to_badreq(anyhow::anyhow!(
+                "wallet has no secret key protected by a password"
+            )))
+        }
+    }
+    if let Some(wait) = req.state().unlock_backoff(&wallet_name) {
+        return Ok(too_many_attempts(wait));
+    }
+    req.state()
+        .change_password(&wallet_name, request.old_password, request.new_password)
+        .context("incorrect password")
+        .map_err(to_forbidden)?;
+    Ok(plain_text("").into())
+}
+
+async fn export_sk_from_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        password: Option<String>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = req.body_json().await?;
+    if let Some(wait) = req.state().unlock_backoff(&wallet_name) {
+        return Ok(too_many_attempts(wait));
+    }
+    // attempt to unlock
+    let secret = req
+        .state()
+        .get_secret_key(&wallet_name, request.password)
+        .context("incorrect password")
+        .map_err(to_forbidden)?;
+    Ok(plain_text(base32::encode(Alphabet::Crockford, &secret.0[..32])).into())
+}
+
+async fn export_mnemonic_from_wallet(
+    mut req: Request<Arc<AppState>>,
+) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        password: Option<String>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = req.body_json().await?;
+    if let Some(wait) = req.state().unlock_backoff(&wallet_name) {
+        return Ok(too_many_attempts(wait));
+    }
+    // attempt to unlock
+    let secret = req
+        .state()
+        .get_secret_key(&wallet_name, request.password)
+        .context("incorrect password")
+        .map_err(to_forbidden)?;
+    let mnemonic = bip39::Mnemonic::from_entropy(&secret.0[..32])?;
+    Ok(plain_text(mnemonic.to_string()).into())
+}
+
+/// Refuses a password attempt on a wallet that has seen too many failed ones.
+fn too_many_attempts(wait: Duration) -> tide::Response {
+    let mut res = tide::Response::new(StatusCode::TooManyRequests);
+    res.insert_header("Retry-After", (wait.as_secs() + 1).to_string());
+    res.set_body(plain_text(
+        "too many failed password attempts; try again later",
+    ));
+    res
+}
+
+/// Arguments shared by every endpoint that prepares a transaction. Outputs take their `additional_data` as a hex string.
+#[derive(Deserialize)]
+struct PrepareTxArgs {
+    #[serde(default)]
+    inputs: Vec<CoinID>,
+    outputs: Vec<CoinData>,
+    signing_key: Option<String>,
+    kind: Option<TxKind>,
+    data: Option<String>,
+    #[serde(default, with = "stdcode::hexvec")]
+    covenants: Vec<Vec<u8>>,
+    #[serde(default)]
+    nobalance: Vec<Denom>,
+    fee_multiplier: Option<u128>,
+    #[serde(default)]
+    single_change: bool,
+    change_address: Option<String>,
+    /// Overrides the configured coin selection strategy.
+    coin_selection: Option<CoinSelection>,
+}
+
+impl PrepareTxArgs {
+    /// Checks every field for problems that would otherwise surface as an opaque preparation failure, naming the field at fault.
+    fn validate(&self) -> tide::Result<()> {
+        self.decoded_signing_key()?;
+        self.decoded_data()?;
+        self.decoded_change_address()?;
+        Ok(())
+    }
+
+    /// Parses the base32 `signing_key` field, if present.
+    fn decoded_signing_key(&self) -> tide::Result<Option<Ed25519SK>> {
+        match self.signing_key.as_ref() {
+            Some(v) => {
+                Ok(Some(v.parse().map_err(|err| {
+                    to_badreq(anyhow::anyhow!("signing_key: {}", err))
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Picks the fee multiplier to use, rejecting overrides that underbid the network.
+    fn fee_multiplier(&self, network_multiplier: u128) -> tide::Result<u128> {
+        match self.fee_multiplier {
+            Some(m) if m < network_multiplier => Err(to_badreq(anyhow::anyhow!(
+                "fee multiplier {} is below the network's current {}",
+                m,
+                network_multiplier
+            ))),
+            Some(m) => Ok(m),
+            None => Ok(network_multiplier),
+        }
+    }
+
+    /// Decodes the hex-encoded `data` field, if present.
+    fn decoded_data(&self) -> tide::Result<Option<Vec<u8>>> {
+        match self.data.as_ref() {
+            Some(v) => {
+                Ok(Some(hex::decode(v).map_err(|err| {
+                    to_badreq(anyhow::anyhow!("data: {}", err))
+                })?))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Parses the `change_address` field, if present.
+    fn decoded_change_address(&self) -> tide::Result<Option<Address>> {
+        match self.change_address.as_ref() {
+            Some(v) => Ok(Some(v.parse().map_err(|_| {
+                to_badreq(anyhow::anyhow!("change_address: cannot parse address"))
+            })?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies the kind, data, and extra covenants to an unsigned transaction.
+    fn apply(&self, mut tx: Transaction, data: &Option<Vec<u8>>) -> Transaction {
+        if let Some(kind) = self.kind {
+            tx.kind = kind
+        }
+        if let Some(data) = data.clone() {
+            tx.data = data
+        }
+        tx.covenants.extend_from_slice(&self.covenants);
+        tx
+    }
+}
+
+/// How a prepared transaction is encoded in the response.
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum TxEncoding {
+    /// The transaction as a JSON object.
+    #[default]
+    Json,
+    /// The hex-encoded `stdcode` bytes of the transaction, exactly as they will be broadcast.
+    Stdcode,
+}
+
+#[derive(Deserialize)]
+struct EncodingQuery {
+    #[serde(default)]
+    encoding: TxEncoding,
+}
+
+async fn prepare_tx(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let query: EncodingQuery = req.query()?;
+    let request: PrepareTxArgs = body_json_fields(&mut req).await?;
+    let start = Instant::now();
+    let res = prepare_signed(req.state(), &wallet_name, request, query.encoding).await;
+    req.state().metrics.record_prepare(start.elapsed());
+    res
+}
+
+/// Most transactions a single batch may prepare.
+const MAX_BATCH_SIZE: usize = 1000;
+
+async fn prepare_batch(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Req {
+        transactions: Vec<PrepareTxArgs>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = body_json_fields(&mut req).await?;
+    if request.transactions.len() > MAX_BATCH_SIZE {
+        return Err(to_badreq(anyhow::anyhow!(
+            "batches are limited to {} transactions",
+            MAX_BATCH_SIZE
+        )));
+    }
+    let state = req.state();
+    // every prepared transaction reserves its inputs, so the ones after it pick other coins
+    let mut prepared: Vec<Transaction> = vec![];
+    for (idx, args) in request.transactions.into_iter().enumerate() {
+        match prepare_signed_tx(state, &wallet_name, args).await {
+            Ok((tx, _)) => prepared.push(tx),
+            Err(err) => {
+                // all or nothing, so we hand back the coins the earlier transactions took
+                if let Some(wallet) = state.get_wallet(&wallet_name).await {
+                    for coin in prepared.iter().flat_map(|tx| tx.inputs.iter()) {
+                        wallet.release_coin(*coin).await?;
+                    }
+                }
+                return Err(tide::Error::new(
+                    err.status(),
+                    anyhow::anyhow!("cannot prepare transaction {} of the batch: {:?}", idx, err),
+                ));
+            }
+        }
+    }
+    Body::from_json(&prepared)
+}
+
+async fn list_templates(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    Body::from_json(&wallet.get_templates().await)
+}
+
+/// Registers a transaction template, checking it the way a transaction prepared from it would be checked.
+async fn put_template(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Deserialize)]
+    struct Req {
+        name: String,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    // the name sits beside the template's fields, which a flattened struct would parse without naming the field at fault
+    let bytes = req.body_bytes().await?;
+    let request: Req = json_fields(&bytes)?;
+    let template: TxTemplate = json_fields(&bytes)?;
+    if !valid_template_name(&request.name) {
+        return Err(to_badreq(anyhow::anyhow!(
+            "name: template names are letters, digits, dashes and underscores"
+        )));
+    }
+    let placeholders = vec![CoinValue(0); template.variable_count()];
+    template_args(&template, &placeholders)?.validate()?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let templates = wallet.get_templates().await;
+    if !templates.contains_key(&request.name) && templates.len() >= MAX_TEMPLATES {
+        return Err(to_badreq(anyhow::anyhow!(
+            "wallets are limited to {} templates",
+            MAX_TEMPLATES
+        )));
+    }
+    wallet.put_template(&request.name, &template).await?;
+    Body::from_json(&template)
+}
+
+async fn delete_template(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let template_name = req.param("tpl").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    if !wallet.delete_template(&template_name).await? {
+        return Err(to_notfound(anyhow::anyhow!("no such template")));
+    }
+    Ok(plain_text(""))
+}
+
+/// Prepares a transaction from a template, given the values the template leaves out and whatever else varies between uses.
+async fn prepare_from_template(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        #[serde(default)]
+        values: Vec<CoinValue>,
+        #[serde(default)]
+        inputs: Vec<CoinID>,
+        signing_key: Option<String>,
+        fee_multiplier: Option<u128>,
+        change_address: Option<String>,
+        coin_selection: Option<CoinSelection>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let template_name = req.param("tpl").map(|v| v.to_string())?;
+    let query: EncodingQuery = req.query()?;
+    let request: Req = body_json_fields(&mut req).await?;
+    let template = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?
+        .get_template(&template_name)
+        .await
+        .context("no such template")
+        .map_err(to_notfound)?;
+    let args = PrepareTxArgs {
+        inputs: request.inputs,
+        signing_key: request.signing_key,
+        fee_multiplier: request.fee_multiplier,
+        change_address: request.change_address,
+        coin_selection: request.coin_selection,
+        ..template_args(&template, &request.values)?
+    };
+    prepare_signed(req.state(), &wallet_name, args, query.encoding).await
+}
+
+/// Fills in a template into arguments for preparing a transaction, leaving everything the template doesn't fix at its default.
+fn template_args(template: &TxTemplate, values: &[CoinValue]) -> tide::Result<PrepareTxArgs> {
+    Ok(PrepareTxArgs {
+        inputs: vec![],
+        outputs: template.outputs(values).map_err(to_badreq)?,
+        signing_key: None,
+        kind: template.kind,
+        data: template.data.clone(),
+        covenants: template.covenants.clone(),
+        nobalance: template.nobalance.clone(),
+        fee_multiplier: None,
+        single_change: template.single_change,
+        change_address: None,
+        coin_selection: None,
+    })
+}
+
+/// Prepares and signs a transaction for the given wallet, answering with the signed transaction.
+async fn prepare_signed(
+    state: &AppState,
+    wallet_name: &str,
+    request: PrepareTxArgs,
+    encoding: TxEncoding,
+) -> tide::Result<tide::Response> {
+    let (prepared_tx, fee_multiplier) = prepare_signed_tx(state, wallet_name, request).await?;
+    prepared_response(&prepared_tx, fee_multiplier, encoding)
+}
+
+/// Responds with a prepared transaction, in the requested encoding.
+fn prepared_response(
+    prepared_tx: &Transaction,
+    fee_multiplier: u128,
+    encoding: TxEncoding,
+) -> tide::Result<tide::Response> {
+    let mut res = tide::Response::new(StatusCode::Ok);
+    res.insert_header("X-Fee-Multiplier", fee_multiplier.to_string());
+    match encoding {
+        TxEncoding::Json => res.set_body(Body::from_json(prepared_tx)?),
+        TxEncoding::Stdcode => res.set_body(plain_text(hex::encode(prepared_tx.stdcode()))),
+    }
+    Ok(res)
+}
+
+/// Prepares and signs a transaction for the given wallet, returning it along with the fee multiplier used.
+async fn prepare_signed_tx(
+    state: &AppState,
+    wallet_name: &str,
+    request: PrepareTxArgs,
+) -> tide::Result<(Transaction, u128)> {
+    request.validate()?;
+    if state.is_watch_only(wallet_name) && state.get_wallet(wallet_name).await.is_some() {
+        return Err(to_forbidden(anyhow::anyhow!("wallet is watch-only")));
+    }
+    let signing_key: Arc<dyn Signer> = if let Some(signing_key) = request.decoded_signing_key()? {
+        Arc::new(signing_key)
+    } else {
+        state
+            .get_signer(wallet_name)
+            .context("wallet is locked")
+            .map_err(to_forbidden)?
+    };
+    let wallet = state
+        .get_wallet(wallet_name)
+        .await
+        .context("no wallet")
+        .map_err(to_badreq)?;
+
+    // calculate fees
+    let snapshot = state.snapshot().await.map_err(to_badgateway)?;
+    let fee_multiplier = request.fee_multiplier(snapshot.current_header().fee_multiplier)?;
+    let data = request.decoded_data()?;
+    let change_address = request.decoded_change_address()?;
+    if let Some(address) = change_address {
+        if !state.owns_address(address).await {
+            log::warn!(
+                "sending change to {}, which is not one of our wallets, so it will not be tracked",
+                address
+            );
+        }
+    }
+    // a concurrent preparation may grab the same coins between our picking and reserving them, in which case we pick again
+    for _ in 0..3 {
+        let prepared_tx = wallet
+            .prepare(
+                request.inputs.clone(),
+                request.outputs.clone(),
+                fee_multiplier,
+                |tx: Transaction| {
+                    let mut tx = request.apply(tx, &data);
+                    for i in 0..tx.inputs.len() {
+                        tx = signing_key.sign_tx(tx, i)?;
+                    }
+                    Ok(tx)
+                },
+                request.nobalance.clone(),
+                request.single_change,
+                change_address,
+                request
+                    .coin_selection
+                    .unwrap_or(state.coin_selection)
+                    .selector(),
+                state.snapshot().await.map_err(to_badgateway)?,
+            )
+            .await
+            .map_err(to_badreq)?;
+        let expires = SystemTime::now().duration_since(UNIX_EPOCH)? + state.coin_reservation;
+        if wallet
+            .reserve_coins(&prepared_tx.inputs, expires.as_secs())
+            .await?
+        {
+            // an external signer only laid out placeholders, so now we go get the real signatures
+            if let (Some(external), None) = (
+                state.get_external_signer(wallet_name),
+                request.signing_key.as_ref(),
+            ) {
+                match external.sign_remotely(prepared_tx.clone()).await {
+                    Ok(signed_tx) => return Ok((signed_tx, fee_multiplier)),
+                    Err(err) => {
+                        for coin in prepared_tx.inputs.iter() {
+                            wallet.release_coin(*coin).await?;
+                        }
+                        return Err(to_badgateway(err));
+                    }
+                }
+            }
+            return Ok((prepared_tx, fee_multiplier));
+        }
+    }
+    Err(tide::Error::new(
+        StatusCode::Conflict,
+        anyhow::anyhow!("coins are reserved by other prepared transactions"),
+    ))
+}
+
+async fn prepare_swap(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        from: String,
+        to: String,
+        value: u128,
+        min_out: Option<u128>,
+        signing_key: Option<String>,
+        fee_multiplier: Option<u128>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let query: EncodingQuery = req.query()?;
+    let request: Req = req.body_json().await?;
+
+    let from = parse_denom(&request.from)?;
+    let to = parse_denom(&request.to)?;
+    if from == to {
+        return Err(to_badreq(anyhow::anyhow!(
+            "cannot swap between identical denoms"
+        )));
+    }
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("no wallet")
+        .map_err(to_badreq)?;
+
+    let pool_key = PoolKey::new(from, to);
+    let pool_state = req
+        .state()
+        .snapshot()
+        .await
+        .map_err(to_badgateway)?
+        .get_pool(pool_key)
+        .await
+        .map_err(to_badgateway)?
+        .ok_or_else(|| to_badreq(anyhow::anyhow!("pool not found")))?;
+    let (expected_out, _) = simulate_swap(pool_key, pool_state, from, request.value);
+    if let Some(min_out) = request.min_out {
+        if expected_out < min_out {
+            return Err(to_badreq(anyhow::anyhow!(
+                "swap would only return {}, below min_out of {}",
+                expected_out,
+                min_out
+            )));
+        }
+    }
+
+    // the first output is what gets transmuted by the pool
+    let args = PrepareTxArgs {
+        inputs: vec![],
+        outputs: vec![CoinData {
+            covhash: wallet.address(),
+            value: CoinValue(request.value),
+            denom: from,
+            additional_data: vec![],
+        }],
+        signing_key: request.signing_key,
+        kind: Some(TxKind::Swap),
+        data: Some(hex::encode(pool_key.to_bytes())),
+        covenants: vec![],
+        nobalance: vec![],
+        fee_multiplier: request.fee_multiplier,
+        single_change: false,
+        change_address: None,
+        coin_selection: None,
+    };
+    prepare_signed(req.state(), &wallet_name, args, query.encoding).await
+}
+
+async fn prepare_stake(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        pubkey: Ed25519PK,
+        e_start: u64,
+        e_post_end: u64,
+        syms_staked: u128,
+        signing_key: Option<String>,
+        fee_multiplier: Option<u128>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let query: EncodingQuery = req.query()?;
+    let request: Req = req.body_json().await?;
+
+    if request.syms_staked == 0 {
+        return Err(to_badreq(anyhow::anyhow!("syms_staked must be positive")));
+    }
+    if request.e_post_end <= request.e_start {
+        return Err(to_badreq(anyhow::anyhow!(
+            "e_post_end must come after e_start"
+        )));
+    }
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("no wallet")
+        .map_err(to_notfound)?;
+    // the network quietly ignores stakes that don't start in a future epoch, so catch them here
+    let current_epoch = req
+        .state()
+        .snapshot()
+        .await
+        .map_err(to_badgateway)?
+        .current_header()
+        .height
+        .epoch();
+    if request.e_start <= current_epoch {
+        return Err(to_badreq(anyhow::anyhow!(
+            "e_start must be after the current epoch {}",
+            current_epoch
+        )));
+    }
+
+    let stake_doc = StakeDoc {
+        pubkey: request.pubkey,
+        e_start: request.e_start,
+        e_post_end: request.e_post_end,
+        syms_staked: CoinValue(request.syms_staked),
+    };
+    // the first output is the staked coin, which the network locks until the stake ends
+    let args = PrepareTxArgs {
+        inputs: vec![],
+        outputs: vec![CoinData {
+            covhash: wallet.address(),
+            value: stake_doc.syms_staked,
+            denom: Denom::Sym,
+            additional_data: vec![],
+        }],
+        signing_key: request.signing_key,
+        kind: Some(TxKind::Stake),
+        data: Some(hex::encode(stake_doc.stdcode())),
+        covenants: vec![],
+        nobalance: vec![],
+        fee_multiplier: request.fee_multiplier,
+        single_change: false,
+        change_address: None,
+        coin_selection: None,
+    };
+    prepare_signed(req.state(), &wallet_name, args, query.encoding).await
+}
+
+async fn consolidate(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        denom: String,
+        #[serde(default = "default_max_inputs")]
+        max_inputs: usize,
+        signing_key: Option<String>,
+        fee_multiplier: Option<u128>,
+    }
+    fn default_max_inputs() -> usize {
+        100
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = req.body_json().await?;
+    let denom = parse_denom(&request.denom)?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+
+    // the smallest coins are the ones worth merging
+    let next_height = req
+        .state()
+        .snapshot()
+        .await
+        .map_err(to_badgateway)?
+        .current_header()
+        .height
+        + BlockHeight(1);
+    let reserved = wallet.get_reserved_coins().await;
+    let frozen = wallet.get_frozen_coins().await;
+    let maturities = wallet.get_coin_maturities().await;
+    let mut coins: Vec<(CoinID, CoinData)> = wallet
+        .get_coin_mapping(true, false)
+        .await
+        .into_iter()
+        .filter(|(coin, data)| {
+            data.denom == denom
+                && !reserved.contains(coin)
+                && !frozen.contains(coin)
+                && maturities.get(coin).is_none_or(|h| *h <= next_height)
+        })
+        .collect();
+    coins.sort_by_key(|(_, data)| data.value);
+    coins.truncate(request.max_inputs);
+    if coins.len() < 2 {
+        return Err(to_badreq(anyhow::anyhow!(
+            "fewer than two spendable {} coins to consolidate",
+            denom
+        )));
+    }
+
+    let args = PrepareTxArgs {
+        inputs: coins.into_iter().map(|(coin, _)| coin).collect(),
+        outputs: vec![],
+        signing_key: request.signing_key,
+        kind: None,
+        data: None,
+        covenants: vec![],
+        nobalance: vec![],
+        fee_multiplier: request.fee_multiplier,
+        single_change: true,
+        change_address: None,
+        coin_selection: None,
+    };
+    prepare_signed(req.state(), &wallet_name, args, TxEncoding::Json).await
+}
+
+async fn sweep(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        address: String,
+        denom: String,
+        signing_key: Option<String>,
+        fee_multiplier: Option<u128>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: Req = req.body_json().await?;
+    let denom = parse_denom(&request.denom)?;
+    let address: Address = request
+        .address
+        .parse()
+        .map_err(|_| to_badreq(anyhow::anyhow!("cannot parse address")))?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+
+    let next_height = req
+        .state()
+        .snapshot()
+        .await
+        .map_err(to_badgateway)?
+        .current_header()
+        .height
+        + BlockHeight(1);
+    let reserved = wallet.get_reserved_coins().await;
+    let frozen = wallet.get_frozen_coins().await;
+    let maturities = wallet.get_coin_maturities().await;
+    let coins: Vec<(CoinID, CoinData)> = wallet
+        .get_coin_mapping(true, false)
+        .await
+        .into_iter()
+        .filter(|(coin, data)| {
+            data.denom == denom
+                && !reserved.contains(coin)
+                && !frozen.contains(coin)
+                && maturities.get(coin).is_none_or(|h| *h <= next_height)
+        })
+        .collect();
+    if coins.is_empty() {
+        return Err(to_badreq(anyhow::anyhow!(
+            "no spendable {} coins to sweep",
+            denom
+        )));
+    }
+    let total: CoinValue = coins.iter().map(|(_, data)| data.value).sum();
+
+    // the fee is paid in MEL, so swept MEL is the change left over after the fee, while any other denomination goes out whole and the fee comes from the rest of the wallet
+    let (outputs, change_address) = if denom == Denom::Mel {
+        (vec![], Some(request.address))
+    } else {
+        (
+            vec![CoinData {
+                covhash: address,
+                value: total,
+                denom,
+                additional_data: vec![],
+            }],
+            None,
+        )
+    };
+    let args = PrepareTxArgs {
+        inputs: coins.into_iter().map(|(coin, _)| coin).collect(),
+        outputs,
+        signing_key: request.signing_key,
+        kind: None,
+        data: None,
+        covenants: vec![],
+        nobalance: vec![],
+        fee_multiplier: request.fee_multiplier,
+        single_change: true,
+        change_address,
+        coin_selection: None,
+    };
+    prepare_signed(req.state(), &wallet_name, args, TxEncoding::Json).await
+}
+
+async fn estimate_fee(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        fee: CoinValue,
+        size: usize,
+        weight: u128,
+        fee_multiplier: u128,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: PrepareTxArgs = body_json_fields(&mut req).await?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("no wallet")
+        .map_err(to_notfound)?;
+    let (estimated_tx, fee_multiplier) = prepare_unsigned(req.state(), &wallet, &request).await?;
+
+    Body::from_json(&Resp {
+        fee: estimated_tx.fee,
+        size: estimated_tx.stdcode().len(),
+        weight: estimated_tx.weight(covenant_weight_from_bytes),
+        fee_multiplier,
+    })
+}
+
+async fn preview_tx(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Input {
+        coin_id: String,
+        coin_data: CoinData,
+    }
+    #[derive(Serialize)]
+    struct Resp {
+        inputs: Vec<Input>,
+        outputs: Vec<AnnCoinID>,
+        fee: CoinValue,
+        fee_multiplier: u128,
+        balance: BTreeMap<String, i128>,
+    }
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: PrepareTxArgs = body_json_fields(&mut req).await?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("no wallet")
+        .map_err(to_notfound)?;
+    let (tx, fee_multiplier) = prepare_unsigned(req.state(), &wallet, &request).await?;
+
+    let mut inputs = vec![];
+    for coin in tx.inputs.iter() {
+        let coin_data = wallet
+            .get_one_coin(*coin)
+            .await
+            .with_context(|| format!("input {} is not a coin of this wallet", coin))
+            .map_err(to_badreq)?;
+        inputs.push(Input {
+            coin_id: coin.to_string(),
+            coin_data,
+        });
+    }
+    let outputs = tx
+        .outputs
+        .iter()
+        .enumerate()
+        .map(|(i, cd)| AnnCoinID {
+            coin_data: cd.clone(),
+            is_change: cd.covhash == wallet.address(),
+            coin_id: tx.output_coinid(i as u8).to_string(),
+        })
+        .collect();
+    Body::from_json(&Resp {
+        inputs,
+        outputs,
+        fee: tx.fee,
+        fee_multiplier,
+        balance: prepared_balance(wallet.address(), &tx),
+    })
+}
+
+/// Prepares a transaction for the given wallet without signing or reserving anything, returning it along with the fee multiplier used.
+async fn prepare_unsigned(
+    state: &AppState,
+    wallet: &Wallet,
+    request: &PrepareTxArgs,
+) -> tide::Result<(Transaction, u128)> {
+    request.validate()?;
+    let snapshot = state.snapshot().await.map_err(to_badgateway)?;
+    let fee_multiplier = request.fee_multiplier(snapshot.current_header().fee_multiplier)?;
+    let data = request.decoded_data()?;
+    let tx = wallet
+        .prepare(
+            request.inputs.clone(),
+            request.outputs.clone(),
+            fee_multiplier,
+            |tx: Transaction| {
+                // stand-in signatures, so that the fee accounts for the weight of real ones
+                let mut tx = request.apply(tx, &data);
+                tx.sigs = vec![vec![0u8; 64]; tx.inputs.len()];
+                Ok(tx)
+            },
+            request.nobalance.clone(),
+            request.single_change,
+            request.decoded_change_address()?,
+            request
+                .coin_selection
+                .unwrap_or(state.coin_selection)
+                .selector(),
+            snapshot,
+        )
+        .await
+        .map_err(to_badreq)?;
+    Ok((tx, fee_multiplier))
+}
 
-async fn export_sk_from_wallet(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+async fn estimate_confirmation(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
     #[derive(Deserialize)]
     struct Req {
-        password: Option<String>,
+        transaction: Option<Transaction>,
+        fee: Option<CoinValue>,
+        weight: Option<u128>,
+    }
+    #[derive(Serialize)]
+    struct Resp {
+        fee_multiplier_paid: u128,
+        current_fee_multiplier: u128,
+        recent_fee_multipliers: Vec<u128>,
+        estimated_blocks: Option<u64>,
     }
-    let wallet_name = req.param("name").map(|v| v.to_string())?;
     let request: Req = req.body_json().await?;
-    // attempt to unlock
-    let secret = req
+    let (fee, weight) = match (request.transaction, request.fee, request.weight) {
+        (Some(tx), None, None) => (tx.fee, tx.weight(covenant_weight_from_bytes)),
+        (None, Some(fee), Some(weight)) => (fee, weight),
+        _ => {
+            return Err(to_badreq(anyhow::anyhow!(
+                "give either a transaction, or both a fee and a weight"
+            )))
+        }
+    };
+    if weight == 0 {
+        return Err(to_badreq(anyhow::anyhow!("weight must be positive")));
+    }
+    let fee_multiplier_paid = fee.0.saturating_mul(1 << 16) / weight;
+
+    let (current_fee_multiplier, recent_fee_multipliers) = req
         .state()
-        .get_secret_key(&wallet_name, request.password)
-        .context("incorrect password")
-        .map_err(to_forbidden)?;
-    Ok(base32::encode(Alphabet::Crockford, &secret.0[..32]).into())
+        .recent_fee_multipliers()
+        .await
+        .map_err(to_badgateway)?;
+
+    // a fee covering the current multiplier goes into the next block; otherwise we guess from how often recent blocks were cheap enough
+    let estimated_blocks = if fee_multiplier_paid >= current_fee_multiplier {
+        Some(1)
+    } else {
+        let cheap_enough = recent_fee_multipliers
+            .iter()
+            .filter(|multiplier| **multiplier <= fee_multiplier_paid)
+            .count() as u64;
+        (cheap_enough > 0).then(|| (recent_fee_multipliers.len() as u64).div_ceil(cheap_enough))
+    };
+
+    Body::from_json(&Resp {
+        fee_multiplier_paid,
+        current_fee_multiplier,
+        recent_fee_multipliers,
+        estimated_blocks,
+    })
 }
 
-// async fn prepare_stake_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
-//     todo!()
-// }
+async fn get_fee_stats(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        current: u128,
+        min: u128,
+        median: u128,
+        max: u128,
+        blocks: usize,
+    }
+    let (current, mut recent) = req
+        .state()
+        .recent_fee_multipliers()
+        .await
+        .map_err(to_badgateway)?;
+    recent.sort_unstable();
+    let mid = recent.len() / 2;
+    let median = if recent.len() % 2 == 0 {
+        (recent[mid - 1] + recent[mid]) / 2
+    } else {
+        recent[mid]
+    };
+    Body::from_json(&Resp {
+        current,
+        min: recent[0],
+        median,
+        max: recent[recent.len() - 1],
+        blocks: recent.len(),
+    })
+}
 
-async fn prepare_tx(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+async fn add_signature(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
     #[derive(Deserialize)]
     struct Req {
-        #[serde(default)]
-        inputs: Vec<CoinID>,
-        outputs: Vec<CoinData>,
-        signing_key: Option<String>,
-        kind: Option<TxKind>,
-        data: Option<String>,
-        #[serde(default, with = "stdcode::hexvec")]
-        covenants: Vec<Vec<u8>>,
-        #[serde(default)]
-        nobalance: Vec<Denom>,
+        transaction: Transaction,
+        key_index: usize,
+        signature: String,
+    }
+    #[derive(Serialize)]
+    struct Resp {
+        signatures: usize,
+        threshold: usize,
+        complete: bool,
+        transaction: Transaction,
     }
     let wallet_name = req.param("name").map(|v| v.to_string())?;
     let request: Req = req.body_json().await?;
-    let signing_key: Arc<dyn Signer> = if let Some(signing_key) = request.signing_key.as_ref() {
-        Arc::new(signing_key.parse::<Ed25519SK>()?)
-    } else {
-        req.state()
-            .get_signer(&wallet_name)
-            .context("wallet is locked")
-            .map_err(to_forbidden)?
-    };
+    let signer = req
+        .state()
+        .get_threshold_signer(&wallet_name)
+        .context("not a multisig wallet")
+        .map_err(to_badreq)?;
     let wallet = req
         .state()
         .get_wallet(&wallet_name)
         .await
         .context("no wallet")
-        .map_err(to_badreq)?;
+        .map_err(to_notfound)?;
+    if !request
+        .transaction
+        .covenants
+        .iter()
+        .any(|c| c.hash() == wallet.address().0)
+    {
+        return Err(to_badreq(anyhow::anyhow!(
+            "transaction does not spend from this wallet"
+        )));
+    }
+    let signature = hex::decode(&request.signature).map_err(to_badreq)?;
 
-    // calculate fees
-    let client = req.state().client.clone();
-    let snapshot = client.snapshot().await.map_err(to_badgateway)?;
-    let fee_multiplier = snapshot.current_header().fee_multiplier;
-    let kind = request.kind;
-    let data = match request.data.as_ref() {
-        Some(v) => Some(hex::decode(v).map_err(to_badreq)?),
-        None => None,
-    };
-    let prepared_tx = wallet
-        .prepare(
-            request.inputs.clone(),
-            request.outputs.clone(),
-            fee_multiplier,
-            |mut tx: Transaction| {
-                if let Some(kind) = kind {
-                    tx.kind = kind
-                }
-                if let Some(data) = data.clone() {
-                    tx.data = data
-                }
-                tx.covenants.extend_from_slice(&request.covenants);
-                for i in 0..tx.inputs.len() {
-                    tx = signing_key.sign_tx(tx, i)?;
-                }
-                Ok(tx)
-            },
-            request.nobalance.clone(),
-            req.state().client.snapshot().await?,
-        )
+    // pick up the signatures gathered so far
+    let txhash = request.transaction.hash_nosigs();
+    let txn = wallet
+        .get_partial_transaction(txhash)
         .await
+        .unwrap_or(request.transaction);
+    let txn = signer
+        .add_signature(txn, request.key_index, signature)
         .map_err(to_badreq)?;
-
-    Body::from_json(&prepared_tx)
+    let signatures = signer.count_signatures(&txn);
+    let complete = signatures >= signer.threshold;
+    if complete {
+        wallet.remove_partial_transaction(txhash).await?;
+    } else {
+        wallet.store_partial_transaction(&txn).await?;
+    }
+    Body::from_json(&Resp {
+        signatures,
+        threshold: signer.threshold,
+        complete,
+        transaction: txn,
+    })
 }
 
-async fn send_tx(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+async fn send_tx(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Query {
+        #[serde(default)]
+        validate: bool,
+    }
+    #[derive(Serialize)]
+    struct Validation {
+        valid: bool,
+        errors: Vec<String>,
+    }
+    let query: Query = req.query()?;
     let wallet_name = req.param("name").map(|v| v.to_string())?;
     let tx: Transaction = req.body_json().await?;
+    if query.validate {
+        // validation ahead of sending should see the latest state, so it skips the shared snapshot
+        let snapshot = req.state().fresh_snapshot().await.map_err(to_badgateway)?;
+        let errors = validate_tx(&snapshot, &tx).await.map_err(to_badgateway)?;
+        return Ok(Body::from_json(&Validation {
+            valid: errors.is_empty(),
+            errors,
+        })?
+        .into());
+    }
+    broadcast(req.state(), &wallet_name, tx)
+        .await?
+        .into_response()
+}
 
-    let wallet = req
-        .state()
-        .get_wallet(&wallet_name)
+/// Checks a transaction against a snapshot the way the network would, returning every reason it would be rejected.
+async fn validate_tx(
+    snapshot: &ValClientSnapshot,
+    tx: &Transaction,
+) -> anyhow::Result<Vec<String>> {
+    let mut errors = vec![];
+    if !tx.is_well_formed() {
+        errors.push("transaction is not well-formed".to_string());
+    }
+    let header = snapshot.current_header();
+    let covenants = tx.covenants_as_map();
+    let mut input_sum: BTreeMap<Denom, CoinValue> = BTreeMap::new();
+    for (i, input) in tx.inputs.iter().enumerate() {
+        let cdh = match snapshot.get_coin(*input).await? {
+            Some(cdh) => cdh,
+            None => {
+                errors.push(format!(
+                    "input {} ({}) does not exist or is already spent",
+                    i, input
+                ));
+                continue;
+            }
+        };
+        *input_sum.entry(cdh.coin_data.denom).or_default() += cdh.coin_data.value;
+        let covhash = cdh.coin_data.covhash;
+        match covenants.get(&covhash) {
+            None => errors.push(format!(
+                "input {} has no covenant hashing to {}",
+                i,
+                covhash.0.to_addr()
+            )),
+            Some(covenant) => {
+                let env = CovenantEnv {
+                    parent_coinid: *input,
+                    parent_cdh: cdh.clone(),
+                    spender_index: i as u8,
+                    last_header: header,
+                };
+                if !Covenant(covenant.clone()).check(tx, env) {
+                    errors.push(format!(
+                        "input {} fails its covenant; is it properly signed?",
+                        i
+                    ));
+                }
+            }
+        }
+    }
+    if tx.kind != TxKind::Faucet {
+        for (denom, output_value) in tx.total_outputs() {
+            if denom == Denom::NewCoin || (tx.kind == TxKind::DoscMint && denom == Denom::Erg) {
+                continue;
+            }
+            let input_value = input_sum.get(&denom).copied().unwrap_or_default();
+            if input_value != output_value {
+                errors.push(format!(
+                    "denomination {} is unbalanced: {} in, {} out including fees",
+                    denom, input_value, output_value
+                ));
+            }
+        }
+    }
+    let min_fee = tx.base_fee(header.fee_multiplier, 0, covenant_weight_from_bytes);
+    if tx.fee < min_fee {
+        errors.push(format!(
+            "fee of {} is below the minimum of {}",
+            tx.fee, min_fee
+        ));
+    }
+    Ok(errors)
+}
+
+/// Prepares, signs, and sends a transaction in one go. Unlike going through `prepare-tx` and `send-tx`, the caller never gets to review the transaction before it goes out, so this is meant for automated services that trust the daemon.
+async fn prepare_and_send(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let request: PrepareTxArgs = body_json_fields(&mut req).await?;
+    let (tx, _) = prepare_signed_tx(req.state(), &wallet_name, request).await?;
+    broadcast(req.state(), &wallet_name, tx)
+        .await?
+        .into_response()
+}
+
+/// Sends a transaction off to the network and marks it as sent by the given wallet.
+async fn broadcast(state: &AppState, wallet_name: &str, tx: Transaction) -> tide::Result<Sent> {
+    let wallet = state
+        .get_wallet(wallet_name)
         .await
         .context("fail")
         .map_err(to_badreq)?;
-    let snapshot = req.state().client.snapshot().await?;
-    // we send it off ourselves
-    snapshot.get_raw().send_tx(tx.clone()).await?;
+    let snapshot = send_to_network(state, &tx).await?;
+    let txhash = tx.hash_nosigs();
     // we mark the TX as sent in this thread.
-    wallet
+    let tracked = state
         .commit_sent(
-            tx.clone(),
-            snapshot.current_header().height + BlockHeight(10),
+            wallet_name,
+            &wallet,
+            tx,
+            snapshot.current_header().height,
+            snapshot.current_header().height + state.commit_timeout,
         )
-        .await
-        .map_err(to_badreq)?;
-    log::info!("sent transaction with hash {}", tx.hash_nosigs());
-    Body::from_json(&tx.hash_nosigs())
+        .await;
+    log::info!("sent transaction with hash {}", txhash);
+    Ok(Sent { txhash, tracked })
 }
 
-// async fn force_revert_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
-//     todo!()
-// }
+/// A transaction that went out to the network.
+struct Sent {
+    txhash: TxHash,
+    /// Whether the wallet recorded the transaction. If not, it is in the send journal, and its change isn't tracked until the daemon restarts.
+    tracked: bool,
+}
+
+impl Sent {
+    /// Answers with the transaction hash, warning in a `Warning` header if the wallet could not record the transaction.
+    fn into_response(self) -> tide::Result<tide::Response> {
+        let mut res = tide::Response::new(StatusCode::Ok);
+        if !self.tracked {
+            res.insert_header(
+                "Warning",
+                "199 melwalletd \"transaction was sent but could not be recorded by the wallet\"",
+            );
+        }
+        res.set_body(Body::from_json(&self.txhash)?);
+        Ok(res)
+    }
+}
+
+/// Sends a transaction off to the network, returning the snapshot it was sent against.
+async fn send_to_network(state: &AppState, tx: &Transaction) -> tide::Result<ValClientSnapshot> {
+    let snapshot = state.fresh_snapshot().await.map_err(to_badgateway)?;
+    // we send it off ourselves
+    let sent = state
+        .node_retry
+        .run("sending a transaction", || {
+            snapshot.get_raw().send_tx(tx.clone())
+        })
+        .await;
+    state.metrics.record_send(sent.is_ok());
+    sent?;
+    Ok(snapshot)
+}
+
+/// Broadcasts a transaction signed elsewhere. Naming a wallet has that wallet track the transaction, as if it had been sent through `send-tx`.
+async fn broadcast_tx(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize)]
+    struct Req {
+        transaction: Transaction,
+        wallet: Option<String>,
+    }
+    let request: Req = req.body_json().await?;
+    let sent = match request.wallet {
+        Some(wallet_name) => {
+            if req.state().get_wallet(&wallet_name).await.is_none() {
+                return Err(to_notfound(anyhow::anyhow!("wallet not found")));
+            }
+            broadcast(req.state(), &wallet_name, request.transaction).await?
+        }
+        None => {
+            send_to_network(req.state(), &request.transaction).await?;
+            let txhash = request.transaction.hash_nosigs();
+            log::info!("broadcast transaction with hash {}", txhash);
+            Sent {
+                txhash,
+                tracked: true,
+            }
+        }
+    };
+    sent.into_response()
+}
+
+/// Decodes a transaction given as hex-encoded stdcode bytes, such as a transaction prepared with `encoding=stdcode`, showing its structure along with what the network derives from it.
+async fn decode_tx(mut req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    #[derive(Serialize)]
+    struct Resp {
+        transaction: Transaction,
+        hash_nosigs: TxHash,
+        output_coin_ids: Vec<CoinID>,
+    }
+    let body = req.body_string().await?;
+    let bytes = hex::decode(body.trim())
+        .map_err(|err| to_badreq(anyhow::anyhow!("transaction is not hex: {}", err)))?;
+    let transaction: Transaction = stdcode::deserialize(&bytes).map_err(|err| {
+        to_badreq(anyhow::anyhow!(
+            "{} bytes do not decode to a transaction: {}",
+            bytes.len(),
+            err
+        ))
+    })?;
+    let output_coin_ids = (0..transaction.outputs.len())
+        .map(|idx| transaction.output_coinid(idx as u8))
+        .collect();
+    Body::from_json(&Resp {
+        hash_nosigs: transaction.hash_nosigs(),
+        output_coin_ids,
+        transaction,
+    })
+}
+
+async fn abandon_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    let txhash: HashVal = req.param("txhash")?.parse().map_err(to_badreq)?;
+    let tx = wallet
+        .get_cached_transaction(txhash.into())
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+    if !wallet.is_pending(txhash.into()).await {
+        return Err(to_notfound(anyhow::anyhow!("transaction is not pending")));
+    }
+    // refuse to abandon only what actually made it on-chain: a transaction whose outputs exist or were seen confirmed
+    let already_confirmed = || {
+        tide::Error::new(
+            StatusCode::Conflict,
+            anyhow::anyhow!("transaction is already confirmed"),
+        )
+    };
+    if wallet.get_tx_confirmation(&tx).await.is_some() {
+        return Err(already_confirmed());
+    }
+    let snapshot = req.state().fresh_snapshot().await.map_err(to_badgateway)?;
+    let outputs = (0..tx.outputs.len()).map(|idx| (tx.output_coinid(idx as u8), true));
+    let inputs = tx.inputs.iter().map(|input| (*input, false));
+    let lookups: Vec<_> = outputs
+        .chain(inputs)
+        .map(|(coin, is_output)| {
+            let snapshot = snapshot.clone();
+            smolscale::spawn(async move {
+                snapshot
+                    .get_coin(coin)
+                    .await
+                    .map(|cdh| (coin, is_output, cdh.is_some()))
+            })
+        })
+        .collect();
+    let mut spent_inputs = vec![];
+    for lookup in lookups {
+        let (coin, is_output, exists) = lookup.await.map_err(to_badgateway)?;
+        if is_output && exists {
+            return Err(already_confirmed());
+        }
+        if !is_output && !exists {
+            spent_inputs.push(coin);
+        }
+    }
+    // outputs may have been spent since, so a gone input is only harmless if some other transaction spent it
+    for input in spent_inputs {
+        let confirmed_height = wallet
+            .get_coin_confirmation(input)
+            .await
+            .with_context(|| format!("input {} was never confirmed in this wallet", input))?
+            .height;
+        let spender = find_spender(&snapshot, input, confirmed_height)
+            .await
+            .map_err(to_badgateway)?;
+        if spender.hash_nosigs() == txhash.into() {
+            return Err(already_confirmed());
+        }
+        log::debug!(
+            "input {} of {} was spent by {} instead",
+            input,
+            txhash,
+            spender.hash_nosigs()
+        );
+    }
+    if !wallet.abandon_transaction(txhash.into()).await? {
+        return Err(to_notfound(anyhow::anyhow!("transaction is not pending")));
+    }
+    log::info!("abandoned transaction with hash {}", txhash);
+    Ok(plain_text(""))
+}
 
 async fn get_tx_balance(req: Request<Arc<AppState>>) -> tide::Result<Body> {
     let wallet_name = req.param("name").map(|v| v.to_string())?;
@@ -453,17 +3356,21 @@ async fn get_tx_balance(req: Request<Arc<AppState>>) -> tide::Result<Body> {
         .state()
         .get_wallet(&wallet_name)
         .await
-        .context("wtf")
-        .map_err(to_badreq)?;
+        .context("wallet not found")
+        .map_err(to_notfound)?;
     let txhash: HashVal = req.param("txhash")?.parse().map_err(to_badreq)?;
     let raw = wallet
-        .get_transaction(txhash.into(), async {
-            Ok(req.state().client.snapshot().await?)
-        })
+        .get_transaction(txhash.into(), async { req.state().snapshot().await })
         .await
         .map_err(to_badgateway)?
         .context("not found")
         .map_err(to_notfound)?;
+    let (self_originated, balance) = tx_balance(&wallet, &raw).await;
+    Body::from_json(&(self_originated, raw.kind, balance))
+}
+
+/// Works out how a transaction changed the balance of a wallet in each denomination, keyed by hex-encoded denomination. Also returns whether the wallet sent the transaction.
+async fn tx_balance(wallet: &Wallet, raw: &Transaction) -> (bool, BTreeMap<String, i128>) {
     // Is this self-originated? We check the covenants
     let self_originated = raw.covenants.iter().any(|c| c.hash() == wallet.address().0);
     // Total balance out
@@ -489,7 +3396,23 @@ async fn get_tx_balance(req: Request<Arc<AppState>>) -> tide::Result<Body> {
             }
         }
     }
-    Body::from_json(&(self_originated, raw.kind, balance))
+    (self_originated, balance)
+}
+
+/// Like [tx_balance], but for a transaction this wallet has prepared and not yet sent, whose outputs it cannot know as coins yet.
+fn prepared_balance(address: Address, tx: &Transaction) -> BTreeMap<String, i128> {
+    let mut balance: BTreeMap<String, i128> = BTreeMap::new();
+    *balance
+        .entry(hex::encode(Denom::Mel.to_bytes()))
+        .or_default() -= tx.fee.0 as i128;
+    for output in tx.outputs.iter() {
+        if output.covhash != address {
+            *balance
+                .entry(hex::encode(output.denom.to_bytes()))
+                .or_default() -= output.value.0 as i128;
+        }
+    }
+    balance
 }
 
 async fn get_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
@@ -499,8 +3422,8 @@ async fn get_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
         .state()
         .get_wallet(&wallet_name)
         .await
-        .context("wtf")
-        .map_err(to_badreq)?;
+        .context("wallet not found")
+        .map_err(to_notfound)?;
     let txhash: HashVal = req.param("txhash")?.parse().map_err(to_badreq)?;
 
     // Must either be pending or
@@ -510,15 +3433,7 @@ async fn get_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
         .await
         .context("not found")
         .map_err(to_notfound)?;
-    let mut confirmed_height = None;
-    for idx in 0..raw.outputs.len() {
-        if let Some(cdh) = wallet
-            .get_coin_confirmation(raw.output_coinid(idx as u8))
-            .await
-        {
-            confirmed_height = Some(cdh.height);
-        }
-    }
+    let confirmed_height = wallet.get_tx_confirmation(&raw).await;
     let outputs = raw
         .outputs
         .iter()
@@ -534,8 +3449,22 @@ async fn get_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
             }
         })
         .collect();
+    // where each input came from, as far as the wallet's history goes
+    let mut inputs = vec![];
+    for input in raw.inputs.iter() {
+        let created_by = wallet
+            .get_cached_transaction(input.txhash)
+            .await
+            .map(|_| input.txhash);
+        inputs.push(AnnInput {
+            coin_id: input.to_string(),
+            coin_data: wallet.get_one_coin(*input).await,
+            created_by,
+        });
+    }
 
-    if confirmed_height.is_none() {
+    let abandoned = wallet.is_abandoned(txhash.into()).await;
+    if confirmed_height.is_none() && !abandoned {
         // Must be pending
         if !wallet.is_pending(txhash.into()).await {
             Err(anyhow::anyhow!(
@@ -548,45 +3477,186 @@ async fn get_tx(req: Request<Arc<AppState>>) -> tide::Result<Body> {
         raw,
         confirmed_height,
         outputs,
+        inputs,
+        abandoned,
+        label: wallet.get_label(txhash.into()).await,
     })
 }
 
-async fn send_faucet(req: Request<Arc<AppState>>) -> tide::Result<Body> {
+async fn stream_tx(req: Request<Arc<AppState>>, sender: tide::sse::Sender) -> tide::Result<()> {
+    #[derive(Deserialize)]
+    struct Query {
+        #[serde(default = "default_timeout")]
+        timeout: u64,
+    }
+    fn default_timeout() -> u64 {
+        600
+    }
+    let query: Query = req.query()?;
+    let wallet_name = req.param("name").map(|v| v.to_string())?;
+    let wallet = req
+        .state()
+        .get_wallet(&wallet_name)
+        .await
+        .context("wallet not found")
+        .map_err(to_notfound)?;
+    let txhash: HashVal = req.param("txhash")?.parse().map_err(to_badreq)?;
+    let raw = wallet
+        .get_cached_transaction(txhash.into())
+        .await
+        .context("not found")
+        .map_err(to_notfound)?;
+
+    // the confirm task updates the database in the background, so we watch it for changes
+    let deadline = Instant::now() + Duration::from_secs(query.timeout);
+    let mut last_event = None;
+    while Instant::now() < deadline {
+        let (event, data, done) = match wallet.get_tx_confirmation(&raw).await {
+            Some(height) => ("confirmed", height.to_string(), true),
+            None if wallet.is_pending(txhash.into()).await => ("pending", String::new(), false),
+            None => ("abandoned", String::new(), true),
+        };
+        if last_event != Some(event) {
+            sender.send(event, data, None).await?;
+            last_event = Some(event);
+        }
+        if done {
+            break;
+        }
+        smol::Timer::after(Duration::from_secs(1)).await;
+    }
+    Ok(())
+}
+
+async fn send_faucet(mut req: Request<Arc<AppState>>) -> tide::Result<tide::Response> {
+    #[derive(Deserialize, Default)]
+    struct Req {
+        value: Option<CoinValue>,
+    }
+    // the body is optional, for compatibility with clients that send none
+    let body = req.body_string().await?;
+    let request: Req = if body.trim().is_empty() {
+        Req::default()
+    } else {
+        serde_json::from_str(&body).map_err(to_badreq)?
+    };
     let wallet_name = req.param("name").map(|v| v.to_string())?;
     let network = req.state().network;
     let wallet = req
         .state()
         .get_wallet(&wallet_name)
         .await
-        .context("wtf")
-        .map_err(to_badreq)?;
+        .context("wallet not found")
+        .map_err(to_notfound)?;
     if network == NetID::Mainnet {
         return Err(tide::Error::new(
             StatusCode::BadRequest,
             anyhow::anyhow!("faucet is not supported on mainnet"),
         ));
     }
-    let tx = Transaction {
-        kind: TxKind::Faucet,
-        inputs: vec![],
-        outputs: vec![CoinData {
-            covhash: wallet.address(),
-            value: CoinValue::from_millions(1001u64),
-            denom: Denom::Mel,
-            additional_data: vec![],
-        }],
-        data: (0..32).map(|_| fastrand::u8(0..=255)).collect(),
-        fee: CoinValue::from_millions(1001u64),
-        covenants: vec![],
-        sigs: vec![],
-    };
-    // we mark the TX as sent in this thread
-    let txhash = tx.hash_nosigs();
-    wallet
-        .commit_sent(tx, BlockHeight(10000000000))
+    if req.state().is_watch_only(&wallet_name) {
+        return Err(to_forbidden(anyhow::anyhow!("wallet is watch-only")));
+    }
+    let value = request
+        .value
+        .unwrap_or_else(|| CoinValue::from_millions(1001u64));
+    if value > req.state().faucet.max_value {
+        return Err(to_badreq(anyhow::anyhow!(
+            "faucet draws are limited to {} MEL",
+            req.state().faucet.max_value
+        )));
+    }
+    let height = req
+        .state()
+        .fresh_snapshot()
         .await
-        .map_err(to_badreq)?;
-    Body::from_json(&txhash)
+        .map_err(to_badgateway)?
+        .current_header()
+        .height;
+    if let Some(wait) = req.state().record_faucet_draw(&wallet_name) {
+        let mut res = tide::Response::new(StatusCode::TooManyRequests);
+        res.insert_header("Retry-After", (wait.as_secs() + 1).to_string());
+        res.set_body(plain_text(
+            "drawing from the faucet too often; try again later",
+        ));
+        return Ok(res);
+    }
+    // a draw that fails to go out doesn't count against the wallet
+    let sent: tide::Result<TxHash> = async {
+        // the nonce comes from a persistent counter, so that no two draws of the same address are alike
+        let counter = wallet.next_faucet_counter().await?;
+        let tx = Transaction {
+            kind: TxKind::Faucet,
+            inputs: vec![],
+            outputs: vec![CoinData {
+                covhash: wallet.address(),
+                value,
+                denom: Denom::Mel,
+                additional_data: vec![],
+            }],
+            data: tmelcrypt::hash_single((wallet.address(), counter).stdcode()).to_vec(),
+            fee: CoinValue::from_millions(1001u64),
+            covenants: vec![],
+            sigs: vec![],
+        };
+        let txhash = tx.hash_nosigs();
+        if wallet.get_cached_transaction(txhash).await.is_some() {
+            return Err(tide::Error::new(
+                StatusCode::Conflict,
+                anyhow::anyhow!("faucet transaction {} was already sent", txhash),
+            ));
+        }
+        // we mark the TX as sent in this thread
+        wallet
+            .commit_sent(tx, height, height + req.state().commit_timeout)
+            .await
+            .map_err(to_badreq)?;
+        Ok(txhash)
+    }
+    .await;
+    if sent.is_err() {
+        req.state().forget_faucet_draw(&wallet_name);
+    }
+    let txhash = sent?;
+    Ok(Body::from_json(&txhash)?.into())
+}
+
+/// A plain-text body, labeled as such outright rather than by however tide converts strings, so clients going by `Content-Type` never try to parse it as JSON.
+fn plain_text(text: impl Into<String>) -> Body {
+    let mut body = Body::from_string(text.into());
+    body.set_mime(tide::http::mime::PLAIN);
+    body
+}
+
+/// Encodes a response body as CBOR for clients that accept `application/cbor`, which is much smaller for large listings, and as JSON otherwise.
+fn negotiated_body<T: Serialize>(req: &Request<Arc<AppState>>, value: &T) -> tide::Result<Body> {
+    let wants_cbor = req
+        .header("Accept")
+        .map(|accept| accept.as_str().contains("application/cbor"))
+        .unwrap_or_default();
+    if !wants_cbor {
+        return Body::from_json(value);
+    }
+    let mut bytes = vec![];
+    ciborium::ser::into_writer(value, &mut bytes)
+        .map_err(|err| tide::Error::from_str(StatusCode::InternalServerError, err.to_string()))?;
+    let mut body = Body::from_bytes(bytes);
+    body.set_mime("application/cbor".parse::<tide::http::Mime>()?);
+    Ok(body)
+}
+
+/// Parses a JSON request body, answering a malformed one with a 400 that names the field at fault, such as `outputs[1].value: invalid type: string "1", expected u128`.
+async fn body_json_fields<T: DeserializeOwned>(
+    req: &mut Request<Arc<AppState>>,
+) -> tide::Result<T> {
+    let bytes = req.body_bytes().await?;
+    json_fields(&bytes)
+}
+
+/// Parses JSON as [body_json_fields] does, for bodies read in more than one piece.
+fn json_fields<T: DeserializeOwned>(bytes: &[u8]) -> tide::Result<T> {
+    serde_path_to_error::deserialize(&mut serde_json::Deserializer::from_slice(bytes))
+        .map_err(|err| to_badreq(anyhow::anyhow!("{}", err)))
 }
 
 fn to_badreq<E: Into<anyhow::Error> + Send + 'static + Sync + Debug>(e: E) -> tide::Error {
@@ -601,7 +3671,17 @@ fn to_notfound<E: Into<anyhow::Error> + Send + 'static + Sync + Debug>(e: E) ->
     tide::Error::new(StatusCode::NotFound, e)
 }
 
+fn to_unavailable<E: Into<anyhow::Error> + Send + 'static + Sync + Debug>(e: E) -> tide::Error {
+    log::warn!("not ready: {:#?}", e);
+    tide::Error::new(StatusCode::ServiceUnavailable, e)
+}
+
 fn to_badgateway<E: Into<anyhow::Error> + Send + 'static + Sync + Debug>(e: E) -> tide::Error {
+    let e: anyhow::Error = e.into();
+    // before we ever reach the network, failing to is expected rather than a broken upstream
+    if e.downcast_ref::<StillSyncing>().is_some() {
+        return to_unavailable(e);
+    }
     log::warn!("bad upstream: {:#?}", e);
     tide::Error::new(StatusCode::BadGateway, e)
 }
@@ -0,0 +1,185 @@
+use std::{
+    fmt::Write,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use dashmap::DashMap;
+use once_cell::sync::Lazy;
+
+/// Upper bounds, in seconds, of the buckets of the `prepare_tx` latency histogram.
+const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// In-memory counters, rendered in the Prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: DashMap<String, AtomicU64>,
+    prepare_buckets: [AtomicU64; LATENCY_BUCKETS.len()],
+    prepare_count: AtomicU64,
+    prepare_sum_micros: AtomicU64,
+    send_success: AtomicU64,
+    send_failure: AtomicU64,
+}
+
+impl Metrics {
+    /// Counts a request to the given URL path.
+    pub fn record_request(&self, path: &str) {
+        self.requests
+            .entry(route_label(&ROUTES, path).to_owned())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Records how long a `prepare_tx` call took.
+    pub fn record_prepare(&self, elapsed: Duration) {
+        let secs = elapsed.as_secs_f64();
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.prepare_buckets.iter()) {
+            if secs <= *bound {
+                bucket.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.prepare_count.fetch_add(1, Ordering::Relaxed);
+        self.prepare_sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// Records the outcome of a `send_tx` call.
+    pub fn record_send(&self, success: bool) {
+        if success {
+            self.send_success.fetch_add(1, Ordering::Relaxed);
+        } else {
+            self.send_failure.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Renders all metrics, plus the given gauges, in the Prometheus text format.
+    pub fn render(&self, wallet_count: usize, pending_count: u64) -> String {
+        let mut out = String::new();
+        let _ = writeln!(out, "# TYPE melwalletd_requests_total counter");
+        let mut requests: Vec<(String, u64)> = self
+            .requests
+            .iter()
+            .map(|kv| (kv.key().clone(), kv.value().load(Ordering::Relaxed)))
+            .collect();
+        requests.sort();
+        for (route, count) in requests {
+            let _ = writeln!(
+                out,
+                "melwalletd_requests_total{{route=\"{}\"}} {}",
+                route, count
+            );
+        }
+
+        let _ = writeln!(out, "# TYPE melwalletd_wallets gauge");
+        let _ = writeln!(out, "melwalletd_wallets {}", wallet_count);
+        let _ = writeln!(out, "# TYPE melwalletd_pending_transactions gauge");
+        let _ = writeln!(out, "melwalletd_pending_transactions {}", pending_count);
+
+        let _ = writeln!(out, "# TYPE melwalletd_prepare_tx_seconds histogram");
+        for (bound, bucket) in LATENCY_BUCKETS.iter().zip(self.prepare_buckets.iter()) {
+            let _ = writeln!(
+                out,
+                "melwalletd_prepare_tx_seconds_bucket{{le=\"{}\"}} {}",
+                bound,
+                bucket.load(Ordering::Relaxed)
+            );
+        }
+        let count = self.prepare_count.load(Ordering::Relaxed);
+        let _ = writeln!(
+            out,
+            "melwalletd_prepare_tx_seconds_bucket{{le=\"+Inf\"}} {}",
+            count
+        );
+        let _ = writeln!(
+            out,
+            "melwalletd_prepare_tx_seconds_sum {}",
+            self.prepare_sum_micros.load(Ordering::Relaxed) as f64 / 1_000_000.0
+        );
+        let _ = writeln!(out, "melwalletd_prepare_tx_seconds_count {}", count);
+
+        let _ = writeln!(out, "# TYPE melwalletd_send_tx_total counter");
+        let _ = writeln!(
+            out,
+            "melwalletd_send_tx_total{{result=\"success\"}} {}",
+            self.send_success.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "melwalletd_send_tx_total{{result=\"failure\"}} {}",
+            self.send_failure.load(Ordering::Relaxed)
+        );
+        out
+    }
+}
+
+/// Patterns of every route, such as `/wallets/:name/coins`, taken from the OpenAPI description, which lists exactly the registered routes.
+static ROUTES: Lazy<Vec<String>> = Lazy::new(|| {
+    crate::openapi::spec()["paths"]
+        .as_object()
+        .map(|paths| {
+            paths
+                .keys()
+                .map(|path| path.replace('{', ":").replace('}', ""))
+                .collect()
+        })
+        .unwrap_or_default()
+});
+
+/// Label of requests matching no route, so that stray paths all share one series.
+const UNMATCHED_ROUTE: &str = "other";
+
+/// Finds the route pattern a URL path falls under, so that wallet names, hashes and stray paths don't each get their own series. Where several patterns match, the one with the most fixed segments wins, as it does in the router.
+fn route_label<'a>(routes: &'a [String], path: &str) -> &'a str {
+    let segments: Vec<&str> = path.split('/').collect();
+    routes
+        .iter()
+        .filter(|route| {
+            let pattern: Vec<&str> = route.split('/').collect();
+            pattern.len() == segments.len()
+                && pattern
+                    .iter()
+                    .zip(segments.iter())
+                    .all(|(p, s)| p == s || (p.starts_with(':') && !s.is_empty()))
+        })
+        .max_by_key(|route| route.split('/').filter(|p| !p.starts_with(':')).count())
+        .map(|route| route.as_str())
+        .unwrap_or(UNMATCHED_ROUTE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn route_labels() {
+        assert_eq!(route_label(&ROUTES, "/wallets"), "/wallets");
+        assert_eq!(
+            route_label(&ROUTES, "/wallets/alice/coins"),
+            "/wallets/:name/coins"
+        );
+        assert_eq!(
+            route_label(&ROUTES, "/wallets/alice/transactions/abcd/balance"),
+            "/wallets/:name/transactions/:txhash/balance"
+        );
+        assert_eq!(route_label(&ROUTES, "/pools/MEL:SYM"), "/pools/:pair");
+        assert_eq!(
+            route_label(&ROUTES, "/address/t1abc/used"),
+            "/address/:address/used"
+        );
+        assert_eq!(
+            route_label(&ROUTES, "/wallets/alice/templates/pay/prepare"),
+            "/wallets/:name/templates/:tpl/prepare"
+        );
+        assert_eq!(route_label(&ROUTES, "/nonsense/1234"), UNMATCHED_ROUTE);
+        assert_eq!(route_label(&ROUTES, "/wallets/"), UNMATCHED_ROUTE);
+    }
+
+    #[test]
+    fn fixed_segments_win() {
+        let routes = vec!["/a/:x".to_owned(), "/a/b".to_owned()];
+        assert_eq!(route_label(&routes, "/a/b"), "/a/b");
+        assert_eq!(route_label(&routes, "/a/c"), "/a/:x");
+    }
+}
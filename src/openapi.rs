@@ -0,0 +1,47 @@
+/// The OpenAPI description of the API. It is maintained by hand, next to the routes registered in `main.rs`.
+const SPEC: &str = include_str!("openapi.json");
+
+/// Returns the OpenAPI description of the API, stamped with the version of this build.
+pub fn spec() -> serde_json::Value {
+    let mut spec: serde_json::Value = serde_json::from_str(SPEC).expect("malformed openapi.json");
+    spec["info"]["version"] = env!("CARGO_PKG_VERSION").into();
+    spec
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use regex::Regex;
+
+    use super::*;
+
+    #[test]
+    fn describes_every_route() {
+        let route = Regex::new(r#"app\.at\("([^"]+)"\)\s*\.(get|post|put|delete)\("#).unwrap();
+        let param = Regex::new(r":(\w+)").unwrap();
+        let registered: BTreeSet<(String, String)> = route
+            .captures_iter(include_str!("main.rs"))
+            .map(|caps| {
+                (
+                    param.replace_all(&caps[1], "{$1}").into_owned(),
+                    caps[2].to_owned(),
+                )
+            })
+            .collect();
+        let spec = spec();
+        let described: BTreeSet<(String, String)> = spec["paths"]
+            .as_object()
+            .unwrap()
+            .iter()
+            .flat_map(|(path, ops)| {
+                ops.as_object()
+                    .unwrap()
+                    .keys()
+                    .map(move |method| (path.clone(), method.clone()))
+            })
+            .collect();
+        assert!(!registered.is_empty());
+        assert_eq!(registered, described);
+    }
+}
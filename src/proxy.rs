@@ -0,0 +1,134 @@
+use std::net::{IpAddr, SocketAddr};
+
+use anyhow::Context;
+use smol::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Opens a connection to `target` through the SOCKS5 proxy at `proxy`, which must not require authentication (Tor's SOCKS port doesn't).
+pub async fn socks5_connect(proxy: SocketAddr, target: SocketAddr) -> anyhow::Result<TcpStream> {
+    let mut stream = TcpStream::connect(proxy)
+        .await
+        .with_context(|| format!("cannot reach proxy {}", proxy))?;
+
+    // offer only the "no authentication" method
+    stream.write_all(&[5, 1, 0]).await?;
+    let mut choice = [0u8; 2];
+    stream.read_exact(&mut choice).await?;
+    if choice != [5, 0] {
+        anyhow::bail!(
+            "{} is not a SOCKS5 proxy that allows unauthenticated use",
+            proxy
+        );
+    }
+
+    let mut request = vec![5, 1, 0];
+    match target.ip() {
+        IpAddr::V4(ip) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        IpAddr::V6(ip) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+    }
+    request.extend_from_slice(&target.port().to_be_bytes());
+    stream.write_all(&request).await?;
+
+    let mut reply = [0u8; 4];
+    stream.read_exact(&mut reply).await?;
+    if reply[1] != 0 {
+        anyhow::bail!(
+            "proxy {} refused to connect to {} (SOCKS5 reply {})",
+            proxy,
+            target,
+            reply[1]
+        );
+    }
+    // skip the address the proxy bound, which we have no use for
+    let bound_len = match reply[3] {
+        1 => 4,
+        4 => 16,
+        3 => {
+            let mut len = [0u8; 1];
+            stream.read_exact(&mut len).await?;
+            len[0] as usize
+        }
+        other => anyhow::bail!("proxy {} sent an unknown address type {}", proxy, other),
+    };
+    let mut bound = vec![0u8; bound_len + 2];
+    stream.read_exact(&mut bound).await?;
+    Ok(stream)
+}
+
+/// Listens on a local port, forwarding every connection to `target` through the SOCKS5 proxy at `proxy`. Returns the local address to connect to instead of `target`, along with the task doing the forwarding.
+pub async fn forward_through(
+    proxy: SocketAddr,
+    target: SocketAddr,
+) -> anyhow::Result<(SocketAddr, smol::Task<()>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let local = listener.local_addr()?;
+    let task = smolscale::spawn(async move {
+        loop {
+            let inbound = match listener.accept().await {
+                Ok((inbound, _)) => inbound,
+                Err(err) => {
+                    log::warn!("cannot accept connection to forward: {:?}", err);
+                    continue;
+                }
+            };
+            smolscale::spawn(async move {
+                let outbound = match socks5_connect(proxy, target).await {
+                    Ok(outbound) => outbound,
+                    Err(err) => {
+                        log::warn!("cannot connect to {} through the proxy: {:?}", target, err);
+                        return;
+                    }
+                };
+                // the connection is over once either side is done
+                smol::future::race(
+                    smol::io::copy(inbound.clone(), outbound.clone()),
+                    smol::io::copy(outbound, inbound),
+                )
+                .await
+                .ok();
+            })
+            .detach();
+        }
+    });
+    Ok((local, task))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speaks_socks5() {
+        smol::block_on(async {
+            let proxy = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy_addr = proxy.local_addr().unwrap();
+            let target: SocketAddr = "10.1.2.3:11814".parse().unwrap();
+            let server = smol::spawn(async move {
+                let (mut conn, _) = proxy.accept().await.unwrap();
+                let mut greeting = [0u8; 3];
+                conn.read_exact(&mut greeting).await.unwrap();
+                assert_eq!(greeting, [5, 1, 0]);
+                conn.write_all(&[5, 0]).await.unwrap();
+                let mut request = [0u8; 10];
+                conn.read_exact(&mut request).await.unwrap();
+                assert_eq!(request, [5, 1, 0, 1, 10, 1, 2, 3, 0x2e, 0x26]);
+                conn.write_all(&[5, 0, 0, 1, 0, 0, 0, 0, 0, 0, b'!'])
+                    .await
+                    .unwrap();
+            });
+            let mut stream = socks5_connect(proxy_addr, target).await.unwrap();
+            let mut payload = [0u8; 1];
+            stream.read_exact(&mut payload).await.unwrap();
+            assert_eq!(&payload, b"!");
+            server.await;
+        });
+    }
+}
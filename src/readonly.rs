@@ -0,0 +1,73 @@
+use tide::{http::Method, Middleware, Next, Request, StatusCode};
+
+/// Routes that take a body but change nothing, so they stay available to a read-only daemon.
+const HARMLESS_POSTS: [&str; 6] = [
+    "/shutdown",
+    "/reload-config",
+    "/pool_info",
+    "/swap-route",
+    "/estimate-confirmation",
+    "/decode-tx",
+];
+
+/// Pool routes, under `/pools/:pair/`, that take a body but change nothing.
+const HARMLESS_POOL_POSTS: [&str; 1] = ["simulate"];
+
+/// Wallet routes, under `/wallets/:name/`, that take a body but change nothing.
+const HARMLESS_WALLET_POSTS: [&str; 3] = ["dump", "estimate-fee", "preview-tx"];
+
+/// Middleware refusing every request that could change wallet data, for daemons serving another daemon's wallet directory.
+pub struct RefuseWrites;
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RefuseWrites {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        if !changes_nothing(req.method(), req.url().path()) {
+            let mut res = tide::Response::new(StatusCode::Forbidden);
+            res.set_body("daemon is read-only");
+            return Ok(res);
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+fn changes_nothing(method: Method, path: &str) -> bool {
+    match method {
+        Method::Get | Method::Head | Method::Options => true,
+        Method::Post => {
+            HARMLESS_POSTS.contains(&path)
+                || path
+                    .strip_prefix("/wallets/")
+                    .and_then(|rest| rest.split_once('/'))
+                    .is_some_and(|(_, route)| HARMLESS_WALLET_POSTS.contains(&route))
+                || path
+                    .strip_prefix("/pools/")
+                    .and_then(|rest| rest.split_once('/'))
+                    .is_some_and(|(_, route)| HARMLESS_POOL_POSTS.contains(&route))
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_only_writes() {
+        assert!(changes_nothing(Method::Get, "/wallets/alice/coins"));
+        assert!(changes_nothing(Method::Post, "/pool_info"));
+        assert!(changes_nothing(Method::Post, "/decode-tx"));
+        assert!(changes_nothing(Method::Post, "/wallets/alice/estimate-fee"));
+        assert!(changes_nothing(Method::Post, "/wallets/alice/preview-tx"));
+        assert!(changes_nothing(Method::Post, "/wallets/alice/dump"));
+        assert!(changes_nothing(Method::Post, "/pools/MEL:SYM/simulate"));
+        assert!(!changes_nothing(Method::Post, "/wallets/alice/prepare-tx"));
+        assert!(!changes_nothing(Method::Put, "/wallets/alice"));
+        assert!(!changes_nothing(Method::Delete, "/wallets/alice"));
+        assert!(!changes_nothing(
+            Method::Post,
+            "/wallets/alice/transactions/abc/estimate-fee"
+        ));
+    }
+}
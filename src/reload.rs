@@ -0,0 +1,168 @@
+use std::{sync::Arc, time::Duration};
+
+use http_types::headers::HeaderValue;
+use parking_lot::{Mutex, RwLock};
+use themelio_structs::BlockHeight;
+use tide::{security::CorsMiddleware, Middleware, Next, Request};
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+use crate::{cli::Config, state::AppState, state::ConfirmSettings};
+
+/// Fields of the configuration that take effect without a restart.
+const RELOADABLE_FIELDS: [&str; 9] = [
+    "allowed_origins",
+    "allowed_methods",
+    "allowed_headers",
+    "allow_credentials",
+    "retransmit_window_secs",
+    "confirm_interval_ms",
+    "confirm_concurrency",
+    "auto_bump_after_blocks",
+    "log_level",
+];
+
+/// Handle for swapping out the log filter of the running daemon.
+pub type LogFilterHandle = reload::Handle<EnvFilter, Registry>;
+
+/// The log filter used when the configuration doesn't set one.
+pub fn default_log_filter() -> String {
+    std::env::var("RUST_LOG").unwrap_or_else(|_| "melwalletd=debug,warn".into())
+}
+
+pub fn generate_cors(
+    origins: Vec<String>,
+    methods: &str,
+    headers: Option<&str>,
+    credentials: bool,
+) -> anyhow::Result<CorsMiddleware> {
+    let mut cors = origins
+        .iter()
+        .fold(CorsMiddleware::new(), |cors, val| {
+            let s: &str = val;
+            cors.allow_origin(s)
+        })
+        .allow_methods(
+            methods
+                .parse::<HeaderValue>()
+                .map_err(|e| anyhow::anyhow!("invalid allowed_methods: {}", e))?,
+        )
+        .allow_credentials(credentials);
+    if let Some(headers) = headers {
+        cors = cors.allow_headers(
+            headers
+                .parse::<HeaderValue>()
+                .map_err(|e| anyhow::anyhow!("invalid allowed_headers: {}", e))?,
+        );
+    }
+
+    Ok(cors)
+}
+
+/// Settings of the running daemon that can change without a restart, along with the configuration they came from.
+pub struct Reloader {
+    config_path: Option<String>,
+    current: Mutex<Config>,
+    cors: RwLock<CorsMiddleware>,
+    log_filter: LogFilterHandle,
+    confirm_settings: Arc<RwLock<ConfirmSettings>>,
+}
+
+impl Reloader {
+    /// Creates a reloader for a daemon configured with `config`, read from `config_path` if it came from a file.
+    pub fn new(
+        config_path: Option<String>,
+        config: Config,
+        log_filter: LogFilterHandle,
+        confirm_settings: Arc<RwLock<ConfirmSettings>>,
+    ) -> anyhow::Result<Self> {
+        let cors = cors_of(&config)?;
+        let reloader = Self {
+            config_path,
+            current: Mutex::new(config),
+            cors: RwLock::new(cors),
+            log_filter,
+            confirm_settings,
+        };
+        reloader.apply_log_level(&reloader.current.lock())?;
+        Ok(reloader)
+    }
+
+    /// Re-reads the configuration file and applies whatever changed, returning the names of the changed fields. Applies nothing if a field that needs a restart changed.
+    pub fn reload(&self) -> anyhow::Result<Vec<String>> {
+        let path = self
+            .config_path
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("daemon was not started with a config file"))?;
+        let new = Config::load(path)?;
+        let mut current = self.current.lock();
+        let old_fields = serde_json::to_value(&*current)?;
+        let new_fields = serde_json::to_value(&new)?;
+        let changed: Vec<String> = new_fields
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(field, value)| old_fields.get(field.as_str()) != Some(*value))
+            .map(|(field, _)| field.clone())
+            .collect();
+        let needs_restart: Vec<&str> = changed
+            .iter()
+            .map(|field| field.as_str())
+            .filter(|field| !RELOADABLE_FIELDS.contains(field))
+            .collect();
+        if !needs_restart.is_empty() {
+            anyhow::bail!("changing {} requires a restart", needs_restart.join(", "));
+        }
+
+        // build everything before applying anything, so that a bad value leaves the old settings alone
+        let cors = cors_of(&new)?;
+        self.apply_log_level(&new)?;
+        *self.cors.write() = cors;
+        *self.confirm_settings.write() = confirm_settings_of(&new);
+        *current = new;
+        Ok(changed)
+    }
+
+    fn apply_log_level(&self, config: &Config) -> anyhow::Result<()> {
+        let directives = config.log_level.clone().unwrap_or_else(default_log_filter);
+        let filter = EnvFilter::try_new(&directives)
+            .map_err(|e| anyhow::anyhow!("invalid log_level: {}", e))?;
+        self.log_filter.reload(filter)?;
+        // the `log` crate caches the most verbose level at startup; the filter itself now decides
+        log::set_max_level(log::LevelFilter::Trace);
+        Ok(())
+    }
+}
+
+fn cors_of(config: &Config) -> anyhow::Result<CorsMiddleware> {
+    generate_cors(
+        config.allowed_origins.clone(),
+        &config.allowed_methods,
+        config.allowed_headers.as_deref(),
+        config.allow_credentials,
+    )
+}
+
+/// The pacing of the confirmation task given by a configuration.
+pub fn confirm_settings_of(config: &Config) -> ConfirmSettings {
+    ConfirmSettings {
+        retransmit_window: Duration::from_secs(config.retransmit_window_secs),
+        interval: Duration::from_millis(config.confirm_interval_ms),
+        concurrency: config.confirm_concurrency,
+        auto_bump_after: BlockHeight(config.auto_bump_after_blocks),
+    }
+}
+
+/// Middleware applying the current CORS settings, which may be reloaded at runtime.
+pub struct ReloadableCors;
+
+#[tide::utils::async_trait]
+impl Middleware<Arc<AppState>> for ReloadableCors {
+    async fn handle(
+        &self,
+        req: Request<Arc<AppState>>,
+        next: Next<'_, Arc<AppState>>,
+    ) -> tide::Result {
+        let cors = req.state().reloader.cors.read().clone();
+        cors.handle(req, next).await
+    }
+}
@@ -0,0 +1,92 @@
+use std::{future::Future, time::Duration};
+
+use melnet::MelnetError;
+
+/// How long to wait before the first retry. Every later retry waits twice as long as the one before.
+const INITIAL_BACKOFF: Duration = Duration::from_millis(200);
+
+/// How persistently calls to the full node are retried.
+#[derive(Clone, Copy, Debug)]
+pub struct RetryPolicy {
+    /// Most times a call is attempted, including the first.
+    pub max_attempts: u32,
+}
+
+impl RetryPolicy {
+    /// Makes a call to the full node, retrying with exponential backoff as long as it fails in ways that a retry might fix.
+    pub async fn run<T, Fut: Future<Output = melnet::Result<T>>>(
+        &self,
+        what: &str,
+        mut call: impl FnMut() -> Fut,
+    ) -> melnet::Result<T> {
+        let mut backoff = INITIAL_BACKOFF;
+        let mut attempt = 1;
+        loop {
+            match call().await {
+                Err(err) if attempt < self.max_attempts && is_transient(&err) => {
+                    log::debug!(
+                        "{} failed on attempt {} of {}, retrying in {:?}: {}",
+                        what,
+                        attempt,
+                        self.max_attempts,
+                        backoff,
+                        err
+                    );
+                    smol::Timer::after(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+                res => return res,
+            }
+        }
+    }
+}
+
+/// Whether an error from the full node might go away by itself, unlike e.g. the node rejecting a transaction.
+fn is_transient(err: &MelnetError) -> bool {
+    matches!(
+        err,
+        MelnetError::Network(_) | MelnetError::InternalServerError
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+
+    use super::*;
+
+    #[test]
+    fn retries_only_transient_errors() {
+        let policy = RetryPolicy { max_attempts: 3 };
+        let attempts = Cell::new(0);
+        let res: melnet::Result<()> = smol::block_on(policy.run("test call", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(MelnetError::InternalServerError) }
+        }));
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 3);
+
+        attempts.set(0);
+        let res: melnet::Result<()> = smol::block_on(policy.run("test call", || {
+            attempts.set(attempts.get() + 1);
+            async { Err(MelnetError::Custom("transaction rejected".into())) }
+        }));
+        assert!(res.is_err());
+        assert_eq!(attempts.get(), 1);
+
+        attempts.set(0);
+        let res = smol::block_on(policy.run("test call", || {
+            attempts.set(attempts.get() + 1);
+            let attempt = attempts.get();
+            async move {
+                if attempt < 2 {
+                    Err(MelnetError::InternalServerError)
+                } else {
+                    Ok(attempt)
+                }
+            }
+        }));
+        assert_eq!(res.unwrap(), 2);
+    }
+}
@@ -4,6 +4,8 @@ use acidjson::AcidJson;
 use serde::{Deserialize, Serialize};
 use tmelcrypt::Ed25519SK;
 
+use crate::signer::{ExternalSigner, ThresholdSigner};
+
 /// Represents a whole directory of persistent secrets, some of which may be unlocked
 pub struct SecretStore {
     /// Maps wallet name to secret.
@@ -22,6 +24,13 @@ impl SecretStore {
         })
     }
 
+    /// Opens an existing secretstore, creating nothing.
+    pub fn open_read_only(path: &Path) -> anyhow::Result<Self> {
+        Ok(Self {
+            secrets: AcidJson::open(path)?,
+        })
+    }
+
     /// Stores a new PersistentSecret into the SecretStore.
     pub fn store(&self, name: String, secret: PersistentSecret) {
         self.secrets.write().insert(name, secret);
@@ -31,13 +40,28 @@ impl SecretStore {
     pub fn load(&self, name: &str) -> Option<PersistentSecret> {
         self.secrets.read().get(name).cloned()
     }
+
+    /// Removes a PersistentSecret from the SecretStore.
+    pub fn remove(&self, name: &str) {
+        self.secrets.write().remove(name);
+    }
+
+    /// Moves a PersistentSecret to a new name, if there is one under the old name.
+    pub fn rename(&self, name: &str, new_name: &str) {
+        let mut secrets = self.secrets.write();
+        if let Some(secret) = secrets.remove(name) {
+            secrets.insert(new_name.to_owned(), secret);
+        }
+    }
 }
 
-/// A persistent signing secret (right now, either a plaintext secret key or a password-protected secret key)
+/// A persistent signing secret (right now, either a plaintext secret key, a password-protected secret key, the public parameters of a multisignature, or where to reach an external signer)
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum PersistentSecret {
     Plaintext(Ed25519SK),
     PasswordEncrypted(EncryptedSK),
+    Threshold(ThresholdSigner),
+    External(ExternalSigner),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -0,0 +1,220 @@
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use themelio_structs::{CoinData, CoinID, CoinValue};
+
+/// Most subsets branch-and-bound looks at before settling for the best it has found, or giving up.
+const BRANCH_AND_BOUND_TRIES: usize = 100_000;
+
+/// Picks which coins of a single denomination pay for a transaction.
+pub trait CoinSelector: Send + Sync {
+    /// Picks coins out of `candidates` adding up to at least `target`, or as close as they can get if they aren't enough.
+    fn select(&self, candidates: &[(CoinID, CoinData)], target: CoinValue) -> Vec<CoinID>;
+}
+
+/// Spends the smallest coins first. This merges dust as a side effect, at the cost of more inputs, and so higher fees, now.
+pub struct SmallestFirst;
+
+impl CoinSelector for SmallestFirst {
+    fn select(&self, candidates: &[(CoinID, CoinData)], target: CoinValue) -> Vec<CoinID> {
+        let mut sorted = candidates.to_vec();
+        sorted.sort_by_key(|(_, data)| data.value.0);
+        take_until(sorted, target)
+    }
+}
+
+/// Spends the largest coins first, using as few inputs, and so paying as little in fees, as possible. Small coins pile up over time.
+pub struct LargestFirst;
+
+impl CoinSelector for LargestFirst {
+    fn select(&self, candidates: &[(CoinID, CoinData)], target: CoinValue) -> Vec<CoinID> {
+        let mut sorted = candidates.to_vec();
+        sorted.sort_by_key(|(_, data)| std::cmp::Reverse(data.value.0));
+        take_until(sorted, target)
+    }
+}
+
+/// Searches for the coins that overshoot the target the least, leaving as little change as possible. An exact match leaves no change output at all, which also hides which output is the payment. Falls back to largest-first when the search turns up nothing in time.
+pub struct BranchAndBound;
+
+impl CoinSelector for BranchAndBound {
+    fn select(&self, candidates: &[(CoinID, CoinData)], target: CoinValue) -> Vec<CoinID> {
+        let mut sorted = candidates.to_vec();
+        sorted.sort_by_key(|(_, data)| std::cmp::Reverse(data.value.0));
+        let values: Vec<u128> = sorted.iter().map(|(_, data)| data.value.0).collect();
+        // what all the coins from each position onwards add up to, for pruning branches that can't reach the target
+        let mut remaining = vec![0u128; values.len() + 1];
+        for idx in (0..values.len()).rev() {
+            remaining[idx] = remaining[idx + 1] + values[idx];
+        }
+        let mut search = Search {
+            values: &values,
+            remaining: &remaining,
+            target: target.0,
+            chosen: vec![],
+            best: None,
+            tries: 0,
+        };
+        search.explore(0, 0);
+        match search.best {
+            Some((_, best)) => best.into_iter().map(|idx| sorted[idx].0).collect(),
+            None => LargestFirst.select(candidates, target),
+        }
+    }
+}
+
+/// The state of a branch-and-bound search, over coins sorted from largest to smallest.
+struct Search<'a> {
+    values: &'a [u128],
+    remaining: &'a [u128],
+    target: u128,
+    chosen: Vec<usize>,
+    /// The least excess found so far, along with the coins giving it.
+    best: Option<(u128, Vec<usize>)>,
+    tries: usize,
+}
+
+impl Search<'_> {
+    fn explore(&mut self, idx: usize, sum: u128) {
+        self.tries += 1;
+        if self.tries > BRANCH_AND_BOUND_TRIES || self.best.as_ref().map(|b| b.0) == Some(0) {
+            return;
+        }
+        if sum >= self.target {
+            let excess = sum - self.target;
+            if self.best.as_ref().map(|b| excess < b.0).unwrap_or(true) {
+                self.best = Some((excess, self.chosen.clone()));
+            }
+            return;
+        }
+        if idx == self.values.len() || sum + self.remaining[idx] < self.target {
+            return;
+        }
+        self.chosen.push(idx);
+        self.explore(idx + 1, sum + self.values[idx]);
+        self.chosen.pop();
+        self.explore(idx + 1, sum);
+    }
+}
+
+/// Takes coins in order until they reach the target.
+fn take_until(sorted: Vec<(CoinID, CoinData)>, target: CoinValue) -> Vec<CoinID> {
+    let mut sum = CoinValue(0);
+    let mut taken = vec![];
+    for (coin, data) in sorted {
+        if sum >= target {
+            break;
+        }
+        sum += data.value;
+        taken.push(coin);
+    }
+    taken
+}
+
+/// The coin selection strategies that can be configured, or asked for by a request.
+#[derive(Serialize, Deserialize, Default, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum CoinSelection {
+    SmallestFirst,
+    #[default]
+    LargestFirst,
+    BranchAndBound,
+}
+
+impl CoinSelection {
+    /// The selector implementing this strategy.
+    pub fn selector(self) -> &'static dyn CoinSelector {
+        match self {
+            CoinSelection::SmallestFirst => &SmallestFirst,
+            CoinSelection::LargestFirst => &LargestFirst,
+            CoinSelection::BranchAndBound => &BranchAndBound,
+        }
+    }
+}
+
+impl FromStr for CoinSelection {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "smallest-first" => Ok(CoinSelection::SmallestFirst),
+            "largest-first" => Ok(CoinSelection::LargestFirst),
+            "branch-and-bound" => Ok(CoinSelection::BranchAndBound),
+            other => anyhow::bail!(
+                "unknown coin selection {:?}; expected smallest-first, largest-first, or branch-and-bound",
+                other
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use themelio_structs::{Address, Denom, TxHash};
+
+    use super::*;
+
+    fn coins(values: &[u128]) -> Vec<(CoinID, CoinData)> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(idx, value)| {
+                (
+                    CoinID {
+                        txhash: TxHash(tmelcrypt::hash_single([idx as u8])),
+                        index: 0,
+                    },
+                    CoinData {
+                        covhash: Address(Default::default()),
+                        value: CoinValue(*value),
+                        denom: Denom::Mel,
+                        additional_data: vec![],
+                    },
+                )
+            })
+            .collect()
+    }
+
+    fn values_of(candidates: &[(CoinID, CoinData)], picked: Vec<CoinID>) -> Vec<u128> {
+        let mut values: Vec<u128> = picked
+            .into_iter()
+            .map(|coin| {
+                candidates
+                    .iter()
+                    .find(|(id, _)| *id == coin)
+                    .unwrap()
+                    .1
+                    .value
+                    .0
+            })
+            .collect();
+        values.sort_unstable();
+        values
+    }
+
+    #[test]
+    fn strategies_pick_differently() {
+        let candidates = coins(&[1, 2, 5, 40, 100]);
+        let pick = |selection: CoinSelection| {
+            values_of(
+                &candidates,
+                selection.selector().select(&candidates, CoinValue(45)),
+            )
+        };
+        assert_eq!(pick(CoinSelection::SmallestFirst), vec![1, 2, 5, 40]);
+        assert_eq!(pick(CoinSelection::LargestFirst), vec![100]);
+        assert_eq!(pick(CoinSelection::BranchAndBound), vec![5, 40]);
+        // not enough coins: everything is spent, and balancing reports the shortfall
+        assert_eq!(
+            values_of(
+                &candidates,
+                BranchAndBound.select(&candidates, CoinValue(1000))
+            ),
+            vec![1, 2, 5, 40, 100]
+        );
+        assert_eq!(
+            "branch-and-bound".parse::<CoinSelection>().unwrap(),
+            CoinSelection::BranchAndBound
+        );
+    }
+}
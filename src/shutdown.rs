@@ -0,0 +1,66 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use smol::channel::{Receiver, Sender};
+use tide::{Middleware, Next, Request};
+
+/// Coordinates shutting the daemon down cleanly, whether asked to by a signal or over the API.
+pub struct Shutdown {
+    inflight: AtomicUsize,
+    send_trigger: Sender<()>,
+    recv_trigger: Receiver<()>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        let (send_trigger, recv_trigger) = smol::channel::bounded(1);
+        Self {
+            inflight: AtomicUsize::new(0),
+            send_trigger,
+            recv_trigger,
+        }
+    }
+}
+
+impl Shutdown {
+    /// Asks the daemon to shut down. Asking more than once does nothing more.
+    pub fn trigger(&self) {
+        let _ = self.send_trigger.try_send(());
+    }
+
+    /// Waits until the daemon is asked to shut down.
+    pub async fn wait(&self) {
+        let _ = self.recv_trigger.recv().await;
+    }
+
+    /// Waits for every in-flight request to finish, giving up after the timeout. Returns whether all of them finished.
+    pub async fn drain(&self, timeout: Duration) -> bool {
+        let start = Instant::now();
+        while self.inflight.load(Ordering::SeqCst) > 0 {
+            if start.elapsed() > timeout {
+                return false;
+            }
+            smol::Timer::after(Duration::from_millis(50)).await;
+        }
+        true
+    }
+}
+
+/// Middleware counting in-flight requests, so that shutdown can wait for them.
+pub struct TrackRequests(pub Arc<Shutdown>);
+
+#[tide::utils::async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for TrackRequests {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        self.0.inflight.fetch_add(1, Ordering::SeqCst);
+        scopeguard::defer!({
+            self.0.inflight.fetch_sub(1, Ordering::SeqCst);
+        });
+        Ok(next.run(req).await)
+    }
+}
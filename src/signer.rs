@@ -1,9 +1,13 @@
-use std::cell::RefCell;
+use std::{cell::RefCell, time::Duration};
 
+use anyhow::Context;
 use lru::LruCache;
-use themelio_stf::melvm::Covenant;
+use serde::{Deserialize, Serialize};
+use smol_timeout::TimeoutExt;
+use stdcode::StdcodeSerializeExt;
+use themelio_stf::melvm::{opcode::OpCode, Covenant};
 use themelio_structs::{Transaction, TxHash};
-use tmelcrypt::Ed25519SK;
+use tmelcrypt::{Ed25519PK, Ed25519SK};
 
 /// This trait is implemented by anything "secret key-like" that can sign a transaction. This includes secret keys, password-encumbered secret keys,
 pub trait Signer: Send + Sync + 'static {
@@ -38,3 +42,284 @@ impl Signer for Ed25519SK {
         Covenant::std_ed25519_pk_new(self.to_public())
     }
 }
+
+// heap addresses that melvm populates before running a covenant
+const HADDR_SPENDER_TX: u16 = 0;
+const HADDR_SPENDER_TXHASH: u16 = 1;
+
+/// A `threshold`-of-n multisignature over ed25519 keys. The kth signature slot of a transaction holds the signature by the kth key, so every input is covered by the same set of signatures.
+///
+/// This holds no secrets: signing only lays out placeholder signature slots, which the parties fill in later with [ThresholdSigner::add_signature]. The placeholders are signature-sized, so that fees computed before the real signatures arrive still cover them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ThresholdSigner {
+    pub public_keys: Vec<Ed25519PK>,
+    pub threshold: usize,
+}
+
+impl ThresholdSigner {
+    /// Creates a new threshold signer, checking that the threshold is attainable.
+    pub fn new(public_keys: Vec<Ed25519PK>, threshold: usize) -> anyhow::Result<Self> {
+        if threshold == 0 || threshold > public_keys.len() {
+            anyhow::bail!(
+                "threshold must be between 1 and the number of keys ({})",
+                public_keys.len()
+            )
+        }
+        Ok(Self {
+            public_keys,
+            threshold,
+        })
+    }
+
+    /// Puts the signature by the `key_idx`th key into its slot, checking it against the transaction.
+    pub fn add_signature(
+        &self,
+        mut txn: Transaction,
+        key_idx: usize,
+        signature: Vec<u8>,
+    ) -> anyhow::Result<Transaction> {
+        let pk = self
+            .public_keys
+            .get(key_idx)
+            .context("no public key at that index")?;
+        if !pk.verify(&txn.hash_nosigs().0, &signature) {
+            anyhow::bail!("signature does not match key {}", key_idx)
+        }
+        txn = self.sign_tx(txn, 0)?;
+        txn.sigs[key_idx] = signature;
+        Ok(txn)
+    }
+
+    /// Counts the valid signatures on a transaction.
+    pub fn count_signatures(&self, txn: &Transaction) -> usize {
+        let h = txn.hash_nosigs();
+        self.public_keys
+            .iter()
+            .zip(txn.sigs.iter())
+            .filter(|(pk, sig)| pk.verify(&h.0, sig))
+            .count()
+    }
+}
+
+impl Signer for ThresholdSigner {
+    fn sign_tx(&self, mut txn: Transaction, _input_idx: usize) -> anyhow::Result<Transaction> {
+        while txn.sigs.len() < self.public_keys.len() {
+            txn.sigs.push(vec![0; 64]);
+        }
+        Ok(txn)
+    }
+
+    fn covenant(&self) -> Covenant {
+        let mut ops = vec![];
+        for (idx, pk) in self.public_keys.iter().enumerate() {
+            ops.extend_from_slice(&[
+                OpCode::PushI((idx as u32).into()),
+                OpCode::PushI(6u32.into()),
+                OpCode::LoadImm(HADDR_SPENDER_TX),
+                OpCode::VRef,
+                OpCode::VRef,
+                OpCode::PushB(pk.0.to_vec()),
+                OpCode::LoadImm(HADDR_SPENDER_TXHASH),
+                OpCode::SigEOk(32),
+            ]);
+            if idx > 0 {
+                ops.push(OpCode::Add);
+            }
+        }
+        // (threshold - 1) < number of good signatures
+        ops.push(OpCode::PushI((self.threshold as u32 - 1).into()));
+        ops.push(OpCode::Lt);
+        Covenant::from_ops(&ops).expect("could not create a threshold signature covenant")
+    }
+}
+
+/// How long to wait for an external signer, which may be waiting on a person to confirm on their device.
+const EXTERNAL_SIGNING_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// An ed25519 key held outside the daemon, such as on a hardware wallet, which signs by answering HTTP callbacks.
+///
+/// Like [ThresholdSigner], signing through the [Signer] trait only lays out placeholder signatures, so that fees come out right. The real signatures come from [ExternalSigner::sign_remotely].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ExternalSigner {
+    pub public_key: Ed25519PK,
+    /// Where to POST `{"index", "tx", "txhash"}` for every input; the answer must be `{"signature"}`, all hex-encoded.
+    pub callback_url: String,
+}
+
+impl ExternalSigner {
+    /// Creates a new external signer, checking that the callback URL is one we can reach.
+    pub fn new(public_key: Ed25519PK, callback_url: String) -> anyhow::Result<Self> {
+        let url = http_types::Url::parse(&callback_url).context("invalid callback URL")?;
+        if url.scheme() != "http" || url.host_str().is_none() {
+            anyhow::bail!("callback URL must be an http:// URL")
+        }
+        Ok(Self {
+            public_key,
+            callback_url,
+        })
+    }
+
+    /// Asks the external signer for the signature of every input, checking each against the public key.
+    pub async fn sign_remotely(&self, mut txn: Transaction) -> anyhow::Result<Transaction> {
+        let txhash = txn.hash_nosigs();
+        for idx in 0..txn.inputs.len() {
+            let signature = self
+                .request_signature(&txn, idx)
+                .timeout(EXTERNAL_SIGNING_TIMEOUT)
+                .await
+                .context("external signer timed out")??;
+            if !self.public_key.verify(&txhash.0, &signature) {
+                anyhow::bail!("external signer gave a bad signature for input {}", idx)
+            }
+            txn = self.sign_tx(txn, idx)?;
+            txn.sigs[idx] = signature;
+        }
+        Ok(txn)
+    }
+
+    async fn request_signature(&self, txn: &Transaction, idx: usize) -> anyhow::Result<Vec<u8>> {
+        #[derive(Serialize)]
+        struct Payload {
+            index: usize,
+            tx: String,
+            txhash: String,
+        }
+        #[derive(Deserialize)]
+        struct Answer {
+            signature: String,
+        }
+        let url = http_types::Url::parse(&self.callback_url)?;
+        let host = url.host_str().context("callback URL has no host")?;
+        let stream = smol::net::TcpStream::connect((host, url.port().unwrap_or(80)))
+            .await
+            .context("cannot reach external signer")?;
+        let mut req = http_types::Request::post(url.clone());
+        req.set_body(
+            http_types::Body::from_json(&Payload {
+                index: idx,
+                tx: hex::encode(txn.stdcode()),
+                txhash: txn.hash_nosigs().to_string(),
+            })
+            .map_err(|e| anyhow::anyhow!("{}", e))?,
+        );
+        let mut res = async_h1::connect(stream, req)
+            .await
+            .map_err(|e| anyhow::anyhow!("external signer failed: {}", e))?;
+        if !res.status().is_success() {
+            anyhow::bail!("external signer refused with status {}", res.status())
+        }
+        let answer: Answer = res
+            .body_json()
+            .await
+            .map_err(|e| anyhow::anyhow!("external signer gave a bad answer: {}", e))?;
+        Ok(hex::decode(answer.signature)?)
+    }
+}
+
+impl Signer for ExternalSigner {
+    fn sign_tx(&self, mut txn: Transaction, input_idx: usize) -> anyhow::Result<Transaction> {
+        while txn.sigs.len() <= input_idx {
+            txn.sigs.push(vec![0; 64]);
+        }
+        Ok(txn)
+    }
+
+    fn covenant(&self) -> Covenant {
+        Covenant::std_ed25519_pk_new(self.public_key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use themelio_structs::TxKind;
+
+    #[test]
+    fn threshold_covenant() {
+        let keys: Vec<Ed25519SK> = (0..3).map(|_| tmelcrypt::ed25519_keygen().1).collect();
+        let signer =
+            ThresholdSigner::new(keys.iter().map(|sk| sk.to_public()).collect(), 2).unwrap();
+        let covenant = signer.covenant();
+        let txn = Transaction {
+            kind: TxKind::Normal,
+            inputs: vec![],
+            outputs: vec![],
+            fee: 0.into(),
+            covenants: vec![covenant.0.clone()],
+            data: vec![],
+            sigs: vec![],
+        };
+        let txn = signer.sign_tx(txn, 0).unwrap();
+        assert!(!covenant.check_opt_env(&txn, None));
+
+        let sig = keys[2].sign(&txn.hash_nosigs().0);
+        let txn = signer.add_signature(txn, 2, sig).unwrap();
+        assert_eq!(signer.count_signatures(&txn), 1);
+        assert!(!covenant.check_opt_env(&txn, None));
+
+        let sig = keys[0].sign(&txn.hash_nosigs().0);
+        assert!(signer.add_signature(txn.clone(), 1, sig.clone()).is_err());
+        let txn = signer.add_signature(txn, 0, sig).unwrap();
+        assert_eq!(signer.count_signatures(&txn), 2);
+        assert!(covenant.check_opt_env(&txn, None));
+    }
+
+    #[test]
+    fn external_signer_round_trip() {
+        #[derive(Deserialize)]
+        struct Payload {
+            index: usize,
+            txhash: String,
+        }
+        smol::block_on(async {
+            let (pk, sk) = tmelcrypt::ed25519_keygen();
+            let listener = smol::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let port = listener.local_addr().unwrap().port();
+            let server = smol::spawn(async move {
+                let mut indices = vec![];
+                for _ in 0..2 {
+                    let (stream, _) = listener.accept().await.unwrap();
+                    let (send_index, recv_index) = smol::channel::bounded(1);
+                    async_h1::accept(stream, |mut req| {
+                        let send_index = send_index.clone();
+                        async move {
+                            let payload: Payload = req.body_json().await?;
+                            send_index.send(payload.index).await?;
+                            let txhash: tmelcrypt::HashVal = payload.txhash.parse()?;
+                            let mut res = http_types::Response::new(200);
+                            res.set_body(http_types::Body::from_json(
+                                &serde_json::json!({ "signature": hex::encode(sk.sign(&txhash.0)) }),
+                            )?);
+                            Ok(res)
+                        }
+                    })
+                    .await
+                    .unwrap();
+                    indices.push(recv_index.recv().await.unwrap());
+                }
+                indices
+            });
+
+            let signer =
+                ExternalSigner::new(pk, format!("http://127.0.0.1:{}/sign", port)).unwrap();
+            let txn = Transaction {
+                kind: TxKind::Normal,
+                inputs: (0..2)
+                    .map(|index| themelio_structs::CoinID {
+                        txhash: TxHash(tmelcrypt::hash_single(b"parent")),
+                        index,
+                    })
+                    .collect(),
+                outputs: vec![],
+                fee: 0.into(),
+                covenants: vec![signer.covenant().0],
+                data: vec![],
+                sigs: vec![],
+            };
+            let txn = signer.sign_remotely(txn).await.unwrap();
+            assert_eq!(server.await, vec![0, 1]);
+            let h = txn.hash_nosigs();
+            assert!(txn.sigs.iter().all(|sig| pk.verify(&h.0, sig)));
+        });
+    }
+}
@@ -1,114 +1,648 @@
-use std::{collections::BTreeMap, net::SocketAddr, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Display,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
+};
 
 use crate::{
     database::{Database, Wallet},
+    journal::{SendJournal, UncommittedSend},
+    metrics::Metrics,
+    reload::Reloader,
+    retry::RetryPolicy,
     secrets::{EncryptedSK, PersistentSecret, SecretStore},
-    signer::Signer,
+    selection::CoinSelection,
+    shutdown::Shutdown,
+    signer::{ExternalSigner, Signer, ThresholdSigner},
+    walletdata::{CoinEvent, WalletDump},
 };
 
+use anyhow::Context;
 use dashmap::DashMap;
+use parking_lot::{Mutex, RwLock};
+use regex::Regex;
 use serde::{Deserialize, Serialize};
 use smol_timeout::TimeoutExt;
-use themelio_nodeprot::ValClient;
-use themelio_stf::melvm::Covenant;
-use themelio_structs::{Address, CoinValue, Denom, NetID};
-use tmelcrypt::Ed25519SK;
+use themelio_nodeprot::{TrustedHeight, ValClient, ValClientSnapshot};
+use themelio_stf::melvm::{covenant_weight_from_bytes, Covenant};
+use themelio_structs::{
+    Address, BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, NetID, Transaction,
+    TxKind,
+};
+use tmelcrypt::{Ed25519SK, Hashable};
+
+/// Failed password attempts allowed on a wallet before further attempts are throttled.
+const FREE_UNLOCK_ATTEMPTS: u32 = 5;
+
+/// How long a snapshot of the network is shared between requests.
+const SNAPSHOT_TTL: Duration = Duration::from_millis(500);
+
+/// How often wallets whose unlocks expired are locked again.
+const RELOCK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How many times recording a sent transaction is attempted before it goes to the send journal.
+const COMMIT_ATTEMPTS: u32 = 4;
+
+/// How long to wait before the first retry of recording a sent transaction. Doubles with every retry.
+const COMMIT_BACKOFF: Duration = Duration::from_millis(100);
+
+/// How many recent blocks fee statistics look at.
+const FEE_HISTORY_BLOCKS: u64 = 20;
+
+/// How many historical snapshots are fetched at once when gathering fee statistics.
+const FEE_HISTORY_CONCURRENCY: usize = 8;
+
+/// Why a network request failed before the daemon ever reached the network.
+#[derive(Debug)]
+pub struct StillSyncing;
+
+impl Display for StillSyncing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        "still syncing with the network".fmt(f)
+    }
+}
+
+/// Unspent coins of every wallet, keyed by wallet name.
+pub type CoinsByWallet = BTreeMap<String, BTreeMap<CoinID, CoinData>>;
+
+/// How many coin events may queue up for a subscriber before further ones are dropped.
+const SUBSCRIBER_BACKLOG: usize = 100;
+
+/// Subscribers to the coins arriving in each wallet, keyed by wallet name.
+#[derive(Clone, Default)]
+pub struct CoinSubscribers(Arc<DashMap<String, Vec<smol::channel::Sender<CoinEvent>>>>);
+
+impl CoinSubscribers {
+    /// Subscribes to the coins arriving in a wallet.
+    pub fn subscribe(&self, name: &str) -> smol::channel::Receiver<CoinEvent> {
+        let (send, recv) = smol::channel::bounded(SUBSCRIBER_BACKLOG);
+        self.0.entry(name.to_owned()).or_default().push(send);
+        recv
+    }
+
+    /// Whether anybody is subscribed to a wallet.
+    pub fn has_subscribers(&self, name: &str) -> bool {
+        self.0.get(name).map(|s| !s.is_empty()).unwrap_or_default()
+    }
+
+    /// Sends an event to every subscriber of a wallet, forgetting subscribers that went away.
+    pub fn publish(&self, name: &str, event: CoinEvent) {
+        if let Some(mut senders) = self.0.get_mut(name) {
+            senders.retain(|send| match send.try_send(event.clone()) {
+                Err(smol::channel::TrySendError::Closed(_)) => false,
+                Err(smol::channel::TrySendError::Full(_)) => {
+                    log::warn!("dropping coin event for slow subscriber of {}", name);
+                    true
+                }
+                Ok(()) => true,
+            });
+        }
+    }
+}
+
+/// Settings for the background task that confirms and rebroadcasts transactions.
+#[derive(Clone, Debug)]
+pub struct ConfirmSettings {
+    /// How long to wait before rebroadcasting a pending transaction again.
+    pub retransmit_window: Duration,
+    /// How long to wait between rounds of syncing.
+    pub interval: Duration,
+    /// How many wallets to sync at once.
+    pub concurrency: usize,
+    /// How long a transaction paying too low a fee may stay pending before wallets with auto-bump on replace it.
+    pub auto_bump_after: BlockHeight,
+}
+
+/// Failed password attempts on each wallet, keyed by wallet name, used to throttle password guessing.
+#[derive(Default)]
+struct UnlockFailures(DashMap<String, (u32, Instant)>);
+
+impl UnlockFailures {
+    /// Records a password attempt on a wallet. A correct password clears the failures.
+    fn record(&self, name: &str, correct: bool) {
+        if correct {
+            self.0.remove(name);
+        } else {
+            let mut entry = self.0.entry(name.to_owned()).or_insert((0, Instant::now()));
+            *entry = (entry.0 + 1, Instant::now());
+        }
+    }
+
+    /// How long until another password attempt on a wallet is allowed. Returns None if attempts aren't being throttled.
+    fn backoff(&self, name: &str) -> Option<Duration> {
+        let (failures, last_failure) = *self.0.get(name)?;
+        if failures < FREE_UNLOCK_ATTEMPTS {
+            return None;
+        }
+        // doubles with every further failure, capping out at a bit over an hour
+        let wait = Duration::from_secs(1 << (failures - FREE_UNLOCK_ATTEMPTS).min(12));
+        wait.checked_sub(last_failure.elapsed())
+    }
+}
+
+/// Moves whatever a map holds for a wallet over to its new name.
+fn rename_entry<V>(map: &DashMap<String, V>, name: &str, new_name: &str) {
+    if let Some((_, value)) = map.remove(name) {
+        map.insert(new_name.to_owned(), value);
+    }
+}
+
+/// Limits on drawing from the faucet of a testnet.
+#[derive(Clone, Debug)]
+pub struct FaucetSettings {
+    /// The most a single draw may ask for.
+    pub max_value: CoinValue,
+    /// How long a wallet must wait between draws.
+    pub interval: Duration,
+}
 
 /// Encapsulates all the state and logic needed for the wallet daemon.
 pub struct AppState {
     pub database: Database,
     pub network: NetID,
     pub client: ValClient,
-    pub unlocked_signers: DashMap<String, Arc<dyn Signer>>,
+    pub unlocked_signers: Arc<DashMap<String, Arc<dyn Signer>>>,
+    unlock_expiries: Arc<DashMap<String, SystemTime>>,
+    _relock_task: smol::Task<()>,
+    unlock_failures: UnlockFailures,
+    faucet_draws: DashMap<String, Instant>,
+    pub faucet: FaucetSettings,
+    pub node_retry: RetryPolicy,
+    /// Blocks a sent transaction may stay unconfirmed before it is given up on.
+    pub commit_timeout: BlockHeight,
+    /// How inputs are picked, unless a request asks otherwise.
+    pub coin_selection: CoinSelection,
+    /// Unspent coins of one denomination past which a wallet counts as fragmented.
+    pub fragmentation_threshold: usize,
+    send_journal: SendJournal,
     pub secrets: SecretStore,
-    pub _confirm_task: smol::Task<()>,
-    // pub trusted_height: TrustedHeight,
+    pub coin_cache_ttl: Duration,
+    pub coin_reservation: Duration,
+    wallet_name_pattern: Regex,
+    coin_cache: Mutex<Option<(Instant, CoinsByWallet)>>,
+    snapshot_cache: smol::lock::Mutex<Option<(Instant, ValClientSnapshot)>>,
+    fee_history_cache: smol::lock::Mutex<Option<(BlockHeight, Vec<u128>)>>,
+    synced: Arc<AtomicBool>,
+    confirm_task: Mutex<Option<smol::Task<()>>>,
+    pub trusted_height: Option<TrustedHeight>,
+    /// Whether the wallet directory belongs to another daemon, so nothing may be written to it.
+    pub read_only: bool,
+    pub started: Instant,
+    pub metrics: Metrics,
+    pub coin_subscribers: CoinSubscribers,
+    pub shutdown: Arc<Shutdown>,
+    pub reloader: Reloader,
 }
 
 ///themelio_bootstrap::checkpoint_height(network).unwrap()
 impl AppState {
-    /// Creates a new appstate, given a network server `addr`.
+    /// Creates a new appstate, given a client to the network.
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         database: Database,
         network: NetID,
         secrets: SecretStore,
-        _addr: SocketAddr,
         client: ValClient,
+        trusted_height: Option<TrustedHeight>,
+        coin_cache_ttl: Duration,
+        coin_reservation: Duration,
+        wallet_name_pattern: Regex,
+        confirm_settings: Arc<RwLock<ConfirmSettings>>,
+        faucet: FaucetSettings,
+        node_retry: RetryPolicy,
+        commit_timeout: BlockHeight,
+        coin_selection: CoinSelection,
+        fragmentation_threshold: usize,
+        send_journal: SendJournal,
+        reloader: Reloader,
+        read_only: bool,
     ) -> Self {
-        let _confirm_task = smolscale::spawn(confirm_task(database.clone(), client.clone()));
+        let coin_subscribers = CoinSubscribers::default();
+        let synced = Arc::new(AtomicBool::new(false));
+        let unlocked_signers: Arc<DashMap<String, Arc<dyn Signer>>> = Default::default();
+        let unlock_expiries: Arc<DashMap<String, SystemTime>> = Default::default();
+        let relock_task = smolscale::spawn(self::relock_task(
+            unlocked_signers.clone(),
+            unlock_expiries.clone(),
+        ));
+        // a read-only daemon leaves syncing to whichever daemon owns the database
+        let confirm_task = (!read_only).then(|| {
+            smolscale::spawn(self::confirm_task(
+                database.clone(),
+                client.clone(),
+                confirm_settings,
+                coin_subscribers.clone(),
+                synced.clone(),
+                unlocked_signers.clone(),
+                commit_timeout,
+                trusted_height.clone(),
+            ))
+        });
 
         Self {
             database,
             network,
             client,
-            unlocked_signers: Default::default(),
+            unlocked_signers,
+            unlock_expiries,
+            _relock_task: relock_task,
+            unlock_failures: Default::default(),
+            faucet_draws: Default::default(),
+            faucet,
+            node_retry,
+            commit_timeout,
+            coin_selection,
+            fragmentation_threshold,
+            send_journal,
             secrets,
-            _confirm_task,
+            coin_cache_ttl,
+            coin_reservation,
+            wallet_name_pattern,
+            coin_cache: Default::default(),
+            snapshot_cache: Default::default(),
+            fee_history_cache: Default::default(),
+            synced,
+            confirm_task: Mutex::new(confirm_task),
+            trusted_height,
+            read_only,
+            started: Instant::now(),
+            metrics: Default::default(),
+            coin_subscribers,
+            shutdown: Default::default(),
+            reloader,
+        }
+    }
+
+    /// Records that a wallet sent a transaction, retrying with backoff. If it still can't be recorded, the transaction goes to the send journal for [AppState::reconcile_sends] instead, and false is returned.
+    pub async fn commit_sent(
+        &self,
+        wallet_name: &str,
+        wallet: &Wallet,
+        tx: Transaction,
+        sent_height: BlockHeight,
+        timeout: BlockHeight,
+    ) -> bool {
+        let mut backoff = COMMIT_BACKOFF;
+        for attempt in 1..=COMMIT_ATTEMPTS {
+            match wallet.commit_sent(tx.clone(), sent_height, timeout).await {
+                Ok(()) => return true,
+                Err(err) if attempt < COMMIT_ATTEMPTS => {
+                    log::warn!(
+                        "cannot record sent transaction {} (attempt {}): {:?}",
+                        tx.hash_nosigs(),
+                        attempt,
+                        err
+                    );
+                    smol::Timer::after(backoff).await;
+                    backoff *= 2;
+                }
+                Err(err) => log::error!(
+                    "transaction {} was sent but cannot be recorded in wallet {}, so its change is not tracked: {:?}",
+                    tx.hash_nosigs(),
+                    wallet_name,
+                    err
+                ),
+            }
+        }
+        let txhash = tx.hash_nosigs();
+        let send = UncommittedSend {
+            wallet_name: wallet_name.to_owned(),
+            transaction: tx,
+            sent_height,
+            timeout,
+        };
+        if let Err(err) = self.send_journal.record(&send) {
+            log::error!(
+                "cannot journal transaction {} either; import it by hand: {:?}",
+                txhash,
+                err
+            );
+        }
+        false
+    }
+
+    /// Records every transaction in the send journal that can now be recorded, leaving the rest in the journal. Transactions of wallets that no longer exist are dropped.
+    pub async fn reconcile_sends(&self) -> anyhow::Result<()> {
+        let mut remaining = vec![];
+        for send in self.send_journal.entries()? {
+            let txhash = send.transaction.hash_nosigs();
+            let wallet = match self.get_wallet(&send.wallet_name).await {
+                Some(wallet) => wallet,
+                None => {
+                    log::warn!(
+                        "dropping journaled transaction {} of deleted wallet {}",
+                        txhash,
+                        send.wallet_name
+                    );
+                    continue;
+                }
+            };
+            match wallet
+                .commit_sent(send.transaction.clone(), send.sent_height, send.timeout)
+                .await
+            {
+                Ok(()) => log::info!(
+                    "recorded journaled transaction {} in wallet {}",
+                    txhash,
+                    send.wallet_name
+                ),
+                Err(err) => {
+                    log::error!(
+                        "journaled transaction {} still cannot be recorded in wallet {}: {:?}",
+                        txhash,
+                        send.wallet_name,
+                        err
+                    );
+                    remaining.push(send);
+                }
+            }
+        }
+        self.send_journal.replace(&remaining)
+    }
+
+    /// Stops syncing wallets in the background, waiting for the current round to be cancelled.
+    pub async fn stop_confirm_task(&self) {
+        let task = self.confirm_task.lock().take();
+        if let Some(task) = task {
+            task.cancel().await;
+        }
+    }
+
+    /// Whether the daemon has reached the network at least once since starting.
+    pub fn is_synced(&self) -> bool {
+        self.synced.load(Ordering::SeqCst)
+    }
+
+    /// Returns the latest snapshot of the network. Until the first one is obtained, failures carry a [StillSyncing] context.
+    pub async fn fresh_snapshot(&self) -> anyhow::Result<ValClientSnapshot> {
+        let snapshot = self
+            .node_retry
+            .run("fetching a snapshot", || self.client.snapshot())
+            .await;
+        match snapshot {
+            Ok(snapshot) => {
+                self.synced.store(true, Ordering::SeqCst);
+                Ok(snapshot)
+            }
+            Err(err) if !self.is_synced() => Err(anyhow::Error::from(err).context(StillSyncing)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Returns a snapshot of the network, shared with other requests for `SNAPSHOT_TTL`. Whatever must see the very latest state should call `fresh_snapshot` instead.
+    pub async fn snapshot(&self) -> anyhow::Result<ValClientSnapshot> {
+        // holding the lock while fetching makes concurrent requests wait for one fetch, rather than making their own
+        let mut cache = self.snapshot_cache.lock().await;
+        if let Some((fetched, snapshot)) = cache.as_ref() {
+            if fetched.elapsed() < SNAPSHOT_TTL {
+                return Ok(snapshot.clone());
+            }
+        }
+        let snapshot = self.fresh_snapshot().await?;
+        *cache = Some((Instant::now(), snapshot.clone()));
+        Ok(snapshot)
+    }
+
+    /// Returns the current fee multiplier, along with those of the last `FEE_HISTORY_BLOCKS` blocks, newest first. They are fetched again only once a new block arrives.
+    pub async fn recent_fee_multipliers(&self) -> anyhow::Result<(u128, Vec<u128>)> {
+        let snapshot = self.snapshot().await?;
+        let current = snapshot.current_header();
+        let mut cache = self.fee_history_cache.lock().await;
+        if let Some((height, multipliers)) = cache.as_ref() {
+            if *height == current.height {
+                return Ok((current.fee_multiplier, multipliers.clone()));
+            }
+        }
+        let semaphore = Arc::new(smol::lock::Semaphore::new(FEE_HISTORY_CONCURRENCY));
+        let fetches: Vec<_> = (current.height.0.saturating_sub(FEE_HISTORY_BLOCKS - 1)
+            ..=current.height.0)
+            .rev()
+            .map(|height| {
+                let snapshot = snapshot.clone();
+                let semaphore = semaphore.clone();
+                smolscale::spawn(async move {
+                    let _permit = semaphore.acquire().await;
+                    snapshot
+                        .get_older(BlockHeight(height))
+                        .await
+                        .map(|older| older.current_header().fee_multiplier)
+                })
+            })
+            .collect();
+        let mut multipliers = vec![];
+        for fetch in fetches {
+            multipliers.push(fetch.await?);
+        }
+        *cache = Some((current.height, multipliers.clone()));
+        Ok((current.fee_multiplier, multipliers))
+    }
+
+    /// Returns the unspent coins of every wallet, cached for `coin_cache_ttl`.
+    pub async fn all_unspent_coins(&self) -> CoinsByWallet {
+        if let Some((fetched, coins)) = self.coin_cache.lock().as_ref() {
+            if fetched.elapsed() < self.coin_cache_ttl {
+                return coins.clone();
+            }
+        }
+        let mut toret = BTreeMap::new();
+        for name in self.database.list_wallets().await {
+            if let Some(wallet) = self.database.get_wallet(&name).await {
+                toret.insert(name, wallet.get_coin_mapping(true, false).await);
+            }
         }
+        *self.coin_cache.lock() = Some((Instant::now(), toret.clone()));
+        toret
     }
 
-    /// Returns a summary of wallets.
-    pub async fn list_wallets(&self) -> BTreeMap<String, WalletSummary> {
+    /// Returns a summary of wallets, optionally only those with the given tag.
+    pub async fn list_wallets(&self, tag: Option<&str>) -> BTreeMap<String, WalletSummary> {
         let mlist = self.database.list_wallets().await;
         let mut toret = BTreeMap::new();
-        for name in mlist.into_iter() {
+        for name in mlist.into_iter().filter(|n| self.valid_wallet_name(n)) {
             let wallet = self.database.get_wallet(&name).await.unwrap();
+            let tags = wallet.get_tags().await;
+            if let Some(tag) = tag {
+                if !tags.contains(tag) {
+                    continue;
+                }
+            }
             let balance = wallet.get_balances().await;
+            let confirmed = wallet.get_confirmed_balances().await;
             let summary = WalletSummary {
                 detailed_balance: balance
                     .iter()
-                    .map(|(k, v)| (hex::encode(&k.to_bytes()), *v))
+                    .map(|(k, v)| (hex::encode(k.to_bytes()), *v))
+                    .collect(),
+                balance_breakdown: balance
+                    .iter()
+                    .map(|(k, v)| {
+                        let confirmed = confirmed.get(k).copied().unwrap_or_default();
+                        let breakdown = DenomBalance {
+                            confirmed,
+                            pending: v.checked_sub(confirmed).unwrap_or_default(),
+                        };
+                        (hex::encode(k.to_bytes()), breakdown)
+                    })
                     .collect(),
                 total_micromel: balance.get(&Denom::Mel).copied().unwrap_or_default(),
                 network: self.network,
                 address: wallet.address(),
-                locked: !self.unlocked_signers.contains_key(&name),
+                locked: self.get_signer(&name).is_none(),
+                watch_only: self.is_watch_only(&name),
                 staked_microsym: Default::default(),
+                tags,
+                auto_bump: wallet.auto_bump().await,
             };
             toret.insert(name, summary);
         }
         toret
     }
 
-    /// Obtains the signer of a wallet. If the wallet is still locked, returns None.
+    /// Checks whether any wallet of the daemon has the given address.
+    pub async fn owns_address(&self, address: Address) -> bool {
+        for name in self.database.list_wallets().await {
+            if let Some(wallet) = self.database.get_wallet(&name).await {
+                if wallet.address() == address {
+                    return true;
+                }
+            }
+        }
+        false
+    }
+
+    /// Whether any wallet of the daemon ever saw a coin sent to the given address.
+    pub async fn address_seen(&self, address: Address) -> bool {
+        self.database.address_seen(address).await
+    }
+
+    /// Obtains the signer of a wallet. If the wallet is still locked, returns None. Multisignature and externally signed wallets are never locked, since they hold no secrets.
     pub fn get_signer(&self, name: &str) -> Option<Arc<dyn Signer>> {
+        if let Some(signer) = self.get_threshold_signer(name) {
+            return Some(Arc::new(signer));
+        }
+        if let Some(signer) = self.get_external_signer(name) {
+            return Some(Arc::new(signer));
+        }
         let res = self.unlocked_signers.get(name)?;
         Some(res.clone())
     }
 
+    /// Obtains the multisignature parameters of a wallet, if it is a multisignature wallet.
+    pub fn get_threshold_signer(&self, name: &str) -> Option<ThresholdSigner> {
+        match self.secrets.load(name)? {
+            PersistentSecret::Threshold(signer) => Some(signer),
+            _ => None,
+        }
+    }
+
+    /// Obtains the external signer of a wallet whose key is held elsewhere. Returns None for any other kind of wallet.
+    pub fn get_external_signer(&self, name: &str) -> Option<ExternalSigner> {
+        match self.secrets.load(name)? {
+            PersistentSecret::External(signer) => Some(signer),
+            _ => None,
+        }
+    }
+
     /// Unlocks a particular wallet. Returns None if unlocking failed.
-    pub fn unlock(&self, name: &str, pwd: Option<String>) -> Option<()> {
-        let enc = self.secrets.load(name)?;
-        match enc {
-            PersistentSecret::Plaintext(sec) => {
-                self.unlocked_signers.insert(name.to_owned(), Arc::new(sec));
+    pub fn unlock(&self, name: &str, pwd: Option<String>, ttl: Option<Duration>) -> Option<()> {
+        let sk = self.get_secret_key(name, pwd)?;
+        self.unlocked_signers.insert(name.to_owned(), Arc::new(sk));
+        match ttl {
+            Some(ttl) => {
+                self.unlock_expiries
+                    .insert(name.to_owned(), SystemTime::now() + ttl);
             }
-            PersistentSecret::PasswordEncrypted(enc) => {
-                let decrypted = enc.decrypt(&pwd?)?;
-                self.unlocked_signers
-                    .insert(name.to_owned(), Arc::new(decrypted));
+            None => {
+                self.unlock_expiries.remove(name);
             }
         }
         Some(())
     }
 
+    /// When an unlocked wallet will lock itself again, if it was unlocked for a limited time.
+    pub fn unlock_expiry(&self, name: &str) -> Option<SystemTime> {
+        self.unlock_expiries.get(name).map(|expiry| *expiry)
+    }
+
     /// Dumps a particular private key. Use carefully!
     pub fn get_secret_key(&self, name: &str, pwd: Option<String>) -> Option<Ed25519SK> {
         let enc = self.secrets.load(name)?;
-        match enc {
+        let sk = match enc {
             PersistentSecret::Plaintext(sk) => Some(sk),
-            PersistentSecret::PasswordEncrypted(enc) => {
-                let decrypted = enc.decrypt(&pwd?)?;
-                Some(decrypted)
+            PersistentSecret::PasswordEncrypted(enc) => pwd.and_then(|pwd| enc.decrypt(&pwd)),
+            PersistentSecret::Threshold(_) | PersistentSecret::External(_) => return None,
+        };
+        self.unlock_failures.record(name, sk.is_some());
+        sk
+    }
+
+    /// Whether a password lets the secret of a wallet be exported, checking it as [AppState::get_secret_key] does. Threshold and external wallets keep only public keys and a callback, so they need no password.
+    pub fn secret_exportable(&self, name: &str, pwd: Option<String>) -> bool {
+        match self.secrets.load(name) {
+            None | Some(PersistentSecret::Threshold(_)) | Some(PersistentSecret::External(_)) => {
+                true
             }
+            Some(_) => self.get_secret_key(name, pwd).is_some(),
         }
     }
+
+    /// Re-encrypts the secret key of a wallet under a new password, or stores it in plaintext if there is no new password. Returns None if the old password is wrong.
+    pub fn change_password(
+        &self,
+        name: &str,
+        old_pwd: Option<String>,
+        new_pwd: Option<String>,
+    ) -> Option<()> {
+        let sk = self.get_secret_key(name, old_pwd)?;
+        let secret = match new_pwd {
+            Some(pwd) => PersistentSecret::PasswordEncrypted(EncryptedSK::new(sk, &pwd)),
+            None => PersistentSecret::Plaintext(sk),
+        };
+        self.secrets.store(name.to_owned(), secret);
+        Some(())
+    }
+
+    /// How long until another password attempt on this wallet is allowed. Returns None if attempts aren't being throttled.
+    pub fn unlock_backoff(&self, name: &str) -> Option<Duration> {
+        self.unlock_failures.backoff(name)
+    }
+
+    /// Whether a wallet name is allowed by the configured pattern.
+    pub fn valid_wallet_name(&self, name: &str) -> bool {
+        self.wallet_name_pattern.is_match(name)
+    }
+
+    /// Records a faucet draw by a wallet. If the wallet drew too recently, records nothing and returns how long until it may draw again.
+    pub fn record_faucet_draw(&self, name: &str) -> Option<Duration> {
+        let mut last_draw = self
+            .faucet_draws
+            .entry(name.to_owned())
+            .or_insert_with(|| Instant::now() - self.faucet.interval);
+        if let Some(wait) = self.faucet.interval.checked_sub(last_draw.elapsed()) {
+            if !wait.is_zero() {
+                return Some(wait);
+            }
+        }
+        *last_draw = Instant::now();
+        None
+    }
+
+    /// Forgets the last faucet draw of a wallet, for a draw that was recorded but never went out. Any earlier draw was at least an interval before it, so it no longer throttles anything.
+    pub fn forget_faucet_draw(&self, name: &str) {
+        self.faucet_draws.remove(name);
+    }
+
     pub async fn get_wallet(&self, name: &str) -> Option<Wallet> {
         self.database.get_wallet(name).await
     }
     /// Locks a particular wallet.
     pub fn lock(&self, name: &str) {
         self.unlocked_signers.remove(name);
+        self.unlock_expiries.remove(name);
     }
 
     /// Creates a wallet with a given name.
@@ -119,54 +653,283 @@ impl AppState {
         pwd: Option<String>,
     ) -> anyhow::Result<()> {
         let covenant = Covenant::std_ed25519_pk_new(key.to_public());
+        let secret = match pwd {
+            Some(pwd) => PersistentSecret::PasswordEncrypted(EncryptedSK::new(key, &pwd)),
+            None => PersistentSecret::Plaintext(key),
+        };
+        self.store_wallet(name, covenant, secret).await
+    }
+
+    /// Creates a watch-only wallet, which tracks an address without being able to spend from it.
+    pub async fn create_watch_wallet(
+        &self,
+        name: &str,
+        address: Address,
+        covenant: Option<Covenant>,
+    ) -> anyhow::Result<()> {
+        match covenant {
+            Some(covenant) => {
+                if covenant.hash() != address {
+                    anyhow::bail!("covenant does not hash to the given address")
+                }
+                self.database.create_wallet(name, covenant).await?
+            }
+            None => self.database.create_address_wallet(name, address).await?,
+        }
+        log::info!("created watch-only wallet with name {}", name);
+        Ok(())
+    }
+
+    /// Whether a name belongs to a wallet of another network. Every network has its own database, but they all share the secret store, so such a name must not be reused here.
+    pub async fn name_used_elsewhere(&self, name: &str) -> bool {
+        self.secrets.load(name).is_some() && self.get_wallet(name).await.is_none()
+    }
+
+    /// Whether a wallet has no way of signing at all.
+    pub fn is_watch_only(&self, name: &str) -> bool {
+        self.secrets.load(name).is_none()
+    }
+
+    /// Creates a wallet whose key is held by an external signer.
+    pub async fn create_external_wallet(
+        &self,
+        name: &str,
+        signer: ExternalSigner,
+    ) -> anyhow::Result<()> {
+        let covenant = signer.covenant();
+        self.store_wallet(name, covenant, PersistentSecret::External(signer))
+            .await
+    }
+
+    /// Creates a multisignature wallet with a given name.
+    pub async fn create_threshold_wallet(
+        &self,
+        name: &str,
+        signer: ThresholdSigner,
+    ) -> anyhow::Result<()> {
+        let covenant = signer.covenant();
+        self.store_wallet(name, covenant, PersistentSecret::Threshold(signer))
+            .await
+    }
+
+    async fn store_wallet(
+        &self,
+        name: &str,
+        covenant: Covenant,
+        secret: PersistentSecret,
+    ) -> anyhow::Result<()> {
+        // the database refuses duplicate names, so a failure here never clobbers an existing secret of this network
+        if self.name_used_elsewhere(name).await {
+            anyhow::bail!("wallet {} belongs to another network", name)
+        }
         self.database.create_wallet(name, covenant).await?;
-        self.secrets.store(
-            name.to_owned(),
-            match pwd {
-                Some(pwd) => PersistentSecret::PasswordEncrypted(EncryptedSK::new(key, &pwd)),
-                None => PersistentSecret::Plaintext(key),
-            },
-        );
+        self.secrets.store(name.to_owned(), secret);
         log::info!("created wallet with name {}", name);
         Ok(())
     }
+
+    /// Dumps everything needed to recreate a wallet, including its secret exactly as stored if asked to.
+    pub async fn dump_wallet(&self, name: &str, include_secret: bool) -> Option<WalletDump> {
+        let wallet = self.get_wallet(name).await?;
+        Some(WalletDump {
+            address: wallet.address(),
+            covenant: wallet.covenant().map(|c| c.0).unwrap_or_default(),
+            secret: if include_secret {
+                self.secrets.load(name)
+            } else {
+                None
+            },
+            labels: wallet.get_labels().await,
+            tags: wallet.get_tags().await,
+            network: Some(self.network),
+        })
+    }
+
+    /// Recreates a wallet from a dump, replacing any existing wallet of the same name.
+    pub async fn restore_wallet(&self, name: &str, dump: WalletDump) -> anyhow::Result<()> {
+        if !dump.covenant.is_empty() && Covenant(dump.covenant.clone()).hash() != dump.address {
+            anyhow::bail!("covenant does not hash to the given address")
+        }
+        if let Some(network) = dump.network {
+            if network != self.network {
+                anyhow::bail!(
+                    "wallet was dumped from {:?}, but this daemon is on {:?}",
+                    network,
+                    self.network
+                )
+            }
+        }
+        if self.name_used_elsewhere(name).await {
+            anyhow::bail!("wallet {} belongs to another network", name)
+        }
+        // the database swaps the wallet in a single transaction, so a failure there leaves the old wallet and its secret alone
+        self.database
+            .restore_wallet(name, dump.address, dump.covenant, &dump.labels, &dump.tags)
+            .await?;
+        self.lock(name);
+        match dump.secret {
+            Some(secret) => self.secrets.store(name.to_owned(), secret),
+            None => self.secrets.remove(name),
+        }
+        log::info!("restored wallet with name {}", name);
+        Ok(())
+    }
+
+    /// Deletes a wallet with a given name, locking it and purging its secret. Returns false if the wallet did not exist.
+    pub async fn delete_wallet(&self, name: &str) -> anyhow::Result<bool> {
+        if !self.database.delete_wallet(name).await? {
+            return Ok(false);
+        }
+        self.lock(name);
+        self.secrets.remove(name);
+        log::info!("deleted wallet with name {}", name);
+        Ok(true)
+    }
+
+    /// Renames a wallet, moving its secret and leaving it unlocked if it was. Returns false if the wallet did not exist.
+    pub async fn rename_wallet(&self, name: &str, new_name: &str) -> anyhow::Result<bool> {
+        if !self.database.rename_wallet(name, new_name).await? {
+            return Ok(false);
+        }
+        self.secrets.rename(name, new_name);
+        rename_entry(&self.unlocked_signers, name, new_name);
+        rename_entry(&self.unlock_expiries, name, new_name);
+        // throttling follows the wallet, so renaming it back and forth cannot reset it
+        rename_entry(&self.unlock_failures.0, name, new_name);
+        rename_entry(&self.faucet_draws, name, new_name);
+        // the cached coins are keyed by the old name
+        *self.coin_cache.lock() = None;
+        log::info!("renamed wallet {} to {}", name, new_name);
+        Ok(true)
+    }
+}
+
+/// The balance of a wallet in one denomination, split by whether the coins are confirmed yet.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct DenomBalance {
+    /// Coins confirmed on-chain, which can be spent right away.
+    pub confirmed: CoinValue,
+    /// Coins from our own transactions that are still pending, typically change.
+    pub pending: CoinValue,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct WalletSummary {
     pub total_micromel: CoinValue,
     pub detailed_balance: BTreeMap<String, CoinValue>,
+    #[serde(default)]
+    pub balance_breakdown: BTreeMap<String, DenomBalance>,
     pub staked_microsym: CoinValue,
     pub network: NetID,
     #[serde(with = "stdcode::asstr")]
     pub address: Address,
     pub locked: bool,
+    pub watch_only: bool,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    #[serde(default)]
+    pub auto_bump: bool,
+}
+
+// task that locks wallets again once their time-limited unlocks expire
+async fn relock_task(
+    unlocked_signers: Arc<DashMap<String, Arc<dyn Signer>>>,
+    unlock_expiries: Arc<DashMap<String, SystemTime>>,
+) {
+    loop {
+        smol::Timer::after(RELOCK_INTERVAL).await;
+        let now = SystemTime::now();
+        unlock_expiries.retain(|name, expiry| {
+            if *expiry > now {
+                return true;
+            }
+            unlocked_signers.remove(name);
+            log::info!("locked wallet {} as its unlock expired", name);
+            false
+        });
+    }
 }
 
-// task that periodically pulls random coins to try to confirm
-async fn confirm_task(database: Database, client: ValClient) {
-    let mut pacer = smol::Timer::interval(Duration::from_millis(15000));
-    // let sent = Arc::new(Mutex::new(HashMap::new()));
+// task that periodically syncs every wallet, a few at a time, to try to confirm
+#[allow(clippy::too_many_arguments)]
+async fn confirm_task(
+    database: Database,
+    client: ValClient,
+    settings: Arc<RwLock<ConfirmSettings>>,
+    subscribers: CoinSubscribers,
+    synced: Arc<AtomicBool>,
+    unlocked_signers: Arc<DashMap<String, Arc<dyn Signer>>>,
+    commit_timeout: BlockHeight,
+    checkpoint: Option<TrustedHeight>,
+) {
     loop {
+        // the settings may be reloaded while we run, so each round reads them afresh
+        let round_start = Instant::now();
+        let settings = settings.read().clone();
+        let semaphore = Arc::new(smol::lock::Semaphore::new(settings.concurrency.max(1)));
         let possible_wallets = database.list_wallets().await;
         log::trace!("-- confirm loop sees {} wallets --", possible_wallets.len());
         match client.snapshot().await {
             Ok(snap) => {
-                for wname in possible_wallets {
-                    if let Some(wallet) = database.get_wallet(&wname).await {
-                        let r = wallet
-                            .network_sync(snap.clone())
-                            .timeout(Duration::from_secs(120))
-                            .await;
-                        match r {
-                            None => log::warn!("sync {} timed out", wname),
-                            Some(Err(err)) => log::warn!("sync {} failed: {:?}", wname, err),
-                            _ => (),
-                        }
+                synced.store(true, Ordering::SeqCst);
+                // the client only hands out snapshots it verified, so the next start can trust this header instead of verifying its way here again
+                if let Some(checkpoint) = checkpoint.as_ref() {
+                    let verified = TrustedHeight {
+                        height: snap.current_header().height,
+                        header_hash: snap.current_header().hash(),
+                    };
+                    if let Err(err) = database
+                        .save_verified_header(client.netid(), &verified, checkpoint)
+                        .await
+                    {
+                        log::warn!("cannot save the verified header: {:?}", err);
                     }
                 }
+                if let Err(err) = database
+                    .save_synced_height(client.netid(), snap.current_header().height)
+                    .await
+                {
+                    log::warn!("cannot save the synced height: {:?}", err);
+                }
+                let syncs: Vec<_> = possible_wallets
+                    .into_iter()
+                    .map(|wname| {
+                        let database = database.clone();
+                        let snap = snap.clone();
+                        let semaphore = semaphore.clone();
+                        let subscribers = subscribers.clone();
+                        let signer = unlocked_signers.get(&wname).map(|s| s.clone());
+                        let auto_bump_after = settings.auto_bump_after;
+                        smolscale::spawn(async move {
+                            let _permit = semaphore.acquire().await;
+                            if let Some(wallet) = database.get_wallet(&wname).await {
+                                sync_wallet(&wallet, &wname, &snap, &subscribers).await;
+                                // coins landing on subaddresses belong to the wallet too
+                                for subaddress in wallet.subaddress_wallets().await {
+                                    sync_wallet(&subaddress, &wname, &snap, &subscribers).await;
+                                }
+                                // bumping needs a signer, so locked wallets are left alone
+                                if let Some(signer) = signer {
+                                    if wallet.auto_bump().await {
+                                        bump_stuck(
+                                            &wallet,
+                                            signer.as_ref(),
+                                            &snap,
+                                            auto_bump_after,
+                                            commit_timeout,
+                                        )
+                                        .await;
+                                    }
+                                }
+                            }
+                        })
+                    })
+                    .collect();
+                for sync in syncs {
+                    sync.await;
+                }
                 let _ = database
-                    .retransmit_pending(snap)
+                    .retransmit_pending(snap, settings.retransmit_window)
                     .timeout(Duration::from_secs(10))
                     .await;
             }
@@ -174,6 +937,183 @@ async fn confirm_task(database: Database, client: ValClient) {
                 log::warn!("failed to snap: {:?}", err);
             }
         }
-        (&mut pacer).await;
+        smol::Timer::at(round_start + settings.interval).await;
+    }
+}
+
+// syncs the coins of one wallet, or one of its subaddresses, telling subscribers to the wallet about new ones
+async fn sync_wallet(
+    wallet: &Wallet,
+    wname: &str,
+    snap: &ValClientSnapshot,
+    subscribers: &CoinSubscribers,
+) {
+    let r = wallet
+        .network_sync(snap.clone())
+        .timeout(Duration::from_secs(120))
+        .await;
+    match r {
+        None => log::warn!("sync {} timed out", wname),
+        Some(Err(err)) => {
+            log::warn!("sync {} failed: {:?}", wname, err)
+        }
+        Some(Ok(new_coins)) => {
+            if subscribers.has_subscribers(wname) {
+                notify_new_coins(wallet, wname, new_coins, subscribers).await
+            }
+        }
+    }
+}
+
+// replaces the wallet's transactions that have been pending too long on too low a fee with ones paying the current fee
+async fn bump_stuck(
+    wallet: &Wallet,
+    signer: &dyn Signer,
+    snap: &ValClientSnapshot,
+    after: BlockHeight,
+    commit_timeout: BlockHeight,
+) {
+    let header = snap.current_header();
+    for (tx, sent_height) in wallet.get_pending_transactions().await {
+        let stuck = sent_height.map(|h| h + after <= header.height) == Some(true);
+        let ours = tx.covenants.iter().any(|c| c.hash() == wallet.address().0);
+        if !stuck || !ours || tx.kind != TxKind::Normal {
+            continue;
+        }
+        if tx.fee >= tx.base_fee(header.fee_multiplier, 0, covenant_weight_from_bytes) {
+            // it pays enough, so something other than the fee holds it up
+            continue;
+        }
+        let txhash = tx.hash_nosigs();
+        let replacement = match with_fee_multiplier(tx, wallet.address(), header.fee_multiplier)
+            .and_then(|tx| (0..tx.inputs.len()).try_fold(tx, |tx, i| signer.sign_tx(tx, i)))
+        {
+            Ok(replacement) => replacement,
+            Err(err) => {
+                log::warn!(
+                    "cannot bump the fee of stuck transaction {}: {:?}",
+                    txhash,
+                    err
+                );
+                continue;
+            }
+        };
+        if let Err(err) = snap.get_raw().send_tx(replacement.clone()).await {
+            log::warn!(
+                "replacement of stuck transaction {} was refused: {:?}",
+                txhash,
+                err
+            );
+            continue;
+        }
+        let replacement_hash = replacement.hash_nosigs();
+        match wallet
+            .replace_transaction(
+                txhash,
+                replacement,
+                header.height,
+                header.height + commit_timeout,
+            )
+            .await
+        {
+            Ok(()) => log::info!(
+                "replaced stuck transaction {} with {}, which pays the current fee",
+                txhash,
+                replacement_hash
+            ),
+            Err(err) => log::error!(
+                "sent {} to replace stuck transaction {}, but cannot record it: {:?}",
+                replacement_hash,
+                txhash,
+                err
+            ),
+        }
+    }
+}
+
+// raises an unsigned copy of a transaction to the fee the given multiplier asks for, paying the difference out of its MEL change
+fn with_fee_multiplier(
+    mut tx: Transaction,
+    change_address: Address,
+    fee_multiplier: u128,
+) -> anyhow::Result<Transaction> {
+    let change = tx
+        .outputs
+        .iter()
+        .position(|o| o.covhash == change_address && o.denom == Denom::Mel)
+        .context("no MEL change to pay a higher fee out of")?;
+    // the signatures are replaced by ones of the same size, so they still count towards the fee
+    let old_fee = tx.fee;
+    for _ in 0..2 {
+        // a larger fee can itself take a byte more to encode, so we settle it twice
+        tx.fee = tx
+            .base_fee(fee_multiplier, 0, covenant_weight_from_bytes)
+            .max(tx.fee);
+    }
+    let extra = tx.fee - old_fee;
+    tx.outputs[change].value = tx.outputs[change]
+        .value
+        .checked_sub(extra)
+        .context("MEL change is too small to pay a higher fee out of")?;
+    tx.sigs.clear();
+    Ok(tx)
+}
+
+// tells subscribers of a wallet about its newly confirmed coins
+async fn notify_new_coins(
+    wallet: &Wallet,
+    wname: &str,
+    new_coins: Vec<(CoinID, CoinDataHeight)>,
+    subscribers: &CoinSubscribers,
+) {
+    for (coin_id, coin_data_height) in new_coins {
+        let is_change = wallet.is_change(coin_id).await;
+        subscribers.publish(
+            wname,
+            CoinEvent {
+                coin_id: coin_id.to_string(),
+                coin_data_height,
+                is_change,
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bumps_fee_out_of_change() {
+        let change = Address(Default::default());
+        let mut tx = Transaction::empty_test();
+        tx.outputs.push(CoinData {
+            covhash: change,
+            value: CoinValue(1_000_000),
+            denom: Denom::Mel,
+            additional_data: vec![],
+        });
+        tx.sigs = vec![vec![0u8; 64]];
+        let bumped = with_fee_multiplier(tx.clone(), change, 1 << 20).unwrap();
+        assert!(bumped.fee > tx.fee);
+        assert_eq!(bumped.fee + bumped.outputs[0].value, CoinValue(1_000_000));
+        assert!(bumped.sigs.is_empty());
+        assert!(with_fee_multiplier(tx, change, u128::MAX >> 20).is_err());
+    }
+
+    #[test]
+    fn backoff_survives_rename() {
+        let failures = UnlockFailures::default();
+        for _ in 0..=FREE_UNLOCK_ATTEMPTS {
+            failures.record("a", false);
+        }
+        assert!(failures.backoff("a").is_some());
+        rename_entry(&failures.0, "a", "b");
+        assert!(failures.backoff("a").is_none());
+        assert!(failures.backoff("b").is_some());
+        rename_entry(&failures.0, "b", "a");
+        assert!(failures.backoff("a").is_some());
+        failures.record("a", true);
+        assert!(failures.backoff("a").is_none());
     }
 }
@@ -0,0 +1,99 @@
+use stdcode::StdcodeSerializeExt;
+use themelio_stf::melvm::{opcode::OpCode, Covenant};
+
+/// Highest subaddress index a wallet may derive. Every derived subaddress is watched, costing a query each sync round.
+pub const MAX_SUBADDRESS_INDEX: u64 = 9999;
+
+/// Keeps subaddress tags apart from every other hash of a covenant.
+const SUBADDRESS_DOMAIN: &[u8] = b"melwalletd-subaddress";
+
+// heap address the tag is stored to, well clear of those melvm populates before running a covenant
+const HADDR_SUBADDRESS_TAG: u16 = 0x4000;
+
+/// Derives the `index`th subaddress covenant of a wallet. It prefixes the wallet's covenant with a push of a tag, hashed from the covenant and the index, which is stored away unused; what remains checks exactly what the wallet's covenant does, so the wallet's signer can spend from every subaddress. Anyone who knows the wallet's covenant can derive its subaddresses, but the covenant only becomes public once the wallet spends.
+pub fn derive_subaddress(covenant: &Covenant, index: u64) -> anyhow::Result<Covenant> {
+    let tag = tmelcrypt::hash_keyed(SUBADDRESS_DOMAIN, (&covenant.0, index).stdcode());
+    let mut ops = vec![
+        OpCode::PushB(tag.to_vec()),
+        OpCode::StoreImm(HADDR_SUBADDRESS_TAG),
+    ];
+    ops.extend(
+        covenant
+            .to_ops()
+            .map_err(|err| anyhow::anyhow!("cannot decode covenant: {:?}", err))?,
+    );
+    Covenant::from_ops(&ops).map_err(|err| anyhow::anyhow!("cannot encode covenant: {:?}", err))
+}
+
+#[cfg(test)]
+mod tests {
+    use themelio_stf::melvm::CovenantEnv;
+    use themelio_structs::{
+        BlockHeight, CoinData, CoinDataHeight, CoinID, CoinValue, Denom, Header, NetID,
+        Transaction, TxKind,
+    };
+
+    use crate::signer::Signer;
+
+    use super::*;
+
+    #[test]
+    fn subaddresses_differ_but_spend_alike() {
+        let (_, sk) = tmelcrypt::ed25519_keygen();
+        let covenant = sk.covenant();
+        let first = derive_subaddress(&covenant, 0).unwrap();
+        let second = derive_subaddress(&covenant, 1).unwrap();
+        assert_ne!(first.hash(), covenant.hash());
+        assert_ne!(first.hash(), second.hash());
+        assert_eq!(first, derive_subaddress(&covenant, 0).unwrap());
+
+        let tx = Transaction {
+            kind: TxKind::Normal,
+            inputs: vec![CoinID::zero_zero()],
+            outputs: vec![CoinData {
+                covhash: covenant.hash(),
+                value: CoinValue(1),
+                denom: Denom::Mel,
+                additional_data: vec![],
+            }],
+            fee: CoinValue(0),
+            covenants: vec![first.0.clone()],
+            data: vec![],
+            sigs: vec![],
+        };
+        let env = || CovenantEnv {
+            parent_coinid: CoinID::zero_zero(),
+            parent_cdh: CoinDataHeight {
+                coin_data: CoinData {
+                    covhash: first.hash(),
+                    value: CoinValue(1),
+                    denom: Denom::Mel,
+                    additional_data: vec![],
+                },
+                height: BlockHeight(1),
+            },
+            spender_index: 0,
+            last_header: Header {
+                network: NetID::Mainnet,
+                previous: Default::default(),
+                height: BlockHeight(1),
+                history_hash: Default::default(),
+                coins_hash: Default::default(),
+                transactions_hash: Default::default(),
+                fee_pool: CoinValue(0),
+                fee_multiplier: 1,
+                dosc_speed: 1,
+                pools_hash: Default::default(),
+                stakes_hash: Default::default(),
+            },
+        };
+        assert!(first.check(&sk.sign_tx(tx.clone(), 0).unwrap(), env()));
+        // a different transaction, since signatures are cached by transaction hash
+        let tx = Transaction {
+            fee: CoinValue(1),
+            ..tx
+        };
+        let (_, other_sk) = tmelcrypt::ed25519_keygen();
+        assert!(!first.check(&other_sk.sign_tx(tx, 0).unwrap(), env()));
+    }
+}
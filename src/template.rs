@@ -0,0 +1,113 @@
+use serde::{Deserialize, Serialize};
+use themelio_structs::{Address, CoinData, CoinValue, Denom, TxKind};
+
+/// Most templates a single wallet may keep.
+pub const MAX_TEMPLATES: usize = 100;
+
+/// Longest name a template may have.
+pub const MAX_TEMPLATE_NAME_LEN: usize = 64;
+
+/// An output of a template. Its value may be left out, to be supplied every time the template is used.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TemplateOutput {
+    #[serde(with = "stdcode::asstr")]
+    pub covhash: Address,
+    #[serde(default)]
+    pub value: Option<CoinValue>,
+    #[serde(with = "stdcode::asstr")]
+    pub denom: Denom,
+    #[serde(default, with = "stdcode::hex")]
+    pub additional_data: Vec<u8>,
+}
+
+/// The fixed parts of a transaction a wallet sends over and over, registered under a name.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct TxTemplate {
+    pub outputs: Vec<TemplateOutput>,
+    pub kind: Option<TxKind>,
+    pub data: Option<String>,
+    #[serde(default, with = "stdcode::hexvec")]
+    pub covenants: Vec<Vec<u8>>,
+    #[serde(default)]
+    pub nobalance: Vec<Denom>,
+    #[serde(default)]
+    pub single_change: bool,
+}
+
+impl TxTemplate {
+    /// How many values every use of the template has to supply.
+    pub fn variable_count(&self) -> usize {
+        self.outputs.iter().filter(|o| o.value.is_none()).count()
+    }
+
+    /// Builds the outputs, filling in the values left out of the template in order.
+    pub fn outputs(&self, values: &[CoinValue]) -> anyhow::Result<Vec<CoinData>> {
+        if values.len() != self.variable_count() {
+            anyhow::bail!(
+                "values: template takes {} values, got {}",
+                self.variable_count(),
+                values.len()
+            )
+        }
+        let mut values = values.iter();
+        Ok(self
+            .outputs
+            .iter()
+            .map(|output| CoinData {
+                covhash: output.covhash,
+                value: output
+                    .value
+                    .or_else(|| values.next().copied())
+                    .expect("counted above"),
+                denom: output.denom,
+                additional_data: output.additional_data.clone(),
+            })
+            .collect())
+    }
+}
+
+/// Whether a template name is short and plain enough to sit in a URL path.
+pub fn valid_template_name(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= MAX_TEMPLATE_NAME_LEN
+        && name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn output(value: Option<u128>) -> TemplateOutput {
+        TemplateOutput {
+            covhash: Address::coin_destroy(),
+            value: value.map(CoinValue),
+            denom: Denom::Mel,
+            additional_data: vec![],
+        }
+    }
+
+    #[test]
+    fn fills_in_values_in_order() {
+        let template = TxTemplate {
+            outputs: vec![output(None), output(Some(5)), output(None)],
+            kind: None,
+            data: None,
+            covenants: vec![],
+            nobalance: vec![],
+            single_change: false,
+        };
+        let values: Vec<_> = template
+            .outputs(&[CoinValue(1), CoinValue(2)])
+            .unwrap()
+            .into_iter()
+            .map(|o| o.value.0)
+            .collect();
+        assert_eq!(values, vec![1, 5, 2]);
+        assert!(template.outputs(&[CoinValue(1)]).is_err());
+        assert!(template
+            .outputs(&[CoinValue(1), CoinValue(2), CoinValue(3)])
+            .is_err());
+    }
+}
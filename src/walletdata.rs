@@ -2,16 +2,25 @@
 
 use serde::{Deserialize, Serialize};
 
+use std::collections::{BTreeMap, BTreeSet};
 
 use themelio_structs::{
-    BlockHeight, CoinData, Transaction,
+    Address, BlockHeight, CoinData, CoinDataHeight, NetID, Transaction, TxHash,
 };
 
+use crate::secrets::PersistentSecret;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TransactionStatus {
     pub raw: Transaction,
     pub confirmed_height: Option<BlockHeight>,
     pub outputs: Vec<AnnCoinID>,
+    #[serde(default)]
+    pub inputs: Vec<AnnInput>,
+    #[serde(default)]
+    pub abandoned: bool,
+    #[serde(default)]
+    pub label: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
@@ -20,3 +29,38 @@ pub struct AnnCoinID {
     pub is_change: bool,
     pub coin_id: String,
 }
+
+/// A coin spent by a transaction, along with what the wallet knows of where it came from.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct AnnInput {
+    pub coin_id: String,
+    /// The coin itself, if it belonged to the wallet.
+    pub coin_data: Option<CoinData>,
+    /// The transaction that created the coin, if the wallet has it.
+    pub created_by: Option<TxHash>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CoinEvent {
+    pub coin_id: String,
+    pub coin_data_height: CoinDataHeight,
+    pub is_change: bool,
+}
+
+/// Everything needed to recreate a wallet elsewhere. Coins aren't included, since they are rediscovered from the network.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WalletDump {
+    #[serde(with = "stdcode::asstr")]
+    pub address: Address,
+    #[serde(default, with = "stdcode::hex")]
+    pub covenant: Vec<u8>,
+    #[serde(default)]
+    pub secret: Option<PersistentSecret>,
+    #[serde(default)]
+    pub labels: BTreeMap<TxHash, String>,
+    #[serde(default)]
+    pub tags: BTreeSet<String>,
+    /// The network the wallet was dumped from. Older dumps don't say.
+    #[serde(default)]
+    pub network: Option<NetID>,
+}